@@ -0,0 +1,121 @@
+use std::net::IpAddr;
+
+/// 一个CIDR网段，例如10.0.0.0/8或2001:db8::/32；不带前缀长度时只匹配该地址本身
+#[derive(Clone, Debug)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u32,
+}
+
+impl Cidr {
+    pub fn parse(text: &str) -> Result<Cidr, String> {
+        let (address, prefix_len) = match text.trim().split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (text.trim(), None),
+        };
+        let network: IpAddr = address.parse().map_err(|_| format!("无效的IP地址: {}", text))?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().ok().filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("无效的前缀长度: {}", text))?,
+            None => max_len,
+        };
+        // IPv4映射的IPv6网段换成对应的IPv4网段，与contains中对客户端地址的处理一致
+        if let IpAddr::V6(v6) = network
+            && let Some(v4) = v6.to_ipv4_mapped()
+            && prefix_len >= 96
+        {
+            return Ok(Cidr { network: IpAddr::V4(v4), prefix_len: prefix_len - 96 });
+        }
+        Ok(Cidr { network, prefix_len })
+    }
+
+    /// 地址是否属于该网段，IPv4映射的IPv6地址按IPv4比较
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => prefix_matches(network.to_bits().into(), ip.to_bits().into(), self.prefix_len, 32),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => prefix_matches(network.to_bits(), ip.to_bits(), self.prefix_len, 128),
+            _ => false,
+        }
+    }
+}
+
+/// 比较两个地址的前prefix_len位，width为地址的总位数
+fn prefix_matches(network: u128, ip: u128, prefix_len: u32, width: u32) -> bool {
+    if prefix_len == 0 {
+        return true;
+    }
+    let shift = width - prefix_len;
+    network >> shift == ip >> shift
+}
+
+/// 按客户端IP的访问控制：先检查deny，再检查allow，都不匹配时使用默认结果
+#[derive(Clone, Debug)]
+pub struct AccessList {
+    deny: Vec<Cidr>,
+    allow: Vec<Cidr>,
+    default_allow: bool,
+}
+
+impl AccessList {
+    pub fn new(deny: Vec<Cidr>, allow: Vec<Cidr>, default_allow: bool) -> AccessList {
+        AccessList { deny, allow, default_allow }
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        if self.allow.iter().any(|cidr| cidr.contains(ip)) {
+            return true;
+        }
+        self.default_allow
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_matches_ipv4_and_ipv6_prefixes() {
+        let v4 = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(v4.contains(ip("10.1.255.7")));
+        assert!(!v4.contains(ip("10.2.0.1")));
+        let v6 = Cidr::parse("2001:db8::/32").unwrap();
+        assert!(v6.contains(ip("2001:db8:ffff::1")));
+        assert!(!v6.contains(ip("2001:db9::1")));
+        assert!(!v6.contains(ip("10.1.0.1")));
+        // 不带前缀长度时只匹配该地址
+        let single = Cidr::parse("192.0.2.1").unwrap();
+        assert!(single.contains(ip("192.0.2.1")));
+        assert!(!single.contains(ip("192.0.2.2")));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(ip("203.0.113.9")));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_ipv4_networks() {
+        assert!(Cidr::parse("127.0.0.0/8").unwrap().contains(ip("::ffff:127.0.0.1")));
+        assert!(Cidr::parse("::ffff:10.0.0.0/104").unwrap().contains(ip("10.9.8.7")));
+    }
+
+    #[test]
+    fn invalid_cidrs_are_rejected() {
+        for text in ["10.0.0.0/33", "2001:db8::/129", "10.0.0/8", "example.com", "10.0.0.0/x"] {
+            assert!(Cidr::parse(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn deny_is_checked_before_allow() {
+        let access = AccessList::new(vec![Cidr::parse("10.0.5.0/24").unwrap()], vec![Cidr::parse("10.0.0.0/8").unwrap()], false);
+        assert!(access.permits(ip("10.0.4.1")));
+        assert!(!access.permits(ip("10.0.5.1")));
+        assert!(!access.permits(ip("192.168.1.1")));
+        assert!(AccessList::new(Vec::new(), Vec::new(), true).permits(ip("192.168.1.1")));
+    }
+}
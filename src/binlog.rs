@@ -0,0 +1,130 @@
+use std::io::{self, Read};
+
+const VERSION: u8 = 1;
+
+/// 一条记录的最大长度，更长的长度前缀说明文件已损坏
+const MAX_RECORD_LENGTH: usize = 1 << 20;
+
+/// 一条二进制访问日志
+///
+/// 日志文件由连续的记录组成，每条记录以u32（小端）的记录长度开头，不含这4个字节本身，之后依次为：
+///
+/// | 字段 | 类型 |
+/// |------|------|
+/// | 版本号，目前为1 | u8 |
+/// | 请求完成时间，Unix毫秒时间戳 | i64 |
+/// | 状态码 | u16 |
+/// | 总耗时（毫秒） | u32 |
+/// | 后端耗时（毫秒），不是代理请求时为u32::MAX | u32 |
+/// | 客户端地址 | 字符串 |
+/// | 请求行，没有时为空 | 字符串 |
+/// | Referer，没有记录时为空 | 字符串 |
+/// | User-Agent，没有记录时为空 | 字符串 |
+///
+/// 字符串为u16（小端）的字节数加UTF-8内容，超过65535字节的部分被截断。
+/// 整数都是小端。读取时按记录长度跳过无法识别的版本，因此新版本可以在末尾追加字段
+pub struct Record {
+    pub timestamp_millis: i64,
+    pub status_code: u16,
+    pub duration_millis: u32,
+    pub upstream_millis: Option<u32>,
+    pub client_addr: String,
+    pub request_line: String,
+    pub referer: String,
+    pub user_agent: String,
+}
+
+impl Record {
+    /// 编码为带长度前缀的一条记录
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(64 + self.request_line.len() + self.user_agent.len());
+        payload.push(VERSION);
+        payload.extend_from_slice(&self.timestamp_millis.to_le_bytes());
+        payload.extend_from_slice(&self.status_code.to_le_bytes());
+        payload.extend_from_slice(&self.duration_millis.to_le_bytes());
+        payload.extend_from_slice(&self.upstream_millis.unwrap_or(u32::MAX).to_le_bytes());
+        for field in [&self.client_addr, &self.request_line, &self.referer, &self.user_agent] {
+            push_string(&mut payload, field);
+        }
+
+        let mut record = Vec::with_capacity(4 + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&payload);
+        record
+    }
+
+    /// 从payload（不含长度前缀）解码，版本不认识或内容不完整时返回None
+    fn decode(payload: &[u8]) -> Option<Record> {
+        let mut cursor = Cursor { data: payload, position: 0 };
+        if cursor.take(1)?[0] != VERSION {
+            return None;
+        }
+        let timestamp_millis = i64::from_le_bytes(cursor.take(8)?.try_into().ok()?);
+        let status_code = u16::from_le_bytes(cursor.take(2)?.try_into().ok()?);
+        let duration_millis = u32::from_le_bytes(cursor.take(4)?.try_into().ok()?);
+        let upstream_millis = u32::from_le_bytes(cursor.take(4)?.try_into().ok()?);
+        Some(Record {
+            timestamp_millis,
+            status_code,
+            duration_millis,
+            upstream_millis: (upstream_millis != u32::MAX).then_some(upstream_millis),
+            client_addr: cursor.string()?,
+            request_line: cursor.string()?,
+            referer: cursor.string()?,
+            user_agent: cursor.string()?,
+        })
+    }
+}
+
+/// 追加一个带u16长度前缀的字符串，过长时在字符边界截断
+fn push_string(buffer: &mut Vec<u8>, value: &str) {
+    let mut end = value.len().min(u16::MAX as usize);
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    buffer.extend_from_slice(&(end as u16).to_le_bytes());
+    buffer.extend_from_slice(&value.as_bytes()[..end]);
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.position..self.position + length)?;
+        self.position += length;
+        Some(bytes)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let length = u16::from_le_bytes(self.take(2)?.try_into().ok()?) as usize;
+        Some(String::from_utf8_lossy(self.take(length)?).into_owned())
+    }
+}
+
+/// 依次读出文件中的记录，无法识别的记录跳过；文件末尾不完整的记录视为正在写入，忽略
+pub fn read_records(mut reader: impl Read, mut visit: impl FnMut(Record)) -> io::Result<()> {
+    let mut length = [0; 4];
+    loop {
+        match reader.read_exact(&mut length) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        let length = u32::from_le_bytes(length) as usize;
+        if length > MAX_RECORD_LENGTH {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("记录长度 {} 超过上限，文件可能已损坏", length)));
+        }
+        let mut payload = vec![0; length];
+        match reader.read_exact(&mut payload) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        if let Some(record) = Record::decode(&payload) {
+            visit(record);
+        }
+    }
+}
@@ -0,0 +1,193 @@
+/// 消息体的分帧方式
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BodyFraming {
+    /// 没有消息体
+    Empty,
+    /// 长度由Content-Length给出
+    Length(usize),
+    /// chunked传输编码
+    Chunked,
+    /// 一直读到连接关闭
+    UntilClose,
+}
+
+impl BodyFraming {
+    /// 根据请求方法、状态码和响应头判断响应体的分帧方式
+    pub fn for_response(method: &str, status_code: u16, headers: &[(String, String)]) -> BodyFraming {
+        if method == "HEAD" || (100..200).contains(&status_code) || status_code == 204 || status_code == 304 {
+            return BodyFraming::Empty;
+        }
+        BodyFraming::from_headers(headers).unwrap_or(BodyFraming::UntilClose)
+    }
+
+    /// 根据Transfer-Encoding和Content-Length判断分帧方式，两者都没有时返回None
+    pub fn from_headers(headers: &[(String, String)]) -> Option<BodyFraming> {
+        let chunked = headers.iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("Transfer-Encoding"))
+            .flat_map(|(_, value)| value.split(','))
+            .any(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+        if chunked {
+            return Some(BodyFraming::Chunked);
+        }
+        content_lengths(headers).next()
+            .and_then(|value| value.parse().ok())
+            .map(BodyFraming::Length)
+    }
+
+    /// 请求的分帧是否有歧义：同时带有Transfer-Encoding和Content-Length，或者Content-Length无效、多个值互不相同
+    ///
+    /// 代理和后端对这类请求可能切分出不同的长度，多出的部分会被当作下一个请求，用于请求走私
+    pub fn is_ambiguous(headers: &[(String, String)]) -> bool {
+        let lengths: Vec<Option<usize>> = content_lengths(headers).map(|value| value.parse().ok()).collect();
+        let Some(first) = lengths.first() else {
+            return false;
+        };
+        headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("Transfer-Encoding"))
+            || first.is_none()
+            || lengths.iter().any(|length| length != first)
+    }
+}
+
+/// 所有Content-Length头的值，同一个头中用逗号分隔的多个值分别列出
+fn content_lengths(headers: &[(String, String)]) -> impl Iterator<Item = &str> {
+    headers.iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(str::trim)
+}
+
+/// chunked编码的解析状态
+enum ChunkState {
+    /// 正在读取块大小行
+    Size,
+    /// 当前块还剩多少字节数据
+    Data(usize),
+    /// 块数据之后的CRLF
+    DataEnd,
+    /// 最后一个块之后的trailer部分
+    Trailer,
+    Done,
+}
+
+/// 跟踪消息体的接收进度，用于判断何时已经收完
+pub struct BodyTracker {
+    framing: BodyFraming,
+    remaining: usize,
+    chunk_state: ChunkState,
+    line: Vec<u8>,
+}
+
+impl BodyTracker {
+    pub fn new(framing: BodyFraming) -> BodyTracker {
+        let remaining = match framing {
+            BodyFraming::Length(length) => length,
+            _ => 0,
+        };
+        BodyTracker {
+            framing,
+            remaining,
+            chunk_state: ChunkState::Size,
+            line: Vec::new(),
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        match self.framing {
+            BodyFraming::Empty => true,
+            BodyFraming::Length(_) => self.remaining == 0,
+            BodyFraming::Chunked => matches!(self.chunk_state, ChunkState::Done),
+            BodyFraming::UntilClose => false,
+        }
+    }
+
+    /// 处理新收到的数据，返回其中属于消息体的字节数（其后的字节不属于本消息）
+    pub fn feed(&mut self, data: &[u8]) -> usize {
+        match self.framing {
+            BodyFraming::Empty => 0,
+            BodyFraming::Length(_) => {
+                let consumed = data.len().min(self.remaining);
+                self.remaining -= consumed;
+                consumed
+            }
+            BodyFraming::Chunked => self.feed_chunked(data),
+            BodyFraming::UntilClose => data.len(),
+        }
+    }
+
+    fn feed_chunked(&mut self, data: &[u8]) -> usize {
+        let mut position = 0;
+        while position < data.len() {
+            match self.chunk_state {
+                ChunkState::Done => break,
+                ChunkState::Data(left) => {
+                    let take = left.min(data.len() - position);
+                    position += take;
+                    self.chunk_state = if take == left { ChunkState::DataEnd } else { ChunkState::Data(left - take) };
+                }
+                ChunkState::DataEnd => {
+                    if data[position] == b'\n' {
+                        self.chunk_state = ChunkState::Size;
+                    }
+                    position += 1;
+                }
+                ChunkState::Size | ChunkState::Trailer => {
+                    let byte = data[position];
+                    position += 1;
+                    if byte != b'\n' {
+                        self.line.push(byte);
+                        continue;
+                    }
+                    let line = String::from_utf8_lossy(&self.line).trim().to_string();
+                    self.line.clear();
+                    self.chunk_state = match self.chunk_state {
+                        ChunkState::Size => {
+                            let size = line.split(';').next().unwrap_or("").trim();
+                            match usize::from_str_radix(size, 16) {
+                                Ok(0) => ChunkState::Trailer,
+                                Ok(size) => ChunkState::Data(size),
+                                // 无法解析的块大小，停止继续读取
+                                Err(_) => ChunkState::Done,
+                            }
+                        }
+                        _ if line.is_empty() => ChunkState::Done,
+                        _ => ChunkState::Trailer,
+                    };
+                }
+            }
+        }
+        position
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn framing_from_headers() {
+        assert_eq!(BodyFraming::from_headers(&headers(&[("Content-Length", "5")])), Some(BodyFraming::Length(5)));
+        assert_eq!(BodyFraming::from_headers(&headers(&[("content-length", "5, 5")])), Some(BodyFraming::Length(5)));
+        assert_eq!(BodyFraming::from_headers(&headers(&[("Transfer-Encoding", "gzip, chunked")])), Some(BodyFraming::Chunked));
+        assert_eq!(BodyFraming::from_headers(&headers(&[("Host", "a")])), None);
+    }
+
+    #[test]
+    fn conflicting_framing_is_ambiguous() {
+        assert!(BodyFraming::is_ambiguous(&headers(&[("Content-Length", "5"), ("Content-Length", "6")])));
+        assert!(BodyFraming::is_ambiguous(&headers(&[("Content-Length", "5, 6")])));
+        assert!(BodyFraming::is_ambiguous(&headers(&[("Content-Length", "5"), ("Transfer-Encoding", "chunked")])));
+        assert!(BodyFraming::is_ambiguous(&headers(&[("Content-Length", "-1")])));
+    }
+
+    #[test]
+    fn consistent_framing_is_not_ambiguous() {
+        assert!(!BodyFraming::is_ambiguous(&headers(&[("Content-Length", "5")])));
+        assert!(!BodyFraming::is_ambiguous(&headers(&[("Content-Length", "5"), ("Content-Length", "5")])));
+        assert!(!BodyFraming::is_ambiguous(&headers(&[("Transfer-Encoding", "chunked")])));
+        assert!(!BodyFraming::is_ambiguous(&headers(&[])));
+    }
+}
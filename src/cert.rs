@@ -0,0 +1,227 @@
+//! 从DER编码的X.509证书中读取主题，用于把客户端证书的信息转发给后端
+
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+// 证书中版本号字段的上下文标签[0]
+const VERSION: u8 = 0xa0;
+
+/// 一个DER元素：标签、内容以及包括标签和长度在内的完整编码
+struct Element<'a> {
+    tag: u8,
+    content: &'a [u8],
+    encoded: &'a [u8],
+}
+
+/// 读取开头的一个元素，返回它和剩余的字节
+fn read_element(data: &[u8]) -> Option<(Element<'_>, &[u8])> {
+    let (&tag, rest) = data.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (length, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        // 长格式：低7位为长度本身占用的字节数
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let length = rest[..count].iter().fold(0, |length, &byte| length << 8 | byte as usize);
+        (length, &rest[count..])
+    };
+    if rest.len() < length {
+        return None;
+    }
+    let header_len = data.len() - rest.len();
+    let element = Element { tag, content: &rest[..length], encoded: &data[..header_len + length] };
+    Some((element, &rest[length..]))
+}
+
+/// 依次读取内容中的全部元素
+fn read_elements(mut data: &[u8]) -> Option<Vec<Element<'_>>> {
+    let mut elements = Vec::new();
+    while !data.is_empty() {
+        let (element, rest) = read_element(data)?;
+        elements.push(element);
+        data = rest;
+    }
+    Some(elements)
+}
+
+/// 证书主题的RFC 4514字符串表示，例如CN=client,O=Example；无法解析时返回None
+pub fn subject(der: &[u8]) -> Option<String> {
+    let (certificate, _) = read_element(der).filter(|(element, _)| element.tag == SEQUENCE)?;
+    let (tbs_certificate, _) = read_element(certificate.content).filter(|(element, _)| element.tag == SEQUENCE)?;
+    let mut fields = read_elements(tbs_certificate.content)?;
+    if fields.first()?.tag == VERSION {
+        fields.remove(0);
+    }
+    // 序列号、签名算法、颁发者和有效期之后是主题
+    let subject = fields.get(4).filter(|field| field.tag == SEQUENCE)?;
+
+    // RFC 4514从最后一个RDN开始写，同一个RDN中的多个属性用+连接
+    let mut rdns = Vec::new();
+    for rdn in read_elements(subject.content)? {
+        if rdn.tag != SET {
+            return None;
+        }
+        let attributes = read_elements(rdn.content)?.iter()
+            .map(attribute)
+            .collect::<Option<Vec<_>>>()?;
+        rdns.push(attributes.join("+"));
+    }
+    rdns.reverse();
+    Some(rdns.join(","))
+}
+
+/// 一个属性的类型=值，常见类型使用简称，其余使用点分的OID
+fn attribute(element: &Element) -> Option<String> {
+    let [oid, value] = read_elements(element.content)?.try_into().ok()?;
+    if element.tag != SEQUENCE || oid.tag != OBJECT_IDENTIFIER {
+        return None;
+    }
+    let oid = format_oid(oid.content)?;
+    let name = match oid.as_str() {
+        "2.5.4.3" => "CN",
+        "2.5.4.6" => "C",
+        "2.5.4.7" => "L",
+        "2.5.4.8" => "ST",
+        "2.5.4.9" => "STREET",
+        "2.5.4.10" => "O",
+        "2.5.4.11" => "OU",
+        "0.9.2342.19200300.100.1.1" => "UID",
+        "0.9.2342.19200300.100.1.25" => "DC",
+        _ => &oid,
+    };
+    // 无法作为字符串表示的值写成#加完整编码的十六进制
+    let value = match attribute_string(&value) {
+        Some(text) => escape_value(&text),
+        None => format!("#{}", value.encoded.iter().map(|byte| format!("{:02x}", byte)).collect::<String>()),
+    };
+    Some(format!("{}={}", name, value))
+}
+
+/// 字符串类型的属性值：UTF8String、PrintableString、TeletexString、IA5String和BMPString
+fn attribute_string(value: &Element) -> Option<String> {
+    match value.tag {
+        0x0c | 0x13 | 0x14 | 0x16 => String::from_utf8(value.content.to_vec()).ok(),
+        0x1e if value.content.len().is_multiple_of(2) => {
+            let units: Vec<u16> = value.content.chunks(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect();
+            String::from_utf16(&units).ok()
+        }
+        _ => None,
+    }
+}
+
+/// 按RFC 4514转义属性值；控制字符也写成\十六进制，值可以安全地放进请求头
+fn escape_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+    for (index, c) in value.chars().enumerate() {
+        match c {
+            '"' | '+' | ',' | ';' | '<' | '>' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '#' if index == 0 => escaped.push_str("\\#"),
+            ' ' if index == 0 || index == last => escaped.push_str("\\ "),
+            c if c.is_control() => {
+                let mut buffer = [0; 4];
+                for byte in c.encode_utf8(&mut buffer).bytes() {
+                    escaped.push_str(&format!("\\{:02x}", byte));
+                }
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// 把OID的内容解码成点分形式，例如2.5.4.3
+fn format_oid(content: &[u8]) -> Option<String> {
+    let mut arcs = Vec::new();
+    let mut value: u64 = 0;
+    for &byte in content {
+        value = value.checked_mul(128)? | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            arcs.push(value);
+            value = 0;
+        }
+    }
+    // 最后一个字节仍标记着后面还有字节
+    if content.last()? & 0x80 != 0 {
+        return None;
+    }
+    // 第一个值同时编码了前两段
+    let first = arcs.first().copied()?;
+    let (top, second) = if first < 80 { (first / 40, first % 40) } else { (2, first - 80) };
+    let mut parts = vec![top.to_string(), second.to_string()];
+    parts.extend(arcs[1..].iter().map(u64::to_string));
+    Some(parts.join("."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// DER编码一个元素，内容长度不超过255字节
+    fn encode(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![tag];
+        if content.len() < 0x80 {
+            encoded.push(content.len() as u8);
+        } else {
+            encoded.extend_from_slice(&[0x81, content.len() as u8]);
+        }
+        encoded.extend_from_slice(content);
+        encoded
+    }
+
+    /// 只包含主题的最小证书结构，其他字段用空的占位元素
+    fn certificate_with_subject(rdns: &[&[(&[u8], u8, &str)]]) -> Vec<u8> {
+        let mut subject = Vec::new();
+        for rdn in rdns {
+            let mut set = Vec::new();
+            for (oid, tag, value) in rdn.iter() {
+                let mut attribute = encode(OBJECT_IDENTIFIER, oid);
+                attribute.extend(encode(*tag, value.as_bytes()));
+                set.extend(encode(SEQUENCE, &attribute));
+            }
+            subject.extend(encode(SET, &set));
+        }
+        let mut tbs = encode(VERSION, &encode(0x02, &[2]));
+        tbs.extend(encode(0x02, &[1]));
+        tbs.extend(encode(SEQUENCE, &[]));
+        tbs.extend(encode(SEQUENCE, &[]));
+        tbs.extend(encode(SEQUENCE, &[]));
+        tbs.extend(encode(SEQUENCE, &subject));
+        encode(SEQUENCE, &encode(SEQUENCE, &tbs))
+    }
+
+    const CN: &[u8] = &[0x55, 0x04, 0x03];
+    const O: &[u8] = &[0x55, 0x04, 0x0a];
+
+    #[test]
+    fn subject_lists_rdns_in_reverse_order() {
+        let der = certificate_with_subject(&[&[(O, 0x0c, "Example")], &[(CN, 0x13, "client")]]);
+        assert_eq!(subject(&der).as_deref(), Some("CN=client,O=Example"));
+    }
+
+    #[test]
+    fn subject_escapes_special_characters() {
+        let der = certificate_with_subject(&[&[(CN, 0x0c, "#a,b\r\nX-Injected: 1 ")]]);
+        assert_eq!(subject(&der).as_deref(), Some("CN=\\#a\\,b\\0d\\0aX-Injected: 1\\ "));
+    }
+
+    #[test]
+    fn unknown_attributes_use_dotted_oid_and_multivalued_rdns_are_joined() {
+        // 1.2.840.113549.1.9.1是emailAddress
+        let email = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x01];
+        let der = certificate_with_subject(&[&[(CN, 0x0c, "a"), (email, 0x16, "a@example.com")]]);
+        assert_eq!(subject(&der).as_deref(), Some("CN=a+1.2.840.113549.1.9.1=a@example.com"));
+    }
+
+    #[test]
+    fn truncated_certificate_is_rejected() {
+        let der = certificate_with_subject(&[&[(CN, 0x0c, "client")]]);
+        assert_eq!(subject(&der[..der.len() - 1]), None);
+    }
+}
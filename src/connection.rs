@@ -0,0 +1,320 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::os::fd::AsRawFd;
+use std::sync::Arc;
+use std::thread;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::crypto::{CryptoProvider, GetRandomFailed};
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{NoServerSessionStorage, ProducesTickets, WebPkiClientVerifier};
+use rustls::{RootCertStore, ServerConnection, StreamOwned, TicketRotator};
+
+use crate::socket::{ClientAddr, Socket};
+
+/// 与客户端之间的连接，明文或TLS，底层为TCP或Unix domain socket，请求处理只通过Read和Write收发数据
+pub struct ClientStream {
+    transport: Transport,
+    // 接受连接时取得的对端地址，之后连接被重置也不会变成未知
+    peer_addr: ClientAddr,
+    // 通过Write写给客户端的字节数，隧道等直接使用TCP连接转发的数据不计入
+    written: u64,
+    // 已经从连接上读出、属于下一个请求的数据，之后的读取先返回这部分
+    unread: Vec<u8>,
+}
+
+enum Transport {
+    Plain(Socket),
+    Tls(Box<StreamOwned<ServerConnection, Socket>>),
+}
+
+impl ClientStream {
+    /// 按服务器是否配置了TLS包装新接受的连接，TLS握手在第一次读写时进行
+    pub fn new(stream: Socket, peer_addr: ClientAddr, tls: Option<&Arc<rustls::ServerConfig>>) -> io::Result<ClientStream> {
+        let transport = match tls {
+            Some(tls) => {
+                let connection = ServerConnection::new(Arc::clone(tls)).map_err(io::Error::other)?;
+                Transport::Tls(Box::new(StreamOwned::new(connection, stream)))
+            }
+            None => Transport::Plain(stream),
+        };
+        Ok(ClientStream { transport, peer_addr, written: 0, unread: Vec::new() })
+    }
+
+    /// 是否为TLS连接
+    pub fn is_tls(&self) -> bool {
+        matches!(self.transport, Transport::Tls(_))
+    }
+
+    /// 客户端在TLS握手时通过SNI发送的主机名，明文连接或客户端没有发送时返回None
+    pub fn server_name(&self) -> Option<&str> {
+        match &self.transport {
+            Transport::Plain(_) => None,
+            Transport::Tls(stream) => stream.conn.server_name(),
+        }
+    }
+
+    /// 客户端在TLS握手时出示并通过验证的证书（DER编码），没有配置client_ca或客户端没有出示时返回None
+    pub fn client_certificate(&self) -> Option<&[u8]> {
+        match &self.transport {
+            Transport::Plain(_) => None,
+            Transport::Tls(stream) => stream.conn.peer_certificates()?.first().map(|cert| cert.as_ref()),
+        }
+    }
+
+    /// 客户端的地址
+    pub fn peer_addr(&self) -> ClientAddr {
+        self.peer_addr
+    }
+
+    /// 连接建立以来写给客户端的字节数，TLS连接为加密前的字节数
+    pub fn bytes_written(&self) -> u64 {
+        self.written
+    }
+
+    /// 把多读到的数据放回，下一次读取时先返回；用于同一次读取中收到的流水线请求
+    pub fn unread(&mut self, data: &[u8]) {
+        self.unread.splice(0..0, data.iter().copied());
+    }
+
+    /// 底层的连接，用于设置超时等
+    pub fn socket(&self) -> &Socket {
+        match &self.transport {
+            Transport::Plain(stream) => stream,
+            Transport::Tls(stream) => &stream.sock,
+        }
+    }
+
+    /// 在客户端和后端之间双向转发数据，用于隧道和升级后的连接；客户端关闭写方向后继续把后端的数据发给客户端，后端关闭时结束
+    ///
+    /// 转发的数据不计入bytes_written
+    pub fn relay(&mut self, mut backend: Socket) {
+        if !self.unread.is_empty() && backend.write_all(&std::mem::take(&mut self.unread)).is_err() {
+            return;
+        }
+        match &mut self.transport {
+            Transport::Plain(client) => relay_plain(client, backend),
+            Transport::Tls(stream) => relay_tls(stream, backend),
+        }
+    }
+}
+
+/// 明文连接各用一个线程转发一个方向
+fn relay_plain(client: &mut Socket, backend: Socket) {
+    let (Ok(mut client_reader), Ok(mut backend_writer)) = (client.try_clone(), backend.try_clone()) else {
+        return;
+    };
+    let upstream = thread::spawn(move || {
+        let _ = io::copy(&mut client_reader, &mut backend_writer);
+        let _ = backend_writer.shutdown(Shutdown::Write);
+    });
+
+    let mut backend_reader = backend;
+    let _ = io::copy(&mut backend_reader, client);
+    // 后端已关闭，关掉客户端连接让上行方向的线程结束
+    let _ = client.shutdown(Shutdown::Both);
+    let _ = upstream.join();
+}
+
+/// TLS连接的两个方向共用一个会话状态，无法拆给两个线程，在一个线程中用poll交替处理
+fn relay_tls(stream: &mut StreamOwned<ServerConnection, Socket>, mut backend: Socket) {
+    let mut buffer = [0; 16384];
+    let mut client_open = true;
+    loop {
+        // 先转发已经解密但还没有读取的数据，这部分不会让poll返回
+        if client_open {
+            match stream.conn.reader().read(&mut buffer) {
+                Ok(0) => {
+                    // 客户端发送了close_notify
+                    client_open = false;
+                    let _ = backend.shutdown(Shutdown::Write);
+                }
+                Ok(length) => {
+                    if backend.write_all(&buffer[..length]).is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(_) => return,
+            }
+        }
+
+        // 客户端关闭后只等待后端，fd为-1的项被poll忽略
+        let client_fd = if client_open { stream.sock.as_raw_fd() } else { -1 };
+        let mut fds = [
+            libc::pollfd { fd: backend.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+            libc::pollfd { fd: client_fd, events: libc::POLLIN, revents: 0 },
+        ];
+        if unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) } < 0 {
+            if io::Error::last_os_error().kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return;
+        }
+
+        if fds[1].revents != 0 {
+            match stream.conn.read_tls(&mut stream.sock) {
+                // 客户端没有发送close_notify就关闭了连接
+                Ok(0) => {
+                    client_open = false;
+                    let _ = backend.shutdown(Shutdown::Write);
+                }
+                Ok(_) => {
+                    let processed = stream.conn.process_new_packets();
+                    // 出错时也要把告警发给客户端
+                    if write_pending_tls(stream).is_err() || processed.is_err() {
+                        return;
+                    }
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => {}
+                Err(_) => return,
+            }
+        }
+
+        if fds[0].revents != 0 {
+            match backend.read(&mut buffer) {
+                Ok(0) | Err(_) => return,
+                Ok(length) => {
+                    if stream.conn.writer().write_all(&buffer[..length]).is_err() || write_pending_tls(stream).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// 把rustls等待发送的TLS记录全部写到连接上
+fn write_pending_tls(stream: &mut StreamOwned<ServerConnection, Socket>) -> io::Result<()> {
+    while stream.conn.wants_write() {
+        stream.conn.write_tls(&mut stream.sock)?;
+    }
+    Ok(())
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if !self.unread.is_empty() {
+            let length = buffer.len().min(self.unread.len());
+            buffer[..length].copy_from_slice(&self.unread[..length]);
+            self.unread.drain(..length);
+            return Ok(length);
+        }
+        match &mut self.transport {
+            Transport::Plain(stream) => stream.read(buffer),
+            Transport::Tls(stream) => stream.read(buffer),
+        }
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let written = match &mut self.transport {
+            Transport::Plain(stream) => stream.write(data),
+            Transport::Tls(stream) => stream.write(data),
+        }?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.transport {
+            Transport::Plain(stream) => stream.flush(),
+            Transport::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Drop for ClientStream {
+    /// TLS连接关闭前发送close_notify，客户端据此区分正常关闭和连接被截断
+    fn drop(&mut self) {
+        if let Transport::Tls(stream) = &mut self.transport {
+            stream.conn.send_close_notify();
+            let _ = write_pending_tls(stream);
+        }
+    }
+}
+
+/// 读取PEM格式的证书链和私钥，生成TLS配置
+///
+/// ticket_lifetime为会话票据的有效期（秒），None时关闭会话恢复，每次连接都完整握手；
+/// client_auth为验证客户端证书的CA文件和是否必须出示证书，None时不要求客户端证书
+pub fn load_tls_config(cert_path: &str, key_path: &str, ticket_lifetime: Option<u32>, client_auth: Option<(&str, bool)>) -> Result<Arc<rustls::ServerConfig>, String> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("无法读取证书 {}: {}", cert_path, e))?;
+    if certs.is_empty() {
+        return Err(format!("证书文件中没有证书: {}", cert_path));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("无法读取私钥 {}: {}", key_path, e))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let client_verifier = match client_auth {
+        Some((ca_path, required)) => client_cert_verifier(ca_path, required, &provider)?,
+        None => WebPkiClientVerifier::no_client_auth(),
+    };
+    let mut config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("TLS配置无效: {}", e))?
+        .with_client_cert_verifier(client_verifier)
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("无法使用证书和私钥: {}", e))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    match ticket_lifetime {
+        // 票据在两次轮换内有效，轮换间隔取有效期的一半
+        Some(lifetime) => {
+            config.ticketer = Arc::new(TicketRotator::new(lifetime.div_ceil(2).max(1), new_ticketer)
+                .map_err(|e| format!("无法创建会话票据密钥: {}", e))?);
+        }
+        None => {
+            config.session_storage = Arc::new(NoServerSessionStorage {});
+            config.send_tls13_tickets = 0;
+        }
+    }
+    Ok(Arc::new(config))
+}
+
+/// 验证客户端证书的verifier，证书需要由ca_path中的CA签发；required为false时也接受不出示证书的客户端
+fn client_cert_verifier(ca_path: &str, required: bool, provider: &Arc<CryptoProvider>) -> Result<Arc<dyn ClientCertVerifier>, String> {
+    let mut roots = RootCertStore::empty();
+    let cas = CertificateDer::pem_file_iter(ca_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("无法读取客户端CA证书 {}: {}", ca_path, e))?;
+    for ca in cas {
+        roots.add(ca).map_err(|e| format!("无效的客户端CA证书 {}: {}", ca_path, e))?;
+    }
+    let builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), Arc::clone(provider));
+    let builder = if required { builder } else { builder.allow_unauthenticated() };
+    builder.build().map_err(|e| format!("无法使用客户端CA证书 {}: {}", ca_path, e))
+}
+
+/// TicketRotator每次轮换时调用，生成使用新随机密钥的票据加密器
+fn new_ticketer() -> Result<Box<dyn ProducesTickets>, GetRandomFailed> {
+    let ticketer = rustls::crypto::ring::Ticketer::new().map_err(|_| GetRandomFailed)?;
+    Ok(Box::new(SharedTicketer(ticketer)))
+}
+
+/// 把Ticketer::new()返回的Arc包装成TicketRotator需要的Box
+#[derive(Debug)]
+struct SharedTicketer(Arc<dyn ProducesTickets>);
+
+impl ProducesTickets for SharedTicketer {
+    fn enabled(&self) -> bool {
+        self.0.enabled()
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.0.lifetime()
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.0.encrypt(plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        self.0.decrypt(cipher)
+    }
+}
@@ -0,0 +1,74 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use chrono::{DateTime, Local};
+
+use crate::response::html_escape;
+
+/// 目录中的一项
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<DateTime<Local>>,
+}
+
+/// 生成目录列表页面，目录在前、文件在后，各自按名称排序
+///
+/// url_path为客户端请求的目录路径（已解码），用于生成链接和标题
+pub fn directory_listing(directory: &Path, url_path: &str) -> io::Result<String> {
+    let mut entries: Vec<Entry> = fs::read_dir(directory)?
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            Some(Entry {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                is_dir: metadata.is_dir(),
+                size: metadata.len(),
+                modified: metadata.modified().ok().map(DateTime::from),
+            })
+        })
+        .collect();
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+    let base = if url_path.ends_with('/') { url_path.to_string() } else { format!("{}/", url_path) };
+    let title = html_escape(&format!("Index of {}", base));
+    let mut rows = String::new();
+    if base != "/" {
+        rows.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in &entries {
+        let suffix = if entry.is_dir { "/" } else { "" };
+        let href = encode_path(&format!("{}{}{}", base, entry.name, suffix));
+        let size = if entry.is_dir { String::from("-") } else { entry.size.to_string() };
+        let modified = entry.modified.map_or_else(|| String::from("-"), |time| time.format("%Y-%m-%d %H:%M").to_string());
+        rows.push_str(&format!(
+            "<tr><td><a href=\"{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            href, html_escape(&entry.name), suffix, size, modified
+        ));
+    }
+
+    Ok(format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"UTF-8\"><title>{0}</title></head>\n<body>\n<h1>{0}</h1>\n<table>\n<tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n{1}</table>\n<hr><p>nextWeb/{2}</p>\n</body>\n</html>\n",
+        title, rows, env!("CARGO_PKG_VERSION")
+    ))
+}
+
+/// 对路径中除/以外需要转义的字符做百分号编码
+fn encode_path(path: &str) -> String {
+    path.split('/').map(encode_path_segment).collect::<Vec<_>>().join("/")
+}
+
+/// 对单个路径段做百分号编码，只保留RFC 3986中的非保留字符
+fn encode_path_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
@@ -1,10 +1,41 @@
-use std::net::TcpListener;
-use std::io::{Read, Write};
-use toml;
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::io::{self, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::fs::File;
+use std::path::{Path, PathBuf};
 use serde::Deserialize;
 use std::thread;
-use chrono::Local;
+use std::env;
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use std::time::{Duration, Instant};
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+mod acl;
+mod binlog;
+mod body;
+mod cert;
+mod connection;
+mod digest;
+mod listing;
+mod middleware;
+mod pool;
+mod ratelimit;
+mod response;
+mod routing;
+mod socket;
+mod watch;
+use acl::{AccessList, Cidr};
+use body::{BodyFraming, BodyTracker};
+use connection::ClientStream;
+use middleware::{Middleware, RequestContext, ResponseContext};
+use pool::ThreadPool;
+use ratelimit::RateLimiter;
+use response::{ErrorFormat, HttpResponse};
+use routing::HeaderPattern;
+use socket::{ClientAddr, Listener, Socket, SocketAddress};
+use watch::FileWatcher;
 
 #[derive(Deserialize, Clone)]
 struct Server {
@@ -15,6 +46,70 @@ struct Server {
 #[derive(Deserialize)]
 struct Config {
     servers: Vec<Server>,
+    // 是否缓冲访问日志，错误级别的日志始终立即写出
+    #[serde(default)]
+    log_buffering: bool,
+    // 日志时间戳的strftime格式和时区（local或utc）
+    #[serde(default = "default_log_timestamp_format")]
+    log_timestamp_format: String,
+    #[serde(default)]
+    log_timezone: LogTimezone,
+    // 文件描述符的软上限，接近时新连接直接返回503；未设置时按系统限制保留余量后计算
+    #[serde(default)]
+    fd_soft_limit: Option<usize>,
+    // 全局的Server头，服务器可以单独覆盖；空字符串表示不发送，{version}替换为版本号
+    #[serde(default)]
+    server_header: Option<String>,
+    // 所有端口绑定完成后切换到的用户和组，用于以root绑定80/443后降权运行
+    #[serde(default)]
+    user: Option<String>,
+    #[serde(default)]
+    group: Option<String>,
+    // 工作队列已满时直接用503拒绝新连接，未配置时接受线程等待队列空出位置
+    #[serde(default)]
+    load_shedding: Option<LoadSheddingConfig>,
+    // 收到SIGINT/SIGTERM后等待正在处理的连接完成的最长秒数，超时后直接退出
+    #[serde(default = "default_shutdown_timeout_secs")]
+    shutdown_timeout_secs: u64,
+    // 检查配置文件是否被修改的间隔秒数，修改后自动重新加载；0表示不检查
+    #[serde(default = "default_reload_interval_secs")]
+    reload_interval_secs: u64,
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    10
+}
+
+fn default_reload_interval_secs() -> u64 {
+    2
+}
+
+#[derive(Deserialize, Clone)]
+struct LoadSheddingConfig {
+    // 响应正文，未设置时使用内置的503错误响应
+    #[serde(default)]
+    body: Option<String>,
+    #[serde(default = "default_load_shedding_content_type")]
+    content_type: String,
+    // Retry-After秒数，未设置时不发送
+    #[serde(default)]
+    retry_after: Option<u64>,
+}
+
+fn default_load_shedding_content_type() -> String {
+    String::from("text/plain; charset=utf-8")
+}
+
+fn default_log_timestamp_format() -> String {
+    String::from(DEFAULT_LOG_TIMESTAMP_FORMAT)
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum LogTimezone {
+    #[default]
+    Local,
+    Utc,
 }
 
 #[derive(Deserialize, Clone)]
@@ -26,12 +121,330 @@ struct ServerConfig {
     static_config: Option<StaticConfig>,
     #[serde(rename = "proxy", default)]
     proxy_config: Option<ProxyConfig>,
+    #[serde(rename = "redirect", default)]
+    redirect_config: Option<RedirectConfig>,
+    // 内置错误响应的格式：html、json或text
+    #[serde(default)]
+    error_format: ErrorFormat,
+    // 本服务器同时处理中的请求数上限，达到时返回503
+    #[serde(default)]
+    max_concurrent_requests: Option<usize>,
+    // 返回503时使用的页面文件，未设置或读取失败时使用内置错误响应
+    #[serde(default)]
+    overload_page: Option<String>,
+    // 启用的中间件及顺序：logging（访问日志）、server_header（Server头）
+    #[serde(default = "middleware::default_middlewares")]
+    middlewares: Vec<String>,
+    // 健康检查路径，例如/healthz，由服务器直接响应
+    #[serde(default)]
+    health_path: Option<String>,
+    // 本服务器的Server头，覆盖全局配置；空字符串表示不发送，{version}替换为版本号
+    #[serde(default)]
+    server_header: Option<String>,
+    // 配置后在本服务器的端口上终止TLS，只接受https
+    #[serde(default)]
+    tls: Option<TlsConfig>,
+    // 访问日志的输出文件和格式
+    #[serde(default)]
+    log: LogConfig,
+    // 全局的load_shedding配置，加载后由主配置填入
+    #[serde(skip)]
+    load_shedding: Option<LoadSheddingConfig>,
+    // config.toml中的服务器名称，用于健康检查的响应
+    #[serde(skip)]
+    name: String,
+    // 按路径前缀、按客户端IP的限流规则，按顺序使用第一条匹配的规则
+    #[serde(default)]
+    rate_limits: Vec<RateLimitConfig>,
+    // 对所有请求生效的简单限流，加载时转成rate_limits的最后一条规则
+    #[serde(default)]
+    rate_limit: Option<SimpleRateLimitConfig>,
+    // 同一端口上按Host头区分的其他站点，没有匹配时使用本配置中的type、static和proxy
+    #[serde(default)]
+    vhosts: Vec<VirtualHost>,
+    // 按请求头选择站点的规则，按顺序使用第一条匹配的规则，优先于vhosts
+    #[serde(default)]
+    header_routes: Vec<HeaderRoute>,
+    // 启动后的预热阶段，完成前只响应健康检查，其余请求返回503
+    #[serde(default)]
+    warmup: Option<WarmupConfig>,
+}
+
+#[derive(Deserialize, Clone)]
+struct WarmupConfig {
+    // 开始预热前等待的秒数
+    #[serde(default)]
+    delay_secs: u64,
+    // 代理服务器是否等到至少一个后端可以连接
+    #[serde(default = "default_warmup_check_backends")]
+    check_backends: bool,
+    // 预热的最长秒数，超时后照常开始服务；未设置时一直等待
+    #[serde(default)]
+    timeout_secs: Option<u64>,
+}
+
+fn default_warmup_check_backends() -> bool {
+    true
+}
+
+#[derive(Deserialize, Clone)]
+struct VirtualHost {
+    // 该站点的域名，比较时不区分大小写并忽略端口
+    hostnames: Vec<String>,
+    #[serde(rename = "type")]
+    server_type: TypeInfo,
+    #[serde(rename = "static", default)]
+    static_config: Option<StaticConfig>,
+    #[serde(rename = "proxy", default)]
+    proxy_config: Option<ProxyConfig>,
+    #[serde(rename = "redirect", default)]
+    redirect_config: Option<RedirectConfig>,
+}
+
+#[derive(Deserialize, Clone)]
+struct HeaderRoute {
+    // 头部名称，不区分大小写
+    header: String,
+    // 头部值的模式，*匹配任意字符；未设置时只要求请求带有该头部
+    #[serde(default)]
+    value: Option<String>,
+    // 加载配置时由value编译
+    #[serde(skip)]
+    pattern: Option<HeaderPattern>,
+    #[serde(rename = "type")]
+    server_type: TypeInfo,
+    #[serde(rename = "static", default)]
+    static_config: Option<StaticConfig>,
+    #[serde(rename = "proxy", default)]
+    proxy_config: Option<ProxyConfig>,
+    #[serde(rename = "redirect", default)]
+    redirect_config: Option<RedirectConfig>,
+}
+
+#[derive(Deserialize, Clone)]
+struct RateLimitConfig {
+    // 路径前缀，例如/login，未设置时对所有请求生效
+    #[serde(default = "default_rate_limit_path")]
+    path: String,
+    // 每个客户端在per_secs秒内最多的请求数
+    requests: u32,
+    #[serde(default = "default_rate_limit_per_secs")]
+    per_secs: u64,
+    // 允许一次性突发的请求数，默认等于requests
+    #[serde(default)]
+    burst: Option<u32>,
+}
+
+#[derive(Deserialize, Clone)]
+struct SimpleRateLimitConfig {
+    // 每个客户端每秒的请求数
+    requests_per_second: u32,
+    // 允许一次性突发的请求数，默认等于requests_per_second
+    #[serde(default)]
+    burst: Option<u32>,
+}
+
+fn default_rate_limit_per_secs() -> u64 {
+    1
+}
+
+fn default_rate_limit_path() -> String {
+    String::from("/")
+}
+
+#[derive(Deserialize, Clone, Default)]
+struct LogConfig {
+    // 访问日志文件，追加写入；未设置时写到标准输出
+    #[serde(default)]
+    file: Option<String>,
+    // 访问日志格式：simple为默认格式，common为Apache通用日志格式，json为每行一个JSON对象，binary为紧凑的二进制格式（需要设置file）
+    #[serde(default)]
+    format: LogFormat,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum LogFormat {
+    #[default]
+    Simple,
+    Common,
+    Json,
+    Binary,
+}
+
+#[derive(Deserialize, Clone)]
+struct TlsConfig {
+    // PEM格式的证书链文件
+    cert: String,
+    // PEM格式的私钥文件
+    key: String,
+    // 是否允许TLS会话恢复（会话票据和服务端会话缓存），要求严格前向保密时关闭
+    #[serde(default = "default_session_resumption")]
+    session_resumption: bool,
+    // 会话票据的有效期（秒），票据密钥每隔一半的时间轮换一次
+    #[serde(default = "default_session_ticket_lifetime_secs")]
+    session_ticket_lifetime_secs: u32,
+    // 验证客户端证书（mTLS）的CA证书文件，PEM格式；设置后客户端需要出示由这些CA签发的证书
+    #[serde(default)]
+    client_ca: Option<String>,
+    // 设置client_ca时是否必须出示客户端证书，false时也接受不出示证书的客户端，出示的证书仍然要通过验证
+    #[serde(default = "default_require_client_cert")]
+    require_client_cert: bool,
+    // 客户端向本端口发送明文HTTP请求时，400响应中的文本
+    #[serde(default = "default_plaintext_http_message")]
+    plaintext_http_message: String,
+}
+
+fn default_session_resumption() -> bool {
+    true
+}
+
+fn default_session_ticket_lifetime_secs() -> u32 {
+    12 * 60 * 60
+}
+
+fn default_require_client_cert() -> bool {
+    true
+}
+
+fn default_plaintext_http_message() -> String {
+    String::from("This is an HTTPS server")
 }
 
 #[derive(Deserialize, Clone)]
 struct ServerInfo {
+    // 监听的IP地址和端口，设置unix_socket时不使用
+    #[serde(default)]
     address: String,
+    #[serde(default)]
     port: u16,
+    // 监听的Unix domain socket路径，代替address和port
+    #[serde(default)]
+    unix_socket: Option<String>,
+    // 是否记录连接建立和关闭
+    #[serde(default)]
+    connection_log: bool,
+    // 单个客户端IP允许的最大并发连接数
+    #[serde(default)]
+    max_connections_per_ip: Option<usize>,
+    // 请求行（方法 + URI + 版本）的最大长度
+    #[serde(default)]
+    max_request_line_length: Option<usize>,
+    // 请求头包含非法UTF-8时返回400，关闭时按替换字符宽松处理
+    #[serde(default)]
+    strict_utf8: bool,
+    // 连接的SO_LINGER秒数，未设置时使用系统默认行为
+    #[serde(default)]
+    linger_secs: Option<u64>,
+    // 没有版本号的HTTP/0.9请求：reject返回400，respond只返回正文
+    #[serde(default)]
+    http09: Http09Mode,
+    // 客户端连接的读写超时秒数，0表示不限制；请求头必须在这段时间内收完，否则返回408
+    #[serde(default = "default_timeout_secs")]
+    timeout_secs: u64,
+    // 读取客户端请求的超时秒数，覆盖timeout_secs，超时返回408；连接后端或等待后端响应超时返回504
+    #[serde(default)]
+    request_timeout_secs: Option<u64>,
+    // 工作线程数，接受线程把连接放入队列交给工作线程处理，未设置时使用CPU核数
+    #[serde(default)]
+    worker_threads: Option<usize>,
+    // 等待工作线程处理的连接数上限，队列满时暂停接受新连接
+    #[serde(default = "default_worker_queue_capacity")]
+    worker_queue_capacity: usize,
+    // 校验请求体与Content-MD5或Digest头是否一致，不一致时返回400
+    #[serde(default)]
+    verify_body_digest: bool,
+    // 单个请求头值的最大长度，超过时返回431
+    #[serde(default)]
+    max_header_value_length: Option<usize>,
+    // 保持连接时等待下一个请求的秒数，设为0时每个请求之后都关闭连接
+    #[serde(default = "default_keepalive_timeout_secs")]
+    keepalive_timeout_secs: u64,
+    // 请求行加全部请求头的最大字节数，超过时返回431
+    #[serde(default = "default_max_header_size")]
+    max_header_size: usize,
+    // 访问日志中增加请求头个数和总字节数两列，用于发现异常的探测请求
+    #[serde(default)]
+    log_header_stats: bool,
+    // 访问日志中增加Referer和User-Agent两列，请求中没有时记为-
+    #[serde(default)]
+    log_referer_user_agent: bool,
+    // 访问日志中增加TLS握手时客户端发送的SNI主机名一列，明文连接或没有发送时记为-
+    #[serde(default)]
+    log_tls_sni: bool,
+    // 请求体之后多出的数据：discard丢弃后再处理，reject返回400；保持的连接上的流水线请求不受影响
+    #[serde(default)]
+    trailing_data: TrailingData,
+    // 方法名不是全大写时：normalize转成大写后处理，strict返回400
+    #[serde(default)]
+    method_case: MethodCase,
+    // POST、PUT、PATCH请求既没有Content-Length也不是chunked时：require返回411，
+    // wait在unframed_body_wait_ms内继续接收，把收到的数据作为请求体并补上Content-Length
+    #[serde(default)]
+    unframed_body: UnframedBody,
+    #[serde(default = "default_unframed_body_wait_ms")]
+    unframed_body_wait_ms: u64,
+    // 请求头中已废弃的折叠行（以空格或制表符开头的续行）：reject返回400，unfold合并到上一个头部后处理
+    #[serde(default)]
+    header_folding: HeaderFolding,
+    // 按客户端IP的访问控制，IPv4或IPv6的CIDR：先检查deny，再检查allow，不允许访问时返回403
+    #[serde(default)]
+    deny: Vec<String>,
+    #[serde(default)]
+    allow: Vec<String>,
+    // 两个列表都不匹配时是否允许访问，未设置时allow非空则拒绝，否则允许
+    #[serde(default)]
+    access_default: Option<AccessDefault>,
+    // 加载配置时由deny、allow和access_default生成，没有任何规则时为None
+    #[serde(skip)]
+    access: Option<AccessList>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum AccessDefault {
+    Allow,
+    Deny,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum HeaderFolding {
+    #[default]
+    Reject,
+    Unfold,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum UnframedBody {
+    #[default]
+    Require,
+    Wait,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum MethodCase {
+    #[default]
+    Normalize,
+    Strict,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum TrailingData {
+    #[default]
+    Discard,
+    Reject,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Http09Mode {
+    #[default]
+    Reject,
+    Respond,
 }
 
 #[derive(Deserialize, Clone)]
@@ -39,38 +452,546 @@ struct TypeInfo {
     name: String,
 }
 
+/// 一个站点的类型和对应的配置，服务器本身、虚拟主机和请求头路由都是站点
+struct Site<'a> {
+    server_type: &'a TypeInfo,
+    static_config: Option<&'a StaticConfig>,
+    proxy_config: Option<&'a ProxyConfig>,
+    redirect_config: Option<&'a RedirectConfig>,
+}
+
+impl ServerConfig {
+    fn site(&self) -> Site<'_> {
+        Site {
+            server_type: &self.server_type,
+            static_config: self.static_config.as_ref(),
+            proxy_config: self.proxy_config.as_ref(),
+            redirect_config: self.redirect_config.as_ref(),
+        }
+    }
+}
+
+impl VirtualHost {
+    fn site(&self) -> Site<'_> {
+        Site {
+            server_type: &self.server_type,
+            static_config: self.static_config.as_ref(),
+            proxy_config: self.proxy_config.as_ref(),
+            redirect_config: self.redirect_config.as_ref(),
+        }
+    }
+}
+
+impl HeaderRoute {
+    fn site(&self) -> Site<'_> {
+        Site {
+            server_type: &self.server_type,
+            static_config: self.static_config.as_ref(),
+            proxy_config: self.proxy_config.as_ref(),
+            redirect_config: self.redirect_config.as_ref(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+struct RedirectConfig {
+    // 重定向的目标地址，{host}替换为请求的主机名（不含端口），{path}替换为请求路径（包括查询参数）
+    target: String,
+    // 重定向的状态码：301、302、307或308
+    #[serde(default = "default_redirect_status")]
+    status: u16,
+}
+
+fn default_redirect_status() -> u16 {
+    301
+}
+
 #[derive(Deserialize, Clone)]
 struct StaticConfig {
     webroot: String,
     index: String,
+    // 文件不存在时转发到的后端
+    #[serde(default)]
+    fallback_proxy: Option<ProxyConfig>,
+    // 客户端偏好JSON时目录返回的index文件，例如index.json
+    #[serde(default)]
+    json_index: Option<String>,
+    // 目录中没有index文件时逐级向上使用父目录的index文件，最多到webroot
+    #[serde(default)]
+    index_fallback_to_parent: bool,
+    // 客户端支持时对文本类文件进行gzip压缩
+    #[serde(default)]
+    gzip: bool,
+    // 小于该字节数的文件不压缩；代理的响应不压缩，不受此限制；旧名称gzip_min_length仍然可用
+    #[serde(default = "default_compression_min_size", alias = "gzip_min_length")]
+    compression_min_size: usize,
+    // 无法根据扩展名识别类型时使用的Content-Type
+    #[serde(default = "default_content_type")]
+    default_content_type: String,
+    // 目录中没有index文件时生成目录列表
+    #[serde(default)]
+    autoindex: bool,
+    // 不小于该字节数的文件从磁盘分块发送，不读入内存，也不压缩
+    #[serde(default = "default_stream_min_size")]
+    stream_min_size: u64,
+    // 读到的字节数与文件大小不一致（读取过程中文件被修改）时：abort不发送内容，log只记录日志并发送读到的内容
+    #[serde(default)]
+    short_read: ShortRead,
+    // 按状态码配置的错误页面，路径相对于webroot，例如{ 404 = "404.html" }
+    #[serde(default)]
+    error_pages: HashMap<u16, String>,
+    // 单页应用的入口文件，路径相对于webroot；页面请求的文件不存在时返回它而不是404
+    #[serde(default)]
+    fallback: Option<String>,
+}
+
+fn default_worker_queue_capacity() -> usize {
+    64
+}
+
+fn default_timeout_secs() -> u64 {
+    30
+}
+
+fn default_keepalive_timeout_secs() -> u64 {
+    5
+}
+
+fn default_max_header_size() -> usize {
+    8192
+}
+
+fn default_unframed_body_wait_ms() -> u64 {
+    500
+}
+
+fn default_compression_min_size() -> usize {
+    1024
+}
+
+fn default_stream_min_size() -> u64 {
+    1024 * 1024
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ShortRead {
+    #[default]
+    Abort,
+    Log,
+}
+
+fn default_content_type() -> String {
+    String::from("application/octet-stream")
 }
 
 #[derive(Deserialize, Clone, Debug)]
 struct ProxyConfig {
-    backend: String,
+    // 一个后端地址或多个后端地址的列表，多个时按balance分配请求
+    backend: Backends,
+    // 多个后端之间分配请求的方式：round_robin轮流，random随机
+    #[serde(default)]
+    balance: Balance,
     modify_host: bool,
     header_host: String,
     modify_server: bool,
+    // 允许转发的请求方法，未设置时不限制
+    #[serde(default)]
+    allowed_methods: Option<Vec<String>>,
+    // 响应缓冲模式：full读完整个响应再转发，stream边收边转发
+    #[serde(default)]
+    proxy_buffering: ProxyBuffering,
+    // 请求缓冲模式：full读完整个请求再连接后端，stream收到头部就连接后端并边收边转发请求体
+    #[serde(default)]
+    request_buffering: RequestBuffering,
+    // 请求体的最大字节数（分块编码时包括分块格式本身），超过时返回413；流式转发时按已转发的字节累计，超过后中断；
+    // 完整缓冲时未配置也最多读入DEFAULT_MAX_BODY_SIZE
+    #[serde(default)]
+    max_body_size: Option<u64>,
+    // 后端接受连接后开始响应的最长等待时间（秒），超时返回504
+    #[serde(default)]
+    backend_first_byte_timeout: Option<u64>,
+    // 将Location/Content-Location中的后端地址改写为客户端访问的地址
+    #[serde(default)]
+    rewrite_location: bool,
+    // 是否在内部跟随后端返回的重定向
+    #[serde(default)]
+    follow_redirects: bool,
+    // 最多跟随的重定向次数，超过时返回508
+    #[serde(default = "default_max_redirects")]
+    max_redirects: usize,
+    // 是否作为正向代理处理CONNECT隧道
+    #[serde(default)]
+    connect_tunnel: bool,
+    // 发给后端的Accept-Encoding，空字符串表示去掉该头部，未设置时原样转发
+    #[serde(default)]
+    backend_accept_encoding: Option<String>,
+    // 转发前规范化请求头：名称规范大小写、去掉值两端空白、展开折叠行
+    #[serde(default)]
+    normalize_headers: bool,
+    // 健康检查是否反映后端状态，后端无法连接时返回503
+    #[serde(default)]
+    health_check_backend: bool,
+    // 备用后端，按顺序在前一个后端满足proxy_next_upstream条件时尝试
+    #[serde(default)]
+    backup_backends: Vec<String>,
+    // 换到下一个后端的条件：error、timeout、http_500、http_502、http_503、http_504等
+    #[serde(default = "default_proxy_next_upstream")]
+    proxy_next_upstream: Vec<String>,
+    // 总尝试次数上限，未设置时每个后端最多尝试一次
+    #[serde(default)]
+    proxy_next_upstream_tries: Option<usize>,
+    // 一次代理请求所有连接、重试、读取响应头和响应体共用的总超时秒数，读取响应头之前用完时返回504，之后中断响应
+    #[serde(default)]
+    proxy_timeout_budget: Option<u64>,
+    // 是否去掉后端响应中暴露框架和版本信息的头部（见SENSITIVE_RESPONSE_HEADERS）
+    #[serde(default = "default_strip_sensitive_headers")]
+    strip_sensitive_headers: bool,
+    // 额外需要从后端响应中去掉的头部，名称不区分大小写
+    #[serde(default)]
+    remove_response_headers: Vec<String>,
+    // 转发给后端前设置的请求头，替换客户端发来的同名头部
+    #[serde(default)]
+    add_request_headers: HashMap<String, String>,
+    // 返回给客户端前设置的响应头，替换后端返回的同名头部，在remove_response_headers之后添加
+    #[serde(default)]
+    add_response_headers: HashMap<String, String>,
+    // 所属服务器生效的Server头配置，加载后由服务器配置填入
+    #[serde(skip)]
+    server_header: Option<String>,
+    // 所属服务器是否允许保持连接，加载后由服务器配置填入
+    #[serde(skip)]
+    keep_alive: bool,
+    // 后端为主机名时DNS解析结果的缓存时间（秒），0表示每个请求都重新解析
+    #[serde(default = "default_dns_ttl_secs")]
+    dns_ttl_secs: u64,
+    // 连续连接失败达到该次数的后端地址暂时不再分配请求，未设置时不摘除
+    #[serde(default)]
+    max_fails: Option<u32>,
+    // 被摘除的后端地址经过多少秒后重新尝试，再次失败时继续摘除
+    #[serde(default = "default_fail_timeout_secs")]
+    fail_timeout_secs: u64,
+    // 加载配置时解析出的主后端和备用后端地址，主后端在前
+    #[serde(skip)]
+    backend_addrs: Vec<BackendAddr>,
+    // 轮询时下一个请求使用的主后端，各工作线程共用
+    #[serde(skip)]
+    next_backend: Arc<AtomicUsize>,
+}
+
+/// 一个或多个后端，配置中可以写成字符串或字符串数组
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+enum Backends {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Backends {
+    fn urls(&self) -> &[String] {
+        match self {
+            Backends::One(url) => std::slice::from_ref(url),
+            Backends::Many(urls) => urls,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Balance {
+    #[default]
+    RoundRobin,
+    Random,
+}
+
+fn default_dns_ttl_secs() -> u64 {
+    30
+}
+
+fn default_fail_timeout_secs() -> u64 {
+    10
+}
+
+fn default_strip_sensitive_headers() -> bool {
+    true
+}
+
+fn default_proxy_next_upstream() -> Vec<String> {
+    vec![String::from("error"), String::from("timeout")]
+}
+
+fn default_max_redirects() -> usize {
+    5
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ProxyBuffering {
+    Full,
+    #[default]
+    Stream,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum RequestBuffering {
+    #[default]
+    Full,
+    Stream,
 }
 
 /// 加载并解析TOML配置文件
-fn load_config(path: &str) -> Config {
-    let mut config_file = File::open(path).expect("无法打开配置文件");
-    let mut config_contents = String::new();
-    config_file.read_to_string(&mut config_contents).expect("无法读取配置文件");
-    toml::from_str(&config_contents).expect("无法解析配置文件")
+fn load_config(path: &str) -> Result<Config, ConfigError> {
+    parse_config_file(path)
 }
 
 /// 加载并解析服务器配置
-fn load_server_config(path: &str) -> ServerConfig {
-    let mut server_file = File::open(path).expect("无法打开服务器配置文件");
-    let mut server_contents = String::new();
-    server_file.read_to_string(&mut server_contents).expect("无法读取服务器配置文件");
-    let config: ServerConfig = toml::from_str(&server_contents).expect("无法解析服务器配置文件");
+fn load_server_config(path: &str) -> Result<ServerConfig, ConfigError> {
+    let mut config: ServerConfig = parse_config_file(path)?;
+    // [rate_limit]相当于rate_limits中最后一条对所有请求生效的规则
+    if let Some(rate_limit) = &config.rate_limit {
+        let rule = RateLimitConfig {
+            path: default_rate_limit_path(),
+            requests: rate_limit.requests_per_second,
+            per_secs: 1,
+            burst: rate_limit.burst,
+        };
+        config.rate_limits.push(rule);
+    }
+    // 配置错误在启动时一次性报告，而不是等到第一个请求返回500
+    let problems = validate_server_config(&mut config);
+    if !problems.is_empty() {
+        return Err(ConfigError::Invalid(path.to_string(), problems));
+    }
+    for route in &mut config.header_routes {
+        route.pattern = route.value.as_deref().map(HeaderPattern::compile);
+    }
     println!("加载配置文件: {}", path);
     println!("服务器类型: {}", config.server_type.name);
     println!("代理配置: {:?}", config.proxy_config);
-    config
+    Ok(config)
+}
+
+/// 检查服务器配置，返回发现的全部问题；同时解析各代理的后端地址，保存供请求时使用
+fn validate_server_config(config: &mut ServerConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+    match &config.server.unix_socket {
+        Some(_) if !config.server.address.is_empty() => problems.push(String::from("unix_socket和address只能设置一个")),
+        Some(path) if path.is_empty() => problems.push(String::from("unix_socket不能为空")),
+        None if config.server.address.is_empty() => problems.push(String::from("需要设置address和port，或者unix_socket")),
+        _ => {}
+    }
+    // Unix socket的客户端没有IP地址，按IP的设置不会生效，访问控制应使用socket文件的权限
+    if config.server.unix_socket.is_some()
+        && (!config.server.allow.is_empty() || !config.server.deny.is_empty() || config.server.access_default.is_some()
+            || config.server.max_connections_per_ip.is_some() || !config.rate_limits.is_empty())
+    {
+        problems.push(String::from("unix_socket的客户端没有IP地址，不能使用allow、deny、access_default、max_connections_per_ip和rate_limits"));
+    }
+    check_site("", &config.site(), &mut problems);
+    for vhost in &config.vhosts {
+        let prefix = format!("虚拟主机 {}: ", vhost.hostnames.join(", "));
+        check_site(&prefix, &vhost.site(), &mut problems);
+    }
+    for route in &config.header_routes {
+        let prefix = format!("请求头路由 {}: ", route.header);
+        check_site(&prefix, &route.site(), &mut problems);
+    }
+    for rate_limit in &config.rate_limits {
+        if rate_limit.requests == 0 || rate_limit.per_secs == 0 || rate_limit.burst == Some(0) {
+            problems.push(format!("限流规则 {} 的requests、per_secs和burst必须大于0", rate_limit.path));
+        }
+    }
+    for proxy_config in proxy_configs_mut(config) {
+        if let Err(e) = resolve_backends(proxy_config) {
+            problems.push(e);
+        }
+    }
+    match access_list(&config.server) {
+        Ok(access) => config.server.access = access,
+        Err(e) => problems.extend(e),
+    }
+    problems
+}
+
+/// 解析deny和allow中的CIDR，没有配置任何访问控制时返回None
+fn access_list(server: &ServerInfo) -> Result<Option<AccessList>, Vec<String>> {
+    if server.deny.is_empty() && server.allow.is_empty() && server.access_default.is_none() {
+        return Ok(None);
+    }
+    let mut problems = Vec::new();
+    let mut parse = |cidrs: &[String], list: &str| -> Vec<Cidr> {
+        cidrs.iter()
+            .filter_map(|cidr| Cidr::parse(cidr).map_err(|e| problems.push(format!("{}中{}", list, e))).ok())
+            .collect()
+    };
+    let deny = parse(&server.deny, "deny");
+    let allow = parse(&server.allow, "allow");
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+    let default_allow = match server.access_default {
+        Some(access_default) => access_default == AccessDefault::Allow,
+        None => allow.is_empty(),
+    };
+    Ok(Some(AccessList::new(deny, allow, default_allow)))
+}
+
+/// 检查站点类型是否受支持，以及对应的配置块是否存在、webroot是否为目录
+fn check_site(prefix: &str, site: &Site, problems: &mut Vec<String>) {
+    match site.server_type.name.as_str() {
+        "static" => match site.static_config {
+            Some(static_config) => {
+                if !Path::new(&static_config.webroot).is_dir() {
+                    problems.push(format!("{}webroot不是目录: {}", prefix, static_config.webroot));
+                }
+                let mut invalid_codes: Vec<_> = static_config.error_pages.keys().filter(|code| !(400..=599).contains(*code)).collect();
+                invalid_codes.sort();
+                for code in invalid_codes {
+                    problems.push(format!("{}error_pages只能配置4xx和5xx状态码: {}", prefix, code));
+                }
+                if let Some(fallback) = &static_config.fallback
+                    && !Path::new(&static_config.webroot).join(fallback.trim_start_matches('/')).is_file()
+                {
+                    problems.push(format!("{}fallback文件不存在: {}", prefix, fallback));
+                }
+            }
+            None => problems.push(format!("{}类型为static但缺少[static]配置", prefix)),
+        },
+        "proxy" => match site.proxy_config {
+            Some(proxy_config) => {
+                let added = proxy_config.add_request_headers.iter().map(|header| ("add_request_headers", header))
+                    .chain(proxy_config.add_response_headers.iter().map(|header| ("add_response_headers", header)));
+                for (field, (name, value)) in added {
+                    if !is_header_name(name) {
+                        problems.push(format!("{}{}中的头部名称无效: {}", prefix, field, name));
+                    } else if FRAMING_HEADERS.iter().any(|framing| framing.eq_ignore_ascii_case(name)) {
+                        problems.push(format!("{}{}不能设置由代理管理的头部: {}", prefix, field, name));
+                    }
+                    if value.contains(['\r', '\n']) {
+                        problems.push(format!("{}{}中头部 {} 的值不能包含换行", prefix, field, name));
+                    }
+                }
+            }
+            None => problems.push(format!("{}类型为proxy但缺少[proxy]配置", prefix)),
+        },
+        "redirect" => match site.redirect_config {
+            Some(redirect_config) => {
+                if ![301, 302, 307, 308].contains(&redirect_config.status) {
+                    problems.push(format!("{}redirect的status只能是301、302、307或308: {}", prefix, redirect_config.status));
+                }
+                if redirect_config.target.contains(['\r', '\n']) {
+                    problems.push(format!("{}redirect的target不能包含换行", prefix));
+                }
+            }
+            None => problems.push(format!("{}类型为redirect但缺少[redirect]配置", prefix)),
+        },
+        other => problems.push(format!("{}不支持的类型: {}（可选static、proxy或redirect）", prefix, other)),
+    }
+}
+
+/// 决定消息如何分帧和连接如何管理的头部，由代理自己处理，不能通过配置设置
+const FRAMING_HEADERS: [&str; 4] = ["Content-Length", "Transfer-Encoding", "Connection", "Upgrade"];
+
+/// 是否为合法的头部名称（RFC 7230的token）
+fn is_header_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// 配置文件加载失败的原因
+#[derive(Debug)]
+enum ConfigError {
+    /// 文件不存在
+    NotFound(String),
+    /// 文件存在但无法读取
+    Read(String, io::Error),
+    /// TOML语法或字段错误，错误信息中带有行号和列号
+    Parse(String, toml::de::Error),
+    /// 语法正确但取值无效，包含发现的全部问题
+    Invalid(String, Vec<String>),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::NotFound(path) => write!(f, "配置文件不存在: {}", path),
+            ConfigError::Read(path, error) => write!(f, "无法读取配置文件 {}: {}", path, error),
+            ConfigError::Parse(path, error) => write!(f, "无法解析配置文件 {}: {}", path, error),
+            ConfigError::Invalid(path, problems) => {
+                write!(f, "配置文件 {} 无效:", path)?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// 读取并反序列化一个TOML配置文件，解析前先展开其中的环境变量引用
+fn parse_config_file<T: serde::de::DeserializeOwned>(path: &str) -> Result<T, ConfigError> {
+    let contents = std::fs::read_to_string(path).map_err(|error| match error.kind() {
+        ErrorKind::NotFound => ConfigError::NotFound(path.to_string()),
+        _ => ConfigError::Read(path.to_string(), error),
+    })?;
+    let contents = expand_env_vars(&contents).map_err(|problems| ConfigError::Invalid(path.to_string(), problems))?;
+    toml::from_str(&contents).map_err(|error| ConfigError::Parse(path.to_string(), error))
+}
+
+/// 把配置文本中的${NAME}替换为环境变量的值，$${表示字面的${；以#开头的注释行不展开
+///
+/// 在TOML解析之前按文本替换，因此既可以写在字符串中（backend = "${BACKEND}"），也可以直接作为数字（port = ${PORT}）
+fn expand_env_vars(contents: &str) -> Result<String, Vec<String>> {
+    let mut expanded = String::with_capacity(contents.len());
+    let mut problems = Vec::new();
+    for line in contents.split_inclusive('\n') {
+        if line.trim_start().starts_with('#') {
+            expanded.push_str(line);
+            continue;
+        }
+        let mut rest = line;
+        while let Some(start) = rest.find('$') {
+            expanded.push_str(&rest[..start]);
+            let reference = &rest[start..];
+            if let Some(after) = reference.strip_prefix("$${") {
+                expanded.push_str("${");
+                rest = after;
+                continue;
+            }
+            let Some(after) = reference.strip_prefix("${") else {
+                expanded.push('$');
+                rest = &reference[1..];
+                continue;
+            };
+            let Some(end) = after.find('}') else {
+                problems.push(format!("环境变量引用缺少}}: {}", reference.trim_end()));
+                rest = "";
+                break;
+            };
+            let name = &after[..end];
+            let problem = if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+                Some(format!("无效的环境变量名: ${{{}}}", name))
+            } else {
+                match env::var(name) {
+                    Ok(value) => {
+                        expanded.push_str(&value);
+                        None
+                    }
+                    Err(_) => Some(format!("环境变量 {} 未设置", name)),
+                }
+            };
+            if let Some(problem) = problem.filter(|problem| !problems.contains(problem)) {
+                problems.push(problem);
+            }
+            rest = &after[end + 1..];
+        }
+        expanded.push_str(rest);
+    }
+    if problems.is_empty() {
+        Ok(expanded)
+    } else {
+        Err(problems)
+    }
 }
 
 /// 从请求中提取路径
@@ -91,228 +1012,3804 @@ fn extract_path(buffer: &[u8]) -> String {
     }
 }
 
-/// 记录访问日志
-fn log_access(client_addr: &str, path: &str, status_code: u16) {
-    let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S");
-    println!("[{}] {} - {} - {}", timestamp, client_addr, path, status_code);
+/// 从请求中提取方法
+fn extract_method(request: &str) -> String {
+    request.split(' ').next().unwrap_or("").to_string()
 }
 
-/// 处理静态文件请求
-fn handle_static_request(static_config: &StaticConfig, path: &str) -> String {
-    // 如果路径为/，则返回index文件
-    let actual_path = if path == "/" {
-        &static_config.index
-    } else {
-        path
-    };
-    
-    let file_path = format!("{}/{}", static_config.webroot, actual_path);
-    match File::open(&file_path) {
-        Ok(mut file) => {
-            let mut contents = String::new();
-            match file.read_to_string(&mut contents) {
-                Ok(_) => {
-                    let mut response = String::from("HTTP/1.1 200 OK\r\n");
-                    response.push_str("Server: nextWeb/0.1.0\r\n");
-                    response.push_str("Content-Type: text/html; charset=utf-8\r\n");
-                    response.push_str("\r\n");
-                    response.push_str(&contents);
-                    response
-                }
-                Err(_) => {
-                    String::from("HTTP/1.1 500 Internal Server Error\r\n\r\n500 Internal Server Error")
-                }
-            }
-        }
-        Err(_) => {
-            String::from("HTTP/1.1 404 Not Found\r\n\r\n404 Not Found")
-        }
+/// 请求行只有方法和路径、没有版本号时视为HTTP/0.9请求，返回其中的路径
+fn http09_path(buffer: &[u8]) -> Option<String> {
+    let line_end = buffer.iter().position(|&b| b == b'\n')?;
+    let line = String::from_utf8_lossy(&buffer[..line_end]);
+    let mut tokens = line.split_whitespace();
+    match (tokens.next(), tokens.next(), tokens.next()) {
+        (Some("GET"), Some(path), None) => Some(path.to_string()),
+        _ => None,
     }
 }
 
-/// 处理代理请求
-fn handle_proxy_request(proxy_config: &ProxyConfig, request: &str) -> String {
-    use std::net::TcpStream;
-    use std::io::{Read, Write};
-    use std::time::Duration;
-    
-    // 解析后端服务器地址
-    let backend_url = proxy_config.backend.trim_start_matches("http://");
-    let (backend_host, backend_port_str) = match backend_url.split_once(':') {
-        Some((host, port)) => (host, port),
-        None => (backend_url, "80"),
-    };
-    
-    let backend_port: u16 = backend_port_str.parse().unwrap_or(80);
-    
-    // 连接到后端服务器
-    let backend_addr = format!("{}:{}", backend_host, backend_port);
-    let socket_addr: std::net::SocketAddr = backend_addr.parse().expect("Invalid backend address");
-    match TcpStream::connect_timeout(&socket_addr, Duration::from_secs(5)) {
-        Ok(mut backend_stream) => {
-            // 根据配置修改请求头
-            let modified_request = if proxy_config.modify_host {
-                // 替换Host头
-                let host_header = format!("Host: {}", proxy_config.header_host);
-                request.lines()
-                    .map(|line| {
-                        if line.starts_with("Host:") {
-                            host_header.clone()
-                        } else {
-                            line.to_string()
-                        }
-                    })
-                    .collect::<Vec<_>>()
-                    .join("\r\n")
-            } else {
-                request.to_string()
-            };
-            
-            // 发送请求到后端
-            if let Err(_) = backend_stream.write_all(modified_request.as_bytes()) {
-                return String::from("HTTP/1.1 502 Bad Gateway\r\n\r\n502 Bad Gateway");
-            }
-            
-            // 读取后端响应
-            let mut response_buffer = [0; 8192];
-            match backend_stream.read(&mut response_buffer) {
-                Ok(bytes_read) => {
-                    let mut response = String::from_utf8_lossy(&response_buffer[..bytes_read]).to_string();
-                    
-                    // 根据配置修改Server头
-                    if proxy_config.modify_server {
-                        // 提取原始Server头
-                        let original_server = response.lines()
-                            .find(|line| line.starts_with("Server:"))
-                            .map(|line| line.trim_start_matches("Server:").trim().to_string())
-                            .unwrap_or_else(|| "unknown".to_string());
-                        
-                        // 构建新的Server头
-                        let new_server_header = format!("Server: nextWeb({})/0.1.0", original_server);
-                        
-                        // 替换Server头
-                        response = response.lines()
-                            .map(|line| {
-                                if line.starts_with("Server:") {
-                                    new_server_header.clone()
-                                } else {
-                                    line.to_string()
-                                }
-                            })
-                            .collect::<Vec<_>>()
-                            .join("\r\n");
-                    }
-                    
-                    response
-                }
-                Err(_) => {
-                    String::from("HTTP/1.1 502 Bad Gateway\r\n\r\n502 Bad Gateway")
-                }
-            }
-        }
-        Err(_) => {
-            String::from("HTTP/1.1 502 Bad Gateway\r\n\r\n502 Bad Gateway")
-        }
+/// 请求行的长度，不含行尾的CRLF
+fn request_line_length(buffer: &[u8]) -> usize {
+    match buffer.iter().position(|&b| b == b'\n') {
+        Some(line_end) if line_end > 0 && buffer[line_end - 1] == b'\r' => line_end - 1,
+        Some(line_end) => line_end,
+        None => buffer.len(),
     }
 }
 
-/// 处理客户端请求
-fn handle_client(stream: &mut std::net::TcpStream, server_config: &ServerConfig) {
-    let client_addr = match stream.peer_addr() {
-        Ok(addr) => addr.to_string(),
-        Err(_) => String::from("unknown")
-    };
-    
-    let mut buffer = [0; 1024];
-    if let Err(_) = stream.read(&mut buffer) {
-        log_access(&client_addr, "-", 400);
-        return;
-    }
-    
-    // 将原始请求转换为字符串
-    let request = String::from_utf8_lossy(&buffer).to_string();
-    let path = extract_path(&buffer);
-    
-    let response = match server_config.server_type.name.as_str() {
-        "static" => {
-            match &server_config.static_config {
-                Some(static_config) => handle_static_request(static_config, &path),
-                None => String::from("HTTP/1.1 500 Internal Server Error\r\n\r\n500 Internal Server Error: Static configuration is missing")
-            }
-        }
-        "proxy" => {
-            match &server_config.proxy_config {
-                Some(proxy_config) => handle_proxy_request(proxy_config, &request),
-                None => String::from("HTTP/1.1 500 Internal Server Error\r\n\r\n500 Internal Server Error: Proxy configuration is missing")
-            }
-        }
-        _ => String::from("HTTP/1.1 501 Not Implemented\r\n\r\n501 Not Implemented")
-    };
-    
-    // 从响应中提取状态码
-    let status_code = if response.starts_with("HTTP/1.1 200") {
-        200
-    } else if response.starts_with("HTTP/1.1 404") {
-        404
-    } else if response.starts_with("HTTP/1.1 500") {
-        500
-    } else if response.starts_with("HTTP/1.1 501") {
-        501
-    } else if response.starts_with("HTTP/1.1 502") {
-        502
-    } else if response.starts_with("HTTP/1.1") {
-        // 直接返回原始状态码
-        let status_line = response.lines().next().unwrap();
-        let status_code_str = status_line.split(' ').nth(1).unwrap();
-        status_code_str.parse::<u16>().unwrap_or(500)
-    } else {
-        500
-    };
-    
-    log_access(&client_addr, &path, status_code);
-    send_response(stream, &response);
+/// 解析起始行之后的头部字段，遇到空行结束
+fn parse_headers(message: &str) -> Vec<(String, String)> {
+    message.lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        // 折叠的续行不是新的头部，其中的冒号不能当作名称和值的分隔
+        .filter(|line| !line.starts_with([' ', '\t']))
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect()
 }
 
-/// 发送HTTP响应
-fn send_response(stream: &mut std::net::TcpStream, response: &str) {
-    let _ = stream.write(response.as_bytes());
+/// 按名称查找头部（不区分大小写）
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
 }
 
-/// 启动服务器
-fn start_server(server: Server) {
-    let server_config = load_server_config(&server.config);
-    let address = format!("{}:{}", server_config.server.address, server_config.server.port);
-    let listener = TcpListener::bind(&address).expect("无法绑定端口");
-    println!("服务器 '{}' 监听于 {}", server.name, address);
-    
-    for stream in listener.incoming() {
-        match stream {
-            Ok(mut stream) => {
-                handle_client(&mut stream, &server_config);
-            }
-            Err(e) => {
-                eprintln!("接受连接失败: {}", e);
-            }
-        }
-    }
+/// 检查Transfer-Encoding是否只包含chunked或identity
+fn is_supported_transfer_encoding(headers: &[(String, String)]) -> bool {
+    headers.iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Transfer-Encoding"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|coding| coding.trim())
+        .all(|coding| coding.eq_ignore_ascii_case("chunked") || coding.eq_ignore_ascii_case("identity"))
 }
 
-fn main() {
-    println!("nextWeb 0.1.0");
+/// 日志级别，由响应状态码决定
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum LogLevel {
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    fn for_status(status_code: u16) -> LogLevel {
+        match status_code {
+            500.. => LogLevel::Error,
+            400.. => LogLevel::Warn,
+            _ => LogLevel::Info,
+        }
+    }
+}
+
+/// 日志输出，开启缓冲后只有错误级别的日志立即刷新
+struct LogWriter {
+    writer: BufWriter<io::Stdout>,
+    buffered: bool,
+}
+
+static LOG_WRITER: LazyLock<Mutex<LogWriter>> = LazyLock::new(|| {
+    Mutex::new(LogWriter { writer: BufWriter::new(io::stdout()), buffered: false })
+});
+
+const DEFAULT_LOG_TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// 日志时间戳的格式和时区，启动时根据配置设置一次
+struct LogTimestamp {
+    format: String,
+    timezone: LogTimezone,
+}
+
+static LOG_TIMESTAMP: OnceLock<LogTimestamp> = OnceLock::new();
+
+/// 设置日志时间戳的格式和时区，格式无效时返回错误
+fn configure_log_timestamp(format: &str, timezone: LogTimezone) -> Result<(), String> {
+    if chrono::format::StrftimeItems::new(format).any(|item| item == chrono::format::Item::Error) {
+        return Err(format!("无效的日志时间格式: {}", format));
+    }
+    let _ = LOG_TIMESTAMP.set(LogTimestamp { format: format.to_string(), timezone });
+    Ok(())
+}
+
+/// 当前时间的日志时间戳，未配置时使用本地时间和默认格式
+fn log_timestamp() -> String {
+    match LOG_TIMESTAMP.get() {
+        Some(LogTimestamp { format, timezone: LogTimezone::Utc }) => Utc::now().format(format).to_string(),
+        Some(LogTimestamp { format, timezone: LogTimezone::Local }) => Local::now().format(format).to_string(),
+        None => Local::now().format(DEFAULT_LOG_TIMESTAMP_FORMAT).to_string(),
+    }
+}
+
+/// 写出一行日志
+fn write_log_line(line: &str, level: LogLevel) {
+    let mut log = LOG_WRITER.lock().unwrap();
+    let _ = writeln!(log.writer, "{}", line);
+    if !log.buffered || level >= LogLevel::Error {
+        let _ = log.writer.flush();
+    }
+}
+
+/// 立即写出缓冲中的日志，退出前调用
+fn flush_log() {
+    let _ = LOG_WRITER.lock().unwrap().writer.flush();
+}
+
+/// 开启日志缓冲，并定期刷新缓冲区避免低频日志迟迟不出现
+fn enable_log_buffering() {
+    LOG_WRITER.lock().unwrap().buffered = true;
+    thread::spawn(|| loop {
+        thread::sleep(Duration::from_secs(1));
+        let _ = LOG_WRITER.lock().unwrap().writer.flush();
+    });
+}
+
+/// 一个服务器的访问日志输出，未配置文件时写到标准输出
+struct AccessLogOutput {
+    format: LogFormat,
+    // 多个工作线程共用一个文件，整行写完才释放锁，各行不会交错
+    file: Option<Mutex<BufWriter<File>>>,
+    buffered: bool,
+}
+
+impl AccessLogOutput {
+    /// 按配置打开访问日志文件，buffered与全局的log_buffering相同
+    fn open(config: &LogConfig, buffered: bool) -> Result<Arc<AccessLogOutput>, String> {
+        let file = match &config.file {
+            Some(path) => {
+                let file = std::fs::OpenOptions::new().create(true).append(true).open(path)
+                    .map_err(|e| format!("无法打开访问日志 {}: {}", path, e))?;
+                Some(Mutex::new(BufWriter::new(file)))
+            }
+            None if config.format == LogFormat::Binary => return Err(String::from("binary格式的访问日志需要设置file")),
+            None => None,
+        };
+        let access_log = Arc::new(AccessLogOutput { format: config.format, file, buffered });
+        // 与标准输出的日志一样定期刷新缓冲区
+        if buffered && access_log.file.is_some() {
+            let access_log = Arc::clone(&access_log);
+            thread::spawn(move || loop {
+                thread::sleep(Duration::from_secs(1));
+                access_log.flush();
+            });
+        }
+        Ok(access_log)
+    }
+
+    /// 写出一行日志，不缓冲或错误级别时立即刷新
+    fn write_line(&self, line: &str, level: LogLevel) {
+        if self.file.is_none() {
+            write_log_line(line, level);
+            return;
+        }
+        self.write_record(format!("{}\n", line).as_bytes(), level);
+    }
+    
+    /// 向日志文件写出一条完整的记录
+    fn write_record(&self, record: &[u8], level: LogLevel) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let mut file = file.lock().unwrap();
+        let _ = file.write_all(record);
+        if !self.buffered || level >= LogLevel::Error {
+            let _ = file.flush();
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap().flush();
+        }
+    }
+}
+
+/// 一个请求各阶段的耗时
+struct RequestTiming {
+    started: Instant,
+    // 访问日志写到哪里、使用哪种格式
+    access_log: Arc<AccessLogOutput>,
+    // 请求行，用于通用日志格式
+    request_line: Option<String>,
+    // 与后端交互的耗时，只有代理请求才有
+    upstream: Option<Duration>,
+    // 请求头的个数和总字节数，开启log_header_stats时才记录
+    header_stats: Option<(usize, usize)>,
+    // 请求的Referer和User-Agent，开启log_referer_user_agent时才记录
+    referer_user_agent: Option<(String, String)>,
+    // TLS握手时的SNI主机名，开启log_tls_sni时才记录
+    tls_sni: Option<String>,
+    // 写给客户端的字节数（包括头部），响应发送之后才知道
+    bytes_sent: Option<u64>,
+}
+
+impl RequestTiming {
+    fn start(access_log: &Arc<AccessLogOutput>) -> RequestTiming {
+        RequestTiming {
+            started: Instant::now(),
+            access_log: Arc::clone(access_log),
+            request_line: None,
+            upstream: None,
+            header_stats: None,
+            referer_user_agent: None,
+            tls_sni: None,
+            bytes_sent: None,
+        }
+    }
+}
+
+/// 记录访问日志，格式由服务器的[log]配置决定
+fn log_access(client_addr: &str, path: &str, status_code: u16, timing: &RequestTiming) {
+    let level = LogLevel::for_status(status_code);
+    let line = match timing.access_log.format {
+        LogFormat::Simple => simple_log_line(client_addr, path, status_code, timing),
+        LogFormat::Common => common_log_line(client_addr, status_code, timing),
+        LogFormat::Json => json_log_line(client_addr, path, status_code, timing),
+        LogFormat::Binary => {
+            timing.access_log.write_record(&binary_log_record(client_addr, status_code, timing).encode(), level);
+            return;
+        }
+    };
+    timing.access_log.write_line(&line, level);
+}
+
+/// 二进制格式的访问日志记录，格式见binlog::Record
+fn binary_log_record(client_addr: &str, status_code: u16, timing: &RequestTiming) -> binlog::Record {
+    let millis = |duration: Duration| u32::try_from(duration.as_millis()).unwrap_or(u32::MAX - 1);
+    let (referer, user_agent) = timing.referer_user_agent.clone().unwrap_or_default();
+    binlog::Record {
+        timestamp_millis: Utc::now().timestamp_millis(),
+        status_code,
+        duration_millis: millis(timing.started.elapsed()),
+        upstream_millis: timing.upstream.map(millis),
+        client_addr: client_addr.to_string(),
+        request_line: timing.request_line.clone().unwrap_or_default(),
+        referer,
+        user_agent,
+    }
+}
+
+/// 把二进制访问日志转换成文本输出到标准输出，用于查看和导入其他工具
+fn print_binary_log(path: &str) -> io::Result<()> {
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    binlog::read_records(io::BufReader::new(File::open(path)?), |record| {
+        let timestamp = chrono::DateTime::from_timestamp_millis(record.timestamp_millis)
+            .map_or_else(|| String::from("-"), |time| time.with_timezone(&Local).format(DEFAULT_LOG_TIMESTAMP_FORMAT).to_string());
+        let upstream = record.upstream_millis.map_or_else(|| String::from("-"), |millis| format!("{}ms", millis));
+        let _ = writeln!(stdout, "[{}] {} - \"{}\" - {} - {}ms - {} - \"{}\" \"{}\"", timestamp, record.client_addr,
+            quote_log_value(&record.request_line), record.status_code, record.duration_millis, upstream,
+            quote_log_value(&record.referer), quote_log_value(&record.user_agent));
+    })?;
+    stdout.flush()
+}
+
+/// 默认格式的访问日志，包括写出的字节数（包括头部）、总耗时和后端耗时（非代理请求记为-）
+fn simple_log_line(client_addr: &str, path: &str, status_code: u16, timing: &RequestTiming) -> String {
+    let timestamp = log_timestamp();
+    let upstream_time = match timing.upstream {
+        Some(upstream) => format!("{}ms", upstream.as_millis()),
+        None => String::from("-"),
+    };
+    let bytes_sent = timing.bytes_sent.map_or_else(|| String::from("-"), |bytes| format!("{}B", bytes));
+    let mut line = format!("[{}] {} - {} - {} - {} - {}ms - {}", timestamp, client_addr, path, status_code, bytes_sent,
+        timing.started.elapsed().as_millis(), upstream_time);
+    if let Some((count, size)) = timing.header_stats {
+        line.push_str(&format!(" - {} headers - {}B", count, size));
+    }
+    if let Some((referer, user_agent)) = &timing.referer_user_agent {
+        line.push_str(&format!(" - \"{}\" \"{}\"", quote_log_value(referer), quote_log_value(user_agent)));
+    }
+    if let Some(sni) = &timing.tls_sni {
+        line.push_str(&format!(" - sni={}", sni));
+    }
+    line
+}
+
+/// Apache通用日志格式，开启log_referer_user_agent时追加Referer和User-Agent成为combined格式，开启log_tls_sni时再追加SNI主机名
+///
+/// 字节数为写给客户端的字节数（包括头部），没有发送响应时记为-
+fn common_log_line(client_addr: &str, status_code: u16, timing: &RequestTiming) -> String {
+    let host = client_addr.parse::<SocketAddr>().map_or_else(|_| client_addr.to_string(), |addr| addr.ip().to_string());
+    let request_line = timing.request_line.as_deref().map_or_else(|| String::from("-"), quote_log_value);
+    let bytes_sent = timing.bytes_sent.map_or_else(|| String::from("-"), |bytes| bytes.to_string());
+    let mut line = format!("{} - - [{}] \"{}\" {} {}", host, common_log_timestamp(), request_line, status_code, bytes_sent);
+    if let Some((referer, user_agent)) = &timing.referer_user_agent {
+        line.push_str(&format!(" \"{}\" \"{}\"", quote_log_value(referer), quote_log_value(user_agent)));
+    }
+    if let Some(sni) = &timing.tls_sni {
+        line.push_str(&format!(" \"{}\"", quote_log_value(sni)));
+    }
+    line
+}
+
+/// JSON格式的访问日志，每个请求一行，不知道的值记为null
+fn json_log_line(client_addr: &str, path: &str, status_code: u16, timing: &RequestTiming) -> String {
+    let client_ip = client_addr.parse::<SocketAddr>().map_or_else(|_| client_addr.to_string(), |addr| addr.ip().to_string());
+    let method = timing.request_line.as_deref().and_then(|line| line.split_whitespace().next());
+    let millis = |duration: Duration| u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+    let mut record = serde_json::json!({
+        "timestamp": iso_log_timestamp(),
+        "client_ip": client_ip,
+        "method": method,
+        "path": Some(path).filter(|path| *path != "-"),
+        "status": status_code,
+        "bytes_sent": timing.bytes_sent,
+        "duration_ms": millis(timing.started.elapsed()),
+        "upstream_ms": timing.upstream.map(millis),
+    });
+    if let Some((count, size)) = timing.header_stats {
+        record["header_count"] = count.into();
+        record["header_bytes"] = size.into();
+    }
+    if let Some((referer, user_agent)) = &timing.referer_user_agent {
+        record["referer"] = referer.as_str().into();
+        record["user_agent"] = user_agent.as_str().into();
+    }
+    if let Some(sni) = &timing.tls_sni {
+        record["tls_sni"] = sni.as_str().into();
+    }
+    record.to_string()
+}
+
+/// ISO 8601格式的时间戳，精确到毫秒，时区与log_timezone一致
+fn iso_log_timestamp() -> String {
+    match LOG_TIMESTAMP.get() {
+        Some(LogTimestamp { timezone: LogTimezone::Utc, .. }) => Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        _ => Local::now().to_rfc3339_opts(SecondsFormat::Millis, false),
+    }
+}
+
+/// 通用日志格式的时间戳，如10/Oct/2024:13:55:36 +0000，时区与log_timezone一致
+fn common_log_timestamp() -> String {
+    const COMMON_LOG_TIMESTAMP_FORMAT: &str = "%d/%b/%Y:%H:%M:%S %z";
+    match LOG_TIMESTAMP.get() {
+        Some(LogTimestamp { timezone: LogTimezone::Utc, .. }) => Utc::now().format(COMMON_LOG_TIMESTAMP_FORMAT).to_string(),
+        _ => Local::now().format(COMMON_LOG_TIMESTAMP_FORMAT).to_string(),
+    }
+}
+
+/// 日志中用引号包围的值，转义其中的引号和反斜杠
+fn quote_log_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// 记录连接关闭日志，包括连接时长和处理的请求数
+fn log_connection_closed(client_addr: &str, duration: Duration, requests: usize) {
+    let timestamp = log_timestamp();
+    let line = format!("[{}] {} - 连接关闭 - {}ms - {} 个请求", timestamp, client_addr, duration.as_millis(), requests);
+    write_log_line(&line, LogLevel::Info);
+}
+
+/// 记录连接建立日志
+fn log_connection_opened(client_addr: &str) {
+    let timestamp = log_timestamp();
+    let line = format!("[{}] {} - 连接建立", timestamp, client_addr);
+    write_log_line(&line, LogLevel::Info);
+}
+
+/// 处理函数的结果
+enum Outcome {
+    /// 本地生成的响应，由handle_request按服务器配置生成报文并发送
+    Response(HttpResponse),
+    /// 后端返回的原始响应报文，按原始字节发送
+    Raw(Vec<u8>),
+    /// 响应已直接写给客户端，只剩状态码用于记录日志，以及之后能否继续使用该连接
+    Streamed(u16, bool),
+    /// 本地文件，响应头与Response相同方式生成，正文在发送时从文件分块读取
+    File(Box<HttpResponse>, File, u64),
+}
+
+/// 根据扩展名确定Content-Type，无法识别时使用default
+fn content_type_for<'a>(file_path: &str, default: &'a str) -> &'a str {
+    let extension = Path::new(file_path).extension().and_then(|extension| extension.to_str()).map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") | Some("mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("svg") => "image/svg+xml",
+        Some("ico") => "image/x-icon",
+        Some("webp") => "image/webp",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("pdf") => "application/pdf",
+        _ => default,
+    }
+}
+
+/// 各文件旁的<文件名>.mime中指定的Content-Type，没有该文件时记为None
+static MIME_SIDECARS: LazyLock<Mutex<HashMap<String, Option<String>>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// 读取文件旁的<文件名>.mime作为Content-Type，结果会缓存，修改sidecar后重新加载配置或重启才能生效
+fn sidecar_content_type(file_path: &str) -> Option<String> {
+    let mut sidecars = MIME_SIDECARS.lock().unwrap();
+    sidecars.entry(file_path.to_string())
+        .or_insert_with(|| {
+            std::fs::read_to_string(format!("{}.mime", file_path)).ok()
+                .map(|contents| contents.trim().to_string())
+                .filter(|content_type| !content_type.is_empty())
+        })
+        .clone()
+}
+
+/// 根据Accept头判断客户端是否更偏好JSON而不是HTML
+fn prefers_json(accept: &str) -> bool {
+    let mut json_quality = 0.0;
+    let mut html_quality = 0.0;
+    for entry in accept.split(',') {
+        let mut parts = entry.split(';');
+        let media_type = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let quality = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if matches!(media_type.as_str(), "application/json" | "application/*" | "*/*") {
+            json_quality = f32::max(json_quality, quality);
+        }
+        if matches!(media_type.as_str(), "text/html" | "text/*" | "*/*") {
+            html_quality = f32::max(html_quality, quality);
+        }
+    }
+    json_quality > html_quality
+}
+
+/// 是否为浏览器打开页面的请求：Accept中明确接受text/html，并且路径最后一段没有扩展名或是.html/.htm
+///
+/// 脚本、样式和图片的请求不带text/html，或者带有扩展名，缺失时仍返回404
+fn is_page_request(path: &str, headers: &[(String, String)]) -> bool {
+    let name = path.rsplit('/').next().unwrap_or("");
+    let page_path = name.rsplit_once('.')
+        .is_none_or(|(_, extension)| extension.eq_ignore_ascii_case("html") || extension.eq_ignore_ascii_case("htm"));
+    let accepts_html = find_header(headers, "Accept").is_some_and(|accept| accept.split(',').any(|entry| {
+        let mut parts = entry.split(';');
+        let media_type = parts.next().unwrap_or("").trim();
+        let quality = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        media_type.eq_ignore_ascii_case("text/html") && quality > 0.0
+    }));
+    page_path && accepts_html
+}
+
+/// 从目录的父目录开始逐级向上查找index文件，最多查到webroot；path已经过static_file_path检查，不含..
+fn parent_index_file(webroot: &str, path: &str, index: &str) -> Option<String> {
+    let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+    (0..segments.len()).rev()
+        .map(|depth| format!("{}/{}/{}", webroot, segments[..depth].join("/"), index))
+        .find(|candidate| Path::new(candidate).is_file())
+}
+
+/// 去掉查询参数并进行百分号解码，得到要访问的文件路径
+///
+/// 转义无效或解码结果不是UTF-8时返回400，包含..或NUL时返回403
+fn static_file_path(path: &str) -> Result<String, HttpResponse> {
+    let path = path.split(['?', '#']).next().unwrap_or("");
+    let Some(decoded) = percent_decode(path) else {
+        return Err(HttpResponse::error(400).detail("Invalid percent-encoding in path"));
+    };
+    if decoded.contains('\0') || decoded.split(['/', '\\']).any(|segment| segment == "..") {
+        return Err(HttpResponse::error(403));
+    }
+    Ok(decoded)
+}
+
+/// 百分号解码，%后面不是两位十六进制数或解码结果不是UTF-8时返回None
+fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut position = 0;
+    while position < bytes.len() {
+        if bytes[position] != b'%' {
+            decoded.push(bytes[position]);
+            position += 1;
+            continue;
+        }
+        let byte = bytes.get(position + 1..position + 3)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())?;
+        decoded.push(byte);
+        position += 3;
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// 处理静态文件请求
+fn handle_static_request(static_config: &StaticConfig, path: &str, request: &[u8], client: &mut ClientStream, timing: &mut RequestTiming) -> Outcome {
+    match serve_static(static_config, path, request, client, timing) {
+        Outcome::Response(response) if response.is_error() => Outcome::Response(with_error_page(static_config, response)),
+        outcome => outcome,
+    }
+}
+
+/// 配置了该状态码的错误页面时用页面内容替换内置错误响应的正文，页面读取失败时保留内置错误响应
+fn with_error_page(static_config: &StaticConfig, response: HttpResponse) -> HttpResponse {
+    let Some(page) = static_config.error_pages.get(&response.status()) else {
+        return response;
+    };
+    let page_path = format!("{}/{}", static_config.webroot, page.trim_start_matches('/'));
+    match std::fs::read(&page_path) {
+        Ok(contents) => response.page(&static_content_type(&page_path, static_config), contents),
+        Err(e) => {
+            eprintln!("无法读取错误页面 {}: {}", page_path, e);
+            response
+        }
+    }
+}
+
+/// 处理静态文件请求，本地产生的错误由handle_static_request换成配置的错误页面
+fn serve_static(static_config: &StaticConfig, path: &str, request: &[u8], client: &mut ClientStream, timing: &mut RequestTiming) -> Outcome {
+    // 静态文件只支持GET和HEAD，配置了回源代理时其余方法交给后端处理
+    let method = extract_method(&String::from_utf8_lossy(request));
+    if method != "GET" && method != "HEAD" {
+        return match &static_config.fallback_proxy {
+            Some(proxy_config) => handle_proxy_request(proxy_config, request, client, timing),
+            None => Outcome::Response(HttpResponse::error(405).header("Allow", &allow_header(None))),
+        };
+    }
+    
+    // 解码后的路径不能包含..，防止访问webroot之外的文件
+    let path = match static_file_path(path) {
+        Ok(path) => path,
+        Err(response) => return Outcome::Response(response),
+    };
+    let path = path.as_str();
+    let mut file_path = format!("{}/{}", static_config.webroot, path);
+    let mut negotiated = false;
+    let headers = parse_headers(&String::from_utf8_lossy(request));
+    
+    // 目录请求返回其中的index文件
+    let mut requested_directory = None;
+    if path == "/" || Path::new(&file_path).is_dir() {
+        let directory = file_path.trim_end_matches('/').to_string();
+        requested_directory = Some(directory.clone());
+        file_path = format!("{}/{}", directory, static_config.index);
+        if static_config.index_fallback_to_parent
+            && !Path::new(&file_path).is_file()
+            && let Some(parent_index) = parent_index_file(&static_config.webroot, path, &static_config.index)
+        {
+            file_path = parent_index;
+        }
+        
+        // 客户端偏好JSON且目录中存在json_index时优先返回它
+        if let Some(json_index) = &static_config.json_index {
+            negotiated = true;
+            let json_path = format!("{}/{}", directory, json_index);
+            let accept = find_header(&headers, "Accept").unwrap_or("");
+            if prefers_json(accept) && Path::new(&json_path).is_file() {
+                file_path = json_path;
+            }
+        }
+    }
+    
+    // 符号链接等解析之后的真实路径也必须位于webroot之内
+    if let Ok(canonical_path) = std::fs::canonicalize(&file_path)
+        && let Ok(canonical_webroot) = std::fs::canonicalize(&static_config.webroot)
+        && !canonical_path.starts_with(&canonical_webroot)
+    {
+        return Outcome::Response(HttpResponse::error(403));
+    }
+    
+    // 没有可用的index文件时列出目录内容
+    if static_config.autoindex
+        && let Some(directory) = requested_directory
+        && !Path::new(&file_path).is_file()
+    {
+        return Outcome::Response(autoindex_response(&static_config.webroot, &directory, path));
+    }
+    
+    // 单页应用的路由没有对应的文件，页面请求返回入口文件，由前端路由处理；优先于回源代理
+    if let Some(fallback) = &static_config.fallback
+        && !Path::new(&file_path).is_file()
+        && is_page_request(path, &headers)
+    {
+        file_path = format!("{}/{}", static_config.webroot, fallback.trim_start_matches('/'));
+        negotiated = true;
+    }
+    
+    match File::open(&file_path) {
+        Ok(mut file) => {
+            let metadata = file.metadata().ok();
+            // 大文件不读入内存，由handle_request写完头部后分块发送
+            if let Some(metadata) = metadata.as_ref().filter(|metadata| metadata.len() >= static_config.stream_min_size) {
+                let etag = metadata_etag(metadata);
+                if let Some(if_match) = find_header(&headers, "If-Match")
+                    && !etag_matches(if_match, &etag)
+                {
+                    return Outcome::Response(HttpResponse::error(412).header("ETag", &etag));
+                }
+                let last_modified = metadata.modified().ok();
+                if is_not_modified(&headers, &etag, last_modified) {
+                    return Outcome::Response(not_modified_response(&etag, last_modified, negotiated.then_some("Accept")));
+                }
+                let length = metadata.len();
+                let range = match requested_range(&headers, &etag, length) {
+                    Some(ByteRange::Unsatisfiable) => return Outcome::Response(range_not_satisfiable(length)),
+                    Some(ByteRange::Satisfiable(start, end)) => Some((start, end)),
+                    None => None,
+                };
+                let mut response = HttpResponse::new(if range.is_some() { 206 } else { 200 })
+                    .content_type(&static_content_type(&file_path, static_config))
+                    .header("ETag", &etag)
+                    .header("Accept-Ranges", "bytes");
+                if let Some(last_modified) = last_modified {
+                    response = response.header("Last-Modified", &http_date(last_modified));
+                }
+                if negotiated {
+                    response = response.header("Vary", "Accept");
+                }
+                let (offset, count) = match range {
+                    Some((start, end)) => {
+                        response = response.header("Content-Range", &format!("bytes {}-{}/{}", start, end, length));
+                        (start, end - start + 1)
+                    }
+                    None => (0, length),
+                };
+                if offset > 0 && file.seek(SeekFrom::Start(offset)).is_err() {
+                    return Outcome::Response(HttpResponse::error(500));
+                }
+                return Outcome::File(Box::new(response), file, count);
+            }
+            
+            // 按原始字节读取，图片、字体等二进制文件不能经过String
+            let mut contents = Vec::new();
+            let expected_length = metadata.as_ref().map(|metadata| metadata.len());
+            let last_modified = metadata.and_then(|metadata| metadata.modified().ok());
+            match file.read_to_end(&mut contents) {
+                // 读取过程中文件被截断或追加，内容可能新旧混杂，默认不发送；log模式下按读到的内容和长度发送
+                Ok(read_length) if expected_length.is_some_and(|length| length != read_length as u64)
+                    && static_config.short_read == ShortRead::Abort =>
+                {
+                    eprintln!("文件在读取过程中被修改: {} (预期 {} 字节，读到 {} 字节)",
+                        file_path, expected_length.unwrap_or(0), read_length);
+                    Outcome::Response(HttpResponse::error(500))
+                }
+                Ok(read_length) => {
+                    if expected_length.is_some_and(|length| length != read_length as u64) {
+                        eprintln!("文件在读取过程中被修改，发送读到的内容: {} (预期 {} 字节，读到 {} 字节)",
+                            file_path, expected_length.unwrap_or(0), read_length);
+                    }
+                    let etag = content_etag(&contents);
+                    if let Some(if_match) = find_header(&headers, "If-Match")
+                        && !etag_matches(if_match, &etag)
+                    {
+                        return Outcome::Response(HttpResponse::error(412).header("ETag", &etag));
+                    }
+                    
+                    let content_type = static_content_type(&file_path, static_config);
+                    // 文本类文件在客户端支持时gzip压缩，压缩后的表示使用不同的ETag
+                    let compressible = static_config.gzip && is_compressible(&content_type);
+                    let accepts_gzip = find_header(&headers, "Accept-Encoding").is_some_and(accepts_gzip);
+                    let compress = compressible && accepts_gzip && contents.len() >= static_config.compression_min_size;
+                    let vary = match (negotiated, compressible) {
+                        (true, true) => Some("Accept, Accept-Encoding"),
+                        (true, false) => Some("Accept"),
+                        (false, true) => Some("Accept-Encoding"),
+                        (false, false) => None,
+                    };
+                    
+                    // 按将要发送的表示比较ETag，客户端缓存仍然有效时不必压缩
+                    let representation_etag = if compress { gzip_etag(&etag) } else { etag.clone() };
+                    if is_not_modified(&headers, &representation_etag, last_modified) {
+                        return Outcome::Response(not_modified_response(&representation_etag, last_modified, vary));
+                    }
+                    let compressed = if compress { gzip(&contents).ok() } else { None };
+                    let (contents, etag, encoding) = match compressed {
+                        Some(compressed) => (compressed, gzip_etag(&etag), Some("gzip")),
+                        None => (contents, etag, None),
+                    };
+                    
+                    // 压缩后的表示不支持Range，始终返回完整内容
+                    let range = match encoding {
+                        Some(_) => None,
+                        None => requested_range(&headers, &etag, contents.len() as u64),
+                    };
+                    let (status, contents, content_range) = match range {
+                        Some(ByteRange::Unsatisfiable) => return Outcome::Response(range_not_satisfiable(contents.len() as u64)),
+                        Some(ByteRange::Satisfiable(start, end)) => {
+                            let content_range = format!("bytes {}-{}/{}", start, end, contents.len());
+                            (206, contents[start as usize..=end as usize].to_vec(), Some(content_range))
+                        }
+                        None => (200, contents, None),
+                    };
+                    
+                    let mut response = HttpResponse::new(status)
+                        .content_type(&content_type)
+                        .header("ETag", &etag);
+                    match encoding {
+                        Some(encoding) => response = response.header("Content-Encoding", encoding),
+                        None => response = response.header("Accept-Ranges", "bytes"),
+                    }
+                    if let Some(content_range) = content_range {
+                        response = response.header("Content-Range", &content_range);
+                    }
+                    if let Some(last_modified) = last_modified {
+                        response = response.header("Last-Modified", &http_date(last_modified));
+                    }
+                    if let Some(vary) = vary {
+                        response = response.header("Vary", vary);
+                    }
+                    Outcome::Response(response.body(contents))
+                }
+                Err(_) => Outcome::Response(HttpResponse::error(500))
+            }
+        }
+        Err(_) => {
+            // 本地文件不存在时回源到后端
+            match &static_config.fallback_proxy {
+                Some(proxy_config) => handle_proxy_request(proxy_config, request, client, timing),
+                None => Outcome::Response(HttpResponse::error(404))
+            }
+        }
+    }
+}
+
+/// 目录列表页面，目录经过符号链接解析后同样必须位于webroot之内
+fn autoindex_response(webroot: &str, directory: &str, path: &str) -> HttpResponse {
+    let inside_webroot = match (std::fs::canonicalize(directory), std::fs::canonicalize(webroot)) {
+        (Ok(canonical_directory), Ok(canonical_webroot)) => canonical_directory.starts_with(&canonical_webroot),
+        _ => false,
+    };
+    if !inside_webroot {
+        return HttpResponse::error(403);
+    }
+    match listing::directory_listing(Path::new(directory), path) {
+        Ok(listing) => HttpResponse::new(200).content_type("text/html; charset=utf-8").body(listing),
+        Err(e) => {
+            eprintln!("无法列出目录 {}: {}", directory, e);
+            HttpResponse::error(500)
+        }
+    }
+}
+
+/// 适合压缩的Content-Type，图片、字体等已经压缩过的格式不再压缩
+fn is_compressible(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    media_type.starts_with("text/")
+        || matches!(media_type.as_str(), "application/json" | "application/javascript" | "application/xml" | "image/svg+xml")
+}
+
+/// Accept-Encoding中是否接受gzip，q=0表示明确拒绝
+fn accepts_gzip(accept_encoding: &str) -> bool {
+    accept_encoding.split(',').any(|entry| {
+        let mut parts = entry.split(';');
+        let coding = parts.next().unwrap_or("").trim();
+        let quality = parts
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .and_then(|quality| quality.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        (coding.eq_ignore_ascii_case("gzip") || coding == "*") && quality > 0.0
+    })
+}
+
+/// gzip压缩
+fn gzip(contents: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(contents)?;
+    encoder.finish()
+}
+
+/// 根据文件内容生成强ETag
+fn content_etag(contents: &[u8]) -> String {
+    let hex: String = digest::md5(contents).iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("\"{}\"", hex)
+}
+
+/// Range头请求的字节范围
+enum ByteRange {
+    /// 起止位置，包含两端
+    Satisfiable(u64, u64),
+    /// 起始位置超出文件长度，返回416
+    Unsatisfiable,
+}
+
+/// 请求中有效的单个字节范围；没有Range头、If-Range与当前ETag不符或者无法解析时返回None，按完整内容响应
+fn requested_range(headers: &[(String, String)], etag: &str, length: u64) -> Option<ByteRange> {
+    let range = find_header(headers, "Range")?;
+    // If-Range中的日期无法与ETag比较，按不匹配处理，返回完整内容总是安全的
+    if let Some(if_range) = find_header(headers, "If-Range")
+        && if_range.trim() != etag
+    {
+        return None;
+    }
+    parse_range(range, length)
+}
+
+/// 解析bytes=start-end、bytes=start-和bytes=-suffix，多个范围不支持，按完整内容响应
+fn parse_range(value: &str, length: u64) -> Option<ByteRange> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    if start.is_empty() {
+        // 最后suffix个字节，超过文件长度时为整个文件
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 || length == 0 {
+            return Some(ByteRange::Unsatisfiable);
+        }
+        return Some(ByteRange::Satisfiable(length.saturating_sub(suffix), length - 1));
+    }
+    let start: u64 = start.parse().ok()?;
+    let end: u64 = if end.is_empty() { u64::MAX } else { end.parse().ok()? };
+    if end < start {
+        return None;
+    }
+    if start >= length {
+        return Some(ByteRange::Unsatisfiable);
+    }
+    Some(ByteRange::Satisfiable(start, end.min(length - 1)))
+}
+
+/// 416响应，Content-Range给出完整长度
+fn range_not_satisfiable(length: u64) -> HttpResponse {
+    HttpResponse::error(416).header("Content-Range", &format!("bytes */{}", length))
+}
+
+/// 分块发送的大文件不计算内容摘要，ETag由文件大小和修改时间生成
+fn metadata_etag(metadata: &std::fs::Metadata) -> String {
+    let modified = metadata.modified().ok()
+        .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |modified| modified.as_secs());
+    format!("\"{:x}-{:x}\"", modified, metadata.len())
+}
+
+/// 静态文件的Content-Type，优先使用旁路文件中指定的类型
+fn static_content_type(file_path: &str, static_config: &StaticConfig) -> String {
+    match sidecar_content_type(file_path) {
+        Some(content_type) => content_type,
+        None => content_type_for(file_path, &static_config.default_content_type).to_string(),
+    }
+}
+
+/// 从文件分块读取length字节写给客户端，内存中只保留一个缓冲区
+///
+/// 客户端断开或文件在发送过程中被截断时返回错误，此时响应不完整，调用方应关闭连接
+fn send_file_body(stream: &mut impl Write, file: File, length: u64) -> io::Result<()> {
+    let mut file = file.take(length);
+    let mut buffer = vec![0; FILE_CHUNK_SIZE];
+    let mut sent = 0;
+    while sent < length {
+        let bytes_read = file.read(&mut buffer)?;
+        if bytes_read == 0 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, format!("文件在发送过程中被截断 (预期 {} 字节，读到 {} 字节)", length, sent)));
+        }
+        write_fully(stream, &buffer[..bytes_read])?;
+        sent += bytes_read as u64;
+    }
+    Ok(())
+}
+
+/// 分块发送文件时每次读取的字节数
+const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// 压缩后的表示使用的ETag
+fn gzip_etag(etag: &str) -> String {
+    format!("{}-gzip\"", etag.trim_end_matches('"'))
+}
+
+/// 客户端缓存的版本是否仍然有效
+///
+/// 有If-None-Match时只按它弱比较（忽略W/前缀），不再看If-Modified-Since；修改时间精确到秒
+fn is_not_modified(headers: &[(String, String)], etag: &str, modified: Option<std::time::SystemTime>) -> bool {
+    if let Some(if_none_match) = find_header(headers, "If-None-Match") {
+        let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+        return if_none_match.trim() == "*" || if_none_match.split(',').any(|candidate| opaque(candidate) == opaque(etag));
+    }
+    match (find_header(headers, "If-Modified-Since"), modified) {
+        (Some(since), Some(modified)) => DateTime::parse_from_rfc2822(since.trim())
+            .is_ok_and(|since| DateTime::<Utc>::from(modified).timestamp() <= since.timestamp()),
+        _ => false,
+    }
+}
+
+/// 304响应，带上与完整响应相同的验证器和Vary，没有正文
+fn not_modified_response(etag: &str, modified: Option<std::time::SystemTime>, vary: Option<&str>) -> HttpResponse {
+    let mut response = HttpResponse::new(304).header("ETag", etag);
+    if let Some(modified) = modified {
+        response = response.header("Last-Modified", &http_date(modified));
+    }
+    if let Some(vary) = vary {
+        response = response.header("Vary", vary);
+    }
+    response
+}
+
+/// HTTP日期格式（IMF-fixdate），例如Sun, 06 Nov 1994 08:49:37 GMT
+fn http_date(time: std::time::SystemTime) -> String {
+    DateTime::<Utc>::from(time).format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// If-Match是否与当前ETag匹配，按强比较，弱ETag永远不匹配
+fn etag_matches(if_match: &str, etag: &str) -> bool {
+    if_match.trim() == "*" || if_match.split(',').any(|candidate| candidate.trim() == etag)
+}
+
+/// 连接后端的超时时间
+const BACKEND_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 没有配置allowed_methods的代理在Allow头中列出的方法
+const FORWARDED_METHODS: [&str; 7] = ["GET", "HEAD", "POST", "PUT", "DELETE", "OPTIONS", "PATCH"];
+
+/// 405响应中Allow头的值：代理按allowed_methods生成，没有代理时静态文件只支持GET和HEAD；CONNECT隧道不是普通请求，不列出
+fn allow_header(proxy_config: Option<&ProxyConfig>) -> String {
+    let methods: Vec<&str> = match proxy_config {
+        Some(ProxyConfig { allowed_methods: Some(allowed_methods), .. }) => allowed_methods.iter().map(String::as_str).collect(),
+        Some(_) => FORWARDED_METHODS.to_vec(),
+        None => vec!["GET", "HEAD"],
+    };
+    methods.into_iter().filter(|method| *method != "CONNECT").collect::<Vec<_>>().join(", ")
+}
+
+/// 处理代理请求
+fn handle_proxy_request(proxy_config: &ProxyConfig, request: &[u8], client: &mut ClientStream, timing: &mut RequestTiming) -> Outcome {
+    let method = extract_method(&String::from_utf8_lossy(request));
+    
+    // 检查请求方法是否允许，Allow头根据配置生成
+    if let Some(allowed_methods) = &proxy_config.allowed_methods
+        && !allowed_methods.iter().any(|allowed| allowed == &method)
+    {
+        return Outcome::Response(HttpResponse::error(405).header("Allow", &allow_header(Some(proxy_config))));
+    }
+    
+    // 声明的长度或已经收到的请求体超过上限时不连接后端；流式转发时请求体还没读完，响应后关闭连接
+    if let Some(max_body_size) = body_size_limit(proxy_config)
+        && request_body_exceeds(request, max_body_size)
+    {
+        return Outcome::Response(HttpResponse::error(413).header("Connection", "close"));
+    }
+    
+    // 单独统计与后端交互的耗时
+    let upstream_started = Instant::now();
+    let outcome = forward_to_backend(proxy_config, request, &method, client);
+    timing.upstream = Some(upstream_started.elapsed());
+    outcome
+}
+
+/// 后端地址，IP地址和Unix socket在加载配置时确定，主机名在请求时通过DNS解析
+#[derive(Clone, Debug, PartialEq)]
+enum BackendAddr {
+    Ip(SocketAddr),
+    Host(String, u16),
+    Unix(PathBuf),
+}
+
+/// 解析后端服务器地址，格式为http://IP或主机名[:端口]，端口默认80；unix:路径表示Unix domain socket
+fn backend_socket_addr(backend: &str) -> Result<BackendAddr, String> {
+    if let Some(path) = backend.strip_prefix("unix:") {
+        if path.is_empty() {
+            return Err(format!("无效的后端地址: {}", backend));
+        }
+        return Ok(BackendAddr::Unix(PathBuf::from(path)));
+    }
+    let backend_url = backend.trim_start_matches("http://").trim_end_matches('/');
+    let (backend_host, backend_port_str) = match backend_url.split_once(':') {
+        Some((host, port)) => (host, port),
+        None => (backend_url, "80"),
+    };
+    
+    let backend_port: u16 = backend_port_str.parse()
+        .map_err(|_| format!("无效的后端端口: {}", backend))?;
+    
+    if let Ok(ip) = backend_host.parse::<IpAddr>() {
+        return Ok(BackendAddr::Ip(SocketAddr::new(ip, backend_port)));
+    }
+    let valid_host = !backend_host.is_empty()
+        && backend_host.split('.').all(|label| {
+            !label.is_empty() && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        });
+    if !valid_host {
+        return Err(format!("无效的后端地址: {}", backend));
+    }
+    Ok(BackendAddr::Host(backend_host.to_ascii_lowercase(), backend_port))
+}
+
+/// 解析代理配置中主后端和备用后端的地址，保存供请求时使用
+fn resolve_backends(proxy_config: &mut ProxyConfig) -> Result<(), String> {
+    if proxy_config.backend.urls().is_empty() {
+        return Err(String::from("backend至少需要一个后端地址"));
+    }
+    proxy_config.backend_addrs = proxy_config.backend.urls().iter()
+        .chain(&proxy_config.backup_backends)
+        .map(|backend| backend_socket_addr(backend))
+        .collect::<Result<_, _>>()?;
+    Ok(())
+}
+
+/// 主机名后端的DNS解析结果
+struct ResolvedBackend {
+    addrs: Vec<SocketAddr>,
+    resolved_at: Instant,
+    // 下一个请求从第几个地址开始，多个地址之间轮询
+    next: usize,
+}
+
+/// 按主机名和端口缓存的DNS解析结果，所有服务器共用
+static BACKEND_DNS: LazyLock<Mutex<HashMap<(String, u16), ResolvedBackend>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// 取得主机名后端的地址，缓存超过ttl时重新解析
+///
+/// 每次调用返回的地址从下一个位置开始，重新解析失败时继续使用上次的结果
+fn resolve_backend_host(host: &str, port: u16, ttl: Duration) -> Vec<SocketAddr> {
+    let key = (host.to_string(), port);
+    let cached_fresh = BACKEND_DNS.lock().unwrap().get(&key)
+        .is_some_and(|resolved| resolved.resolved_at.elapsed() < ttl);
+    // 解析期间不持有锁，避免一个慢的DNS查询阻塞其他后端
+    if !cached_fresh {
+        match (host, port).to_socket_addrs() {
+            Ok(addrs) => {
+                let mut addrs: Vec<SocketAddr> = addrs.collect();
+                addrs.dedup();
+                let mut cache = BACKEND_DNS.lock().unwrap();
+                let next = cache.get(&key).map_or(0, |resolved| resolved.next);
+                cache.insert(key.clone(), ResolvedBackend { addrs, resolved_at: Instant::now(), next });
+            }
+            Err(e) => eprintln!("无法解析后端主机名 {}: {}", host, e),
+        }
+    }
+    
+    let mut cache = BACKEND_DNS.lock().unwrap();
+    let Some(resolved) = cache.get_mut(&key) else {
+        return Vec::new();
+    };
+    let mut addrs = resolved.addrs.clone();
+    if !addrs.is_empty() {
+        let start = resolved.next % addrs.len();
+        addrs.rotate_left(start);
+        resolved.next = resolved.next.wrapping_add(1);
+    }
+    addrs
+}
+
+/// 主后端和备用后端各自的地址，按配置顺序排列；主机名为解析到的全部地址，无法解析时为空
+fn backend_candidates(proxy_config: &ProxyConfig) -> Vec<Vec<SocketAddress>> {
+    let ttl = Duration::from_secs(proxy_config.dns_ttl_secs);
+    proxy_config.backend_addrs.iter()
+        .map(|backend| match backend {
+            BackendAddr::Ip(addr) => vec![SocketAddress::Tcp(*addr)],
+            BackendAddr::Host(host, port) => resolve_backend_host(host, *port, ttl).into_iter().map(SocketAddress::Tcp).collect(),
+            BackendAddr::Unix(path) => vec![SocketAddress::Unix(path.clone())],
+        })
+        .collect()
+}
+
+/// 本次请求尝试后端的顺序：按balance选出第一个主后端，其余主后端依次在后，备用后端排在最后
+fn balanced_candidates(proxy_config: &ProxyConfig) -> Vec<Vec<SocketAddress>> {
+    let mut candidates = backend_candidates(proxy_config);
+    let primary = proxy_config.backend.urls().len().min(candidates.len());
+    if primary > 1 {
+        let start = match proxy_config.balance {
+            Balance::RoundRobin => proxy_config.next_backend.fetch_add(1, Ordering::Relaxed),
+            Balance::Random => random_index(),
+        };
+        candidates[..primary].rotate_left(start % primary);
+    }
+    
+    // 跳过被摘除的地址；全部被摘除时仍然全部尝试，总比直接返回502好
+    if proxy_config.max_fails.is_some() {
+        let statuses = BACKEND_STATUS.lock().unwrap();
+        let available: Vec<Vec<SocketAddress>> = candidates.iter()
+            .map(|addrs| addrs.iter()
+                .filter(|addr| !statuses.get(*addr).is_some_and(|status| status.is_ejected(proxy_config)))
+                .cloned()
+                .collect())
+            .collect();
+        if available.iter().any(|addrs| !addrs.is_empty()) {
+            candidates = available;
+        }
+    }
+    candidates.retain(|addrs| !addrs.is_empty());
+    candidates
+}
+
+/// 不依赖额外的库取一个随机数：每个RandomState的种子不同，对同一个值的哈希结果也不同
+fn random_index() -> usize {
+    RandomState::new().build_hasher().finish() as usize
+}
+
+/// 一个后端地址最近的连接结果，由代理请求和健康检查记录
+#[derive(Default)]
+struct BackendStatus {
+    successes: u64,
+    failures: u64,
+    last_success: Option<Instant>,
+    last_failure: Option<(Instant, String)>,
+    // 最近一次成功之后连续失败的次数
+    consecutive_failures: u32,
+}
+
+impl BackendStatus {
+    /// 按代理的max_fails和fail_timeout_secs，该地址当前是否被摘除
+    fn is_ejected(&self, proxy_config: &ProxyConfig) -> bool {
+        let Some(max_fails) = proxy_config.max_fails else {
+            return false;
+        };
+        let cooldown = Duration::from_secs(proxy_config.fail_timeout_secs);
+        self.consecutive_failures >= max_fails
+            && self.last_failure.as_ref().is_some_and(|(failed_at, _)| failed_at.elapsed() < cooldown)
+    }
+}
+
+/// 按地址记录的后端连接结果，所有服务器共用
+static BACKEND_STATUS: LazyLock<Mutex<HashMap<SocketAddress, BackendStatus>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// 记录一次连接后端的结果
+fn record_backend_connect(backend_addr: &SocketAddress, result: Result<(), &io::Error>) {
+    let mut statuses = BACKEND_STATUS.lock().unwrap();
+    let status = statuses.entry(backend_addr.clone()).or_default();
+    match result {
+        Ok(()) => {
+            status.successes += 1;
+            status.last_success = Some(Instant::now());
+            status.consecutive_failures = 0;
+        }
+        Err(e) => {
+            status.failures += 1;
+            status.last_failure = Some((Instant::now(), e.to_string()));
+            status.consecutive_failures = status.consecutive_failures.saturating_add(1);
+        }
+    }
+}
+
+/// 健康检查的响应，JSON中包含服务器名称、类型、运行时间，代理服务器还包含各后端地址最近的连接结果
+///
+/// 预热完成前返回503；配置了health_check_backend的代理先主动连接各后端，全部无法连接时返回503
+fn health_response(state: &ServerState) -> HttpResponse {
+    let live = state.live();
+    let server_config = &live.config;
+    let proxy_config = server_config.proxy_config.as_ref().filter(|_| server_config.server_type.name == "proxy");
+    let backend_addrs: Vec<SocketAddress> = proxy_config.map(backend_candidates).unwrap_or_default().into_iter().flatten().collect();
+    
+    let ready = state.ready.load(Ordering::SeqCst);
+    let mut healthy = true;
+    if proxy_config.is_some_and(|proxy_config| proxy_config.health_check_backend) {
+        // 任意一个后端可以连接即视为健康
+        healthy = backend_addrs.iter().any(|backend_addr| {
+            match backend_addr.connect(BACKEND_CONNECT_TIMEOUT) {
+                Ok(_) => {
+                    record_backend_connect(backend_addr, Ok(()));
+                    true
+                }
+                Err(e) => {
+                    eprintln!("健康检查: 后端不可用 {}: {}", backend_addr, e);
+                    record_backend_connect(backend_addr, Err(&e));
+                    false
+                }
+            }
+        });
+    }
+    
+    let mut body = serde_json::json!({
+        "status": if !ready { "starting" } else if healthy { "ok" } else { "unavailable" },
+        "ready": ready,
+        "server": server_config.name,
+        "type": server_config.server_type.name,
+        "uptime_secs": state.started.elapsed().as_secs(),
+    });
+    if proxy_config.is_some() {
+        let statuses = BACKEND_STATUS.lock().unwrap();
+        let backends: Vec<_> = backend_addrs.iter().map(|backend_addr| {
+            let status = statuses.get(backend_addr);
+            // 最近一次连接成功为true，失败为false，还没有连接过为null
+            let reachable = status.and_then(|status| match (status.last_success, &status.last_failure) {
+                (Some(success), Some((failure, _))) => Some(success > *failure),
+                (Some(_), None) => Some(true),
+                (None, Some(_)) => Some(false),
+                (None, None) => None,
+            });
+            serde_json::json!({
+                "address": backend_addr.to_string(),
+                "reachable": reachable,
+                "successes": status.map_or(0, |status| status.successes),
+                "failures": status.map_or(0, |status| status.failures),
+                "last_error": status.and_then(|status| status.last_failure.as_ref()).map(|(_, error)| error),
+                "ejected": proxy_config.is_some_and(|proxy_config| status.is_some_and(|status| status.is_ejected(proxy_config))),
+            })
+        }).collect();
+        body["backends"] = serde_json::Value::from(backends);
+    }
+    HttpResponse::new(if ready && healthy { 200 } else { 503 })
+        .content_type("application/json")
+        .header("Cache-Control", "no-store")
+        .body(body.to_string())
+}
+
+/// 把请求转发给后端并把响应交给客户端
+fn forward_to_backend(proxy_config: &ProxyConfig, request: &[u8], method: &str, client: &mut ClientStream) -> Outcome {
+    let mut modified_request = if proxy_config.normalize_headers {
+        normalize_request_headers(request)
+    } else {
+        request.to_vec()
+    };
+    
+    // WebSocket握手需要把Upgrade交给后端，后端返回101之后在两个连接之间直接转发
+    let request_headers = parse_headers(&String::from_utf8_lossy(request));
+    let websocket = websocket_upgrade(&request_headers);
+    
+    // 逐跳头部只对客户端到本服务器这一跳有效，后端连接单独管理，每个请求用完即关闭
+    modified_request = strip_request_hop_by_hop(&modified_request, websocket);
+    
+    // 告诉后端真实的客户端地址、协议和请求的Host，需要在改写Host之前进行
+    modified_request = add_forwarded_headers(&modified_request, client);
+    
+    // 根据配置修改请求头，其余字节原样转发
+    if proxy_config.modify_host {
+        modified_request = replace_request_header(&modified_request, "Host", Some(&proxy_config.header_host));
+    }
+    
+    // 改写或去掉发给后端的Accept-Encoding，避免后端返回无法再处理的压缩内容
+    if let Some(accept_encoding) = &proxy_config.backend_accept_encoding {
+        let value = Some(accept_encoding.as_str()).filter(|value| !value.is_empty());
+        modified_request = replace_request_header(&modified_request, "Accept-Encoding", value);
+    }
+    
+    // 配置的请求头替换客户端发来的同名头部
+    for (name, value) in &proxy_config.add_request_headers {
+        modified_request = set_request_header(&modified_request, name, value);
+    }
+    
+    let timeouts = BackendTimeouts {
+        first_byte: proxy_config.backend_first_byte_timeout.map(Duration::from_secs),
+        deadline: proxy_config.proxy_timeout_budget.map(|budget| Instant::now() + Duration::from_secs(budget)),
+    };
+    // 流式转发请求体时，客户端还没发完的部分在连接后端之后边读边写
+    let mut client_body = match proxy_config.request_buffering {
+        RequestBuffering::Stream => ClientBody::remaining(request, client, proxy_config.max_body_size),
+        RequestBuffering::Full => None,
+    };
+    let streams_body = client_body.is_some();
+    let (mut target_addr, mut backend_response) = match exchange_with_upstreams(proxy_config, &modified_request, &timeouts, client_body.as_mut()) {
+        Ok(exchanged) => exchanged,
+        Err(outcome) => return outcome,
+    };
+    let mut outgoing_request = modified_request;
+    let mut redirects = 0;
+    let BackendResponse { stream: mut backend_stream, received, head_end } = loop {
+        // 已经流式发出的请求体无法再发给重定向的目标
+        if !proxy_config.follow_redirects || streams_body {
+            break backend_response;
+        }
+        
+        // 在内部跟随后端的重定向，超过上限视为重定向循环
+        let head = String::from_utf8_lossy(&backend_response.received[..backend_response.head_end]).to_string();
+        let status_code = response_status_code(&head);
+        let location = find_header(&parse_headers(&head), "Location").map(str::to_string);
+        let next = match location {
+            Some(location) if matches!(status_code, 301 | 302 | 303 | 307 | 308) => {
+                redirect_request(&outgoing_request, status_code, &location, &target_addr, &proxy_config.backend_addrs)
+            }
+            _ => None,
+        };
+        match next {
+            Some(_) if redirects >= proxy_config.max_redirects => {
+                eprintln!("后端重定向超过 {} 次: {}", proxy_config.max_redirects, target_addr);
+                return Outcome::Response(HttpResponse::error(508));
+            }
+            Some((next_addr, next_request)) => {
+                redirects += 1;
+                target_addr = next_addr;
+                outgoing_request = next_request;
+                backend_response = match exchange_with_backend(std::slice::from_ref(&target_addr), &outgoing_request, &timeouts, None) {
+                    Ok((_, backend_response)) => backend_response,
+                    Err((_, outcome)) => return outcome,
+                };
+            }
+            None => break backend_response,
+        }
+    };
+    
+    let head = String::from_utf8_lossy(&received[..head_end]).to_string();
+    let response_headers = parse_headers(&head);
+    
+    // 后端使用了无法处理的传输编码
+    if !is_supported_transfer_encoding(&response_headers) {
+        return Outcome::Response(HttpResponse::error(502));
+    }
+    
+    // 去掉后端这一跳的逐跳头部，并告知客户端本次响应后关闭连接；同意升级时保留Upgrade
+    let switching_protocols = websocket.is_some() && response_status_code(&head) == 101;
+    let head = if switching_protocols {
+        upgrade_response_head(&head, &response_headers)
+    } else {
+        strip_response_hop_by_hop(&head, &response_headers)
+    };
+    
+    // 去掉暴露后端实现细节的头部
+    let head = if proxy_config.strip_sensitive_headers {
+        remove_header_lines(&head, &SENSITIVE_RESPONSE_HEADERS)
+    } else {
+        head
+    };
+    let head = remove_header_lines(&head, &proxy_config.remove_response_headers);
+    
+    // 根据配置修改Server头，只改动头部不触及响应体
+    let head = match &proxy_config.server_header {
+        _ if !proxy_config.modify_server => head,
+        None => rewrite_server_header(&head),
+        Some(value) if value.is_empty() => remove_header_lines(&head, &["Server"]),
+        Some(value) => format!("{}\r\nServer: {}", remove_header_lines(&head, &["Server"]), expand_server_header(value)),
+    };
+    
+    // 配置的响应头替换后端返回的同名头部
+    let head = set_response_headers(&head, &proxy_config.add_response_headers);
+    
+    // 重定向地址中的后端主机替换为客户端请求的主机
+    let head = match find_header(&request_headers, "Host") {
+        Some(public_host) if proxy_config.rewrite_location => {
+            rewrite_location_headers(&head, proxy_config, public_host)
+        }
+        _ => head,
+    };
+    
+    if switching_protocols {
+        return relay_upgraded(client, backend_stream, &head, &received[head_end + 4..]);
+    }
+    
+    let status_code = response_status_code(&head);
+    let framing = BodyFraming::for_response(method, status_code, &response_headers);
+    let mut tracker = BodyTracker::new(framing);
+    let received_body = &received[head_end + 4..];
+    let received_body = &received_body[..tracker.feed(received_body)];
+    
+    // 事件流(SSE)不能缓冲，无论缓冲模式如何都边收边转发
+    let is_event_stream = find_header(&response_headers, "Content-Type")
+        .is_some_and(|content_type| content_type.starts_with("text/event-stream"));
+    if is_event_stream || proxy_config.proxy_buffering == ProxyBuffering::Stream {
+        if is_event_stream {
+            // 关闭Nagle算法，事件到达后立即发出
+            let _ = client.socket().set_nodelay(true);
+        }
+        // 客户端要求保持连接且响应有明确的结束位置时，转发完后继续使用该连接
+        let keep_alive = proxy_config.keep_alive
+            && framing != BodyFraming::UntilClose
+            && client_wants_keep_alive(&String::from_utf8_lossy(request), &request_headers);
+        let head = if keep_alive {
+            format!("{}\r\nConnection: keep-alive", remove_header_lines(&head, &["Connection"]))
+        } else {
+            head
+        };
+        let mut first_chunk = format!("{}\r\n\r\n", head).into_bytes();
+        first_chunk.extend_from_slice(received_body);
+        // 事件流可以长时间保持，不受代理总超时限制
+        let body_timeouts = if is_event_stream { &BackendTimeouts { first_byte: None, deadline: None } } else { &timeouts };
+        let completed = forward_response_body(&mut backend_stream, client, &first_chunk, &mut tracker, body_timeouts);
+        return Outcome::Streamed(status_code, keep_alive && completed);
+    }
+    
+    // 完整缓冲模式：读完整个响应后再发给客户端
+    let mut body = received_body.to_vec();
+    let mut buffer = [0; 8192];
+    let truncated = loop {
+        if tracker.is_complete() {
+            break false;
+        }
+        if timeouts.limit_read(&backend_stream).is_err() {
+            break true;
+        }
+        match backend_stream.read(&mut buffer) {
+            // 没有长度的响应体以关闭连接结束，其他情况说明响应体没有收完
+            Ok(0) => break framing != BodyFraming::UntilClose,
+            Err(_) => break true,
+            Ok(bytes_read) => {
+                let consumed = tracker.feed(&buffer[..bytes_read]);
+                body.extend_from_slice(&buffer[..consumed]);
+            }
+        }
+    };
+
+    // 残缺的响应体配上后端原来的Content-Length会让客户端当作完整的响应
+    if truncated {
+        eprintln!("后端在响应体完整之前断开或超时: {}", target_addr);
+        return Outcome::Response(HttpResponse::error(502).header("Connection", "close"));
+    }
+
+    // 响应体按原始字节拼接，二进制内容不能经过String
+    let mut response = format!("{}\r\n\r\n", head).into_bytes();
+    response.extend_from_slice(&body);
+    Outcome::Raw(response)
+}
+
+/// 未配置max_body_size时读入内存或丢弃的请求体的上限
+const DEFAULT_MAX_BODY_SIZE: u64 = 64 * 1024 * 1024;
+
+/// 请求体的上限：配置了max_body_size时使用配置；未配置时完整缓冲的请求体使用默认上限，流式转发不限制
+fn body_size_limit(proxy_config: &ProxyConfig) -> Option<u64> {
+    match proxy_config.request_buffering {
+        RequestBuffering::Full => Some(proxy_config.max_body_size.unwrap_or(DEFAULT_MAX_BODY_SIZE)),
+        RequestBuffering::Stream => proxy_config.max_body_size,
+    }
+}
+
+/// 请求声明的Content-Length或已经收到的请求体是否超过max_body_size
+fn request_body_exceeds(request: &[u8], max_body_size: u64) -> bool {
+    let Some(head_end) = find_head_end(request) else {
+        return false;
+    };
+    let headers = parse_headers(&String::from_utf8_lossy(&request[..head_end]));
+    let declared = find_header(&headers, "Content-Length").and_then(|length| length.trim().parse::<u64>().ok());
+    let mut tracker = BodyTracker::new(BodyFraming::from_headers(&headers).unwrap_or(BodyFraming::Empty));
+    let received = tracker.feed(&request[head_end + 4..]) as u64;
+    declared.is_some_and(|length| length > max_body_size) || received > max_body_size
+}
+
+/// 流式转发时客户端尚未发完的请求体
+struct ClientBody<'a> {
+    stream: &'a mut ClientStream,
+    tracker: BodyTracker,
+    // 已经转发的请求体字节数及其上限
+    forwarded: u64,
+    max_body_size: Option<u64>,
+    // 是否已经开始从客户端读取剩余的请求体，之后请求无法再发给别的后端
+    started: bool,
+}
+
+enum ClientBodyError {
+    /// 累计的请求体超过了max_body_size
+    TooLarge,
+    Io(io::Error),
+}
+
+impl<'a> ClientBody<'a> {
+    /// 请求中已收到的请求体不完整时，返回剩余部分的读取状态
+    fn remaining(request: &[u8], stream: &'a mut ClientStream, max_body_size: Option<u64>) -> Option<ClientBody<'a>> {
+        let head_end = find_head_end(request)?;
+        let headers = parse_headers(&String::from_utf8_lossy(&request[..head_end]));
+        let mut tracker = BodyTracker::new(BodyFraming::from_headers(&headers).unwrap_or(BodyFraming::Empty));
+        let forwarded = tracker.feed(&request[head_end + 4..]) as u64;
+        (!tracker.is_complete()).then_some(ClientBody { stream, tracker, forwarded, max_body_size, started: false })
+    }
+    
+    /// 把剩余的请求体边读边写给后端，内存中只保留一个缓冲区
+    fn forward_to(&mut self, backend: &mut Socket) -> Result<(), ClientBodyError> {
+        self.started = true;
+        let mut buffer = [0; 8192];
+        while !self.tracker.is_complete() {
+            let bytes_read = self.stream.read(&mut buffer).map_err(ClientBodyError::Io)?;
+            if bytes_read == 0 {
+                return Err(ClientBodyError::Io(io::Error::new(ErrorKind::UnexpectedEof, "客户端在请求体结束之前关闭了连接")));
+            }
+            let consumed = self.tracker.feed(&buffer[..bytes_read]);
+            self.forwarded += consumed as u64;
+            if self.max_body_size.is_some_and(|max_body_size| self.forwarded > max_body_size) {
+                return Err(ClientBodyError::TooLarge);
+            }
+            backend.write_all(&buffer[..consumed]).map_err(ClientBodyError::Io)?;
+        }
+        Ok(())
+    }
+}
+
+/// 已读取完响应头的后端连接
+struct BackendResponse {
+    stream: Socket,
+    received: Vec<u8>,
+    head_end: usize,
+}
+
+/// 依次尝试主后端和备用后端，满足proxy_next_upstream中的条件时换下一个，返回最后一次的结果
+///
+/// 请求体流式转发时，开始读取剩余的请求体之后不再换后端，读走的请求体无法再发给下一个后端；
+/// 连接失败等发生在这之前的错误仍然可以换下一个后端
+fn exchange_with_upstreams(proxy_config: &ProxyConfig, request: &[u8], timeouts: &BackendTimeouts, mut client_body: Option<&mut ClientBody>) -> Result<(SocketAddress, BackendResponse), Outcome> {
+    let candidates = balanced_candidates(proxy_config);
+    // 主机名全部无法解析时没有可以尝试的后端
+    if candidates.is_empty() {
+        return Err(Outcome::Response(HttpResponse::error(502)));
+    }
+    let tries = proxy_config.proxy_next_upstream_tries.unwrap_or(candidates.len()).clamp(1, candidates.len());
+    
+    let mut result = None;
+    for (attempt, backend_addrs) in candidates.iter().take(tries).enumerate() {
+        let (condition, exchanged) = match exchange_with_backend(backend_addrs, request, timeouts, client_body.as_deref_mut()) {
+            Ok((backend_addr, backend_response)) => {
+                let head = String::from_utf8_lossy(&backend_response.received[..backend_response.head_end]).to_string();
+                (format!("http_{}", response_status_code(&head)), Ok((backend_addr, backend_response)))
+            }
+            Err((condition, outcome)) => (condition.to_string(), Err(outcome)),
+        };
+        result = Some(exchanged);
+        if attempt + 1 == tries || !proxy_config.proxy_next_upstream.contains(&condition)
+            || client_body.as_ref().is_some_and(|client_body| client_body.started)
+        {
+            break;
+        }
+        if timeouts.remaining().is_none() {
+            eprintln!("代理总超时已用完，不再尝试下一个后端");
+            return Err(Outcome::Response(HttpResponse::error(504)));
+        }
+        eprintln!("后端 {} 触发 {}，尝试下一个后端", backend_addrs[0], condition);
+    }
+    result.expect("至少有一个后端")
+}
+
+/// 与后端交互的超时设置
+struct BackendTimeouts {
+    // 等待响应第一个字节的超时
+    first_byte: Option<Duration>,
+    // 整个代理请求的截止时间，由proxy_timeout_budget决定，包括读取和转发响应体
+    deadline: Option<Instant>,
+}
+
+impl BackendTimeouts {
+    /// 距离截止时间的剩余时间，没有截止时间时为Duration::MAX，已经用完时返回None
+    fn remaining(&self) -> Option<Duration> {
+        match self.deadline {
+            Some(deadline) => Some(deadline.saturating_duration_since(Instant::now())).filter(|left| !left.is_zero()),
+            None => Some(Duration::MAX),
+        }
+    }
+    
+    /// 有截止时间时把剩余时间设为后端连接的读取超时，没有截止时间时不限制；已经用完时返回超时错误
+    fn limit_read(&self, stream: &Socket) -> io::Result<()> {
+        if self.deadline.is_none() {
+            return stream.set_read_timeout(None);
+        }
+        match self.remaining() {
+            Some(remaining) => stream.set_read_timeout(Some(remaining)),
+            None => Err(io::Error::new(ErrorKind::TimedOut, "代理总超时已用完")),
+        }
+    }
+}
+
+/// 依次连接同一个后端的各个地址，返回第一个连接成功的地址；全部失败时返回最后一个错误
+///
+/// 还没有发送任何数据，换一个地址总是安全的，不受proxy_next_upstream限制
+fn connect_backend(backend_addrs: &[SocketAddress], timeouts: &BackendTimeouts) -> io::Result<(SocketAddress, Socket)> {
+    let mut last_error = io::Error::new(ErrorKind::NotFound, "没有可用的后端地址");
+    for backend_addr in backend_addrs {
+        let Some(remaining) = timeouts.remaining() else {
+            return Err(io::Error::new(ErrorKind::TimedOut, "代理总超时已用完"));
+        };
+        match backend_addr.connect(BACKEND_CONNECT_TIMEOUT.min(remaining)) {
+            Ok(stream) => {
+                record_backend_connect(backend_addr, Ok(()));
+                return Ok((backend_addr.clone(), stream));
+            }
+            Err(e) => {
+                record_backend_connect(backend_addr, Err(&e));
+                if backend_addrs.len() > 1 {
+                    eprintln!("无法连接后端地址 {}: {}", backend_addr, e);
+                }
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// 连接后端、发送请求并读取响应头，返回实际连接的地址；连接和首字节等待都不会超过总超时的剩余时间
+///
+/// 失败时返回对应的proxy_next_upstream条件（error或timeout）和应发给客户端的错误响应
+fn exchange_with_backend(backend_addrs: &[SocketAddress], request: &[u8], timeouts: &BackendTimeouts, client_body: Option<&mut ClientBody>) -> Result<(SocketAddress, BackendResponse), (&'static str, Outcome)> {
+    let Some(remaining) = timeouts.remaining() else {
+        return Err(("timeout", Outcome::Response(HttpResponse::error(504))));
+    };
+    let first_byte_timeout = match (timeouts.first_byte, timeouts.deadline) {
+        (Some(first_byte), _) => Some(first_byte.min(remaining)),
+        (None, Some(_)) => Some(remaining),
+        (None, None) => None,
+    };
+    
+    let (backend_addr, mut stream) = match connect_backend(backend_addrs, timeouts) {
+        Ok(connected) => connected,
+        Err(e) => {
+            // 等待后端超时与客户端发送过慢（408）不同，返回504；连接到一半用完总超时也是这种情况
+            if is_timeout(&e) {
+                match timeouts.remaining() {
+                    Some(_) => eprintln!("后端连接超时: {:?}", backend_addrs),
+                    None => eprintln!("连接后端时代理总超时已用完: {:?}", backend_addrs),
+                }
+                return Err(("timeout", Outcome::Response(HttpResponse::error(504))));
+            }
+            return Err(("error", Outcome::Response(HttpResponse::error(502))));
+        }
+    };
+    
+    // 发送请求到后端
+    if stream.write_all(request).is_err() {
+        return Err(("error", Outcome::Response(HttpResponse::error(502))));
+    }
+    // 请求体没有读完，响应后不能继续在该连接上读取下一个请求
+    if let Some(client_body) = client_body {
+        match client_body.forward_to(&mut stream) {
+            Ok(()) => {}
+            Err(ClientBodyError::TooLarge) => {
+                eprintln!("请求体超过 max_body_size，中断转发: {}", backend_addr);
+                return Err(("error", Outcome::Response(HttpResponse::error(413).header("Connection", "close"))));
+            }
+            Err(ClientBodyError::Io(e)) => {
+                eprintln!("转发请求体失败: {}", e);
+                return Err(("error", Outcome::Response(HttpResponse::error(502).header("Connection", "close"))));
+            }
+        }
+    }
+    
+    // 读取后端响应头
+    let mut received = Vec::new();
+    match read_head(&mut stream, &mut received, first_byte_timeout, timeouts) {
+        // 不是HTTP响应的内容不转发给客户端，例如崩溃时输出的错误信息或者端口上其实是别的服务
+        Ok(Some(head_end)) if !is_valid_status_line(&received[..head_end]) => {
+            eprintln!("后端返回的状态行无效: {}: {:?}", backend_addr, status_line_preview(&received));
+            Err(("error", Outcome::Response(HttpResponse::error(502))))
+        }
+        Ok(Some(head_end)) => Ok((backend_addr, BackendResponse { stream, received, head_end })),
+        Err(e) if e.kind() == ErrorKind::InvalidData => {
+            eprintln!("后端返回的不是HTTP响应: {}: {:?}", backend_addr, status_line_preview(&received));
+            Err(("error", Outcome::Response(HttpResponse::error(502))))
+        }
+        // 后端在头部结束之前关闭了连接，不把残缺的报文转发给客户端
+        Ok(None) => {
+            eprintln!("后端在响应头完整之前关闭连接: {}", backend_addr);
+            Err(("error", Outcome::Response(HttpResponse::error(502))))
+        }
+        Err(e) if received.is_empty() && is_timeout(&e) => {
+            eprintln!("后端首字节超时: {}", backend_addr);
+            Err(("timeout", Outcome::Response(HttpResponse::error(504))))
+        }
+        Err(_) => Err(("error", Outcome::Response(HttpResponse::error(502)))),
+    }
+}
+
+/// 根据Location生成跟随重定向的请求，返回新的后端地址和请求报文；无法跟随时返回None
+fn redirect_request(request: &[u8], status_code: u16, location: &str, current_addr: &SocketAddress, backends: &[BackendAddr]) -> Option<(SocketAddress, Vec<u8>)> {
+    // 绝对地址只支持http，相对地址沿用当前后端
+    let (target_addr, target_host, target_path) = if let Some(rest) = location.strip_prefix("http://") {
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        // 只跟随到配置的后端或当前后端，其他主机的重定向交给客户端，避免后端借此让代理访问任意地址
+        let backend = backend_socket_addr(authority).ok().filter(|backend| {
+            backends.contains(backend) || matches!(backend, BackendAddr::Ip(addr) if SocketAddress::Tcp(*addr) == *current_addr)
+        })?;
+        let addr = match backend {
+            BackendAddr::Ip(addr) => addr,
+            BackendAddr::Host(host, port) => (host.as_str(), port).to_socket_addrs().ok()?.next()?,
+            BackendAddr::Unix(_) => return None,
+        };
+        (SocketAddress::Tcp(addr), Some(authority), path)
+    } else if location.starts_with('/') {
+        (current_addr.clone(), None, location)
+    } else {
+        return None;
+    };
+    
+    let head_end = find_head_end(request)?;
+    let head = String::from_utf8_lossy(&request[..head_end]);
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?;
+    let version = request_line.nth(1).unwrap_or("HTTP/1.1");
+    
+    // 303以及POST遇到301/302时改为不带请求体的GET，其余保持方法和请求体
+    let new_method = match (status_code, method) {
+        (_, "HEAD") => "HEAD",
+        (303, _) | (301 | 302, "POST") => "GET",
+        _ => method,
+    };
+    let keep_body = new_method == method;
+    
+    let mut redirected = format!("{} {} {}\r\n", new_method, target_path, version);
+    for line in lines {
+        let name = line.split(':').next().unwrap_or("").trim();
+        let is_body_header = name.eq_ignore_ascii_case("Content-Length") || name.eq_ignore_ascii_case("Transfer-Encoding");
+        if (target_host.is_some() && name.eq_ignore_ascii_case("Host")) || (!keep_body && is_body_header) {
+            continue;
+        }
+        redirected.push_str(line);
+        redirected.push_str("\r\n");
+    }
+    if let Some(host) = target_host {
+        redirected.push_str(&format!("Host: {}\r\n", host));
+    }
+    redirected.push_str("\r\n");
+    
+    let mut redirected = redirected.into_bytes();
+    if keep_body {
+        redirected.extend_from_slice(&request[head_end + 4..]);
+    }
+    Some((target_addr, redirected))
+}
+
+/// 读取直到头部结束，返回头部结束位置（\r\n\r\n之前）；连接提前关闭时返回None
+///
+/// first_byte_timeout只约束第一个字节的到达，之后只受代理总超时的限制
+fn read_head(stream: &mut Socket, received: &mut Vec<u8>, first_byte_timeout: Option<Duration>, timeouts: &BackendTimeouts) -> io::Result<Option<usize>> {
+    if first_byte_timeout.is_some() {
+        stream.set_read_timeout(first_byte_timeout)?;
+    }
+    let mut buffer = [0; 8192];
+    loop {
+        if let Some(head_end) = find_head_end(received) {
+            return Ok(Some(head_end));
+        }
+        let bytes_read = stream.read(&mut buffer)?;
+        if bytes_read == 0 {
+            return Ok(None);
+        }
+        if received.is_empty() {
+            timeouts.limit_read(stream)?;
+        }
+        received.extend_from_slice(&buffer[..bytes_read]);
+        // 开头不是HTTP/时不必等待头部结束，非HTTP服务可能永远不会发送空行
+        let prefix_length = received.len().min(5);
+        if received[..prefix_length] != b"HTTP/"[..prefix_length] {
+            return Err(io::Error::new(ErrorKind::InvalidData, "响应不以HTTP/开头"));
+        }
+    }
+}
+
+/// 响应头的第一行是否为HTTP/1.x加三位状态码，原因短语可以省略
+fn is_valid_status_line(head: &[u8]) -> bool {
+    let line_end = head.windows(2).position(|window| window == b"\r\n").unwrap_or(head.len());
+    let line = &head[..line_end];
+    let Some(rest) = line.strip_prefix(b"HTTP/1.0 ").or_else(|| line.strip_prefix(b"HTTP/1.1 ")) else {
+        return false;
+    };
+    rest.len() >= 3 && rest[..3].iter().all(u8::is_ascii_digit) && (rest.len() == 3 || rest[3] == b' ')
+}
+
+/// 日志中显示的响应开头，最多64个字节
+fn status_line_preview(received: &[u8]) -> String {
+    let line_end = received.iter().position(|&b| b == b'\r' || b == b'\n').unwrap_or(received.len()).min(64);
+    String::from_utf8_lossy(&received[..line_end]).into_owned()
+}
+
+/// 查找头部结束的位置（\r\n\r\n之前）
+fn find_head_end(message: &[u8]) -> Option<usize> {
+    message.windows(4).position(|window| window == b"\r\n\r\n")
+}
+
+/// 规范化请求头，请求行和请求体保持不变；头部不是合法UTF-8时原样返回
+fn normalize_request_headers(request: &[u8]) -> Vec<u8> {
+    let Some(head_end) = find_head_end(request) else {
+        return request.to_vec();
+    };
+    let Ok(head) = std::str::from_utf8(&request[..head_end]) else {
+        return request.to_vec();
+    };
+    
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or("");
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in lines {
+        // 以空白开头的行是上一个头部的折叠续行
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+            continue;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((canonical_header_name(name.trim()), value.trim().to_string()));
+        }
+    }
+    
+    let mut normalized = format!("{}\r\n", request_line);
+    for (name, value) in &headers {
+        normalized.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    normalized.push_str("\r\n");
+    let mut normalized = normalized.into_bytes();
+    normalized.extend_from_slice(&request[head_end + 4..]);
+    normalized
+}
+
+/// 头部名称的规范写法，例如content-type写作Content-Type
+fn canonical_header_name(name: &str) -> String {
+    name.split('-')
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => first.to_ascii_uppercase().to_string() + &chars.as_str().to_ascii_lowercase(),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// 替换请求中已有的某个头部，value为None时删除该头部，其余字节保持不变
+fn replace_request_header(request: &[u8], name: &str, value: Option<&str>) -> Vec<u8> {
+    let head_len = find_head_end(request).map_or(request.len(), |head_end| head_end + 2);
+    let prefix = format!("{}:", name);
+    let mut modified = Vec::with_capacity(request.len());
+    for line in request[..head_len].split_inclusive(|&b| b == b'\n') {
+        if line.len() < prefix.len() || !line[..prefix.len()].eq_ignore_ascii_case(prefix.as_bytes()) {
+            modified.extend_from_slice(line);
+        } else if let Some(value) = value {
+            modified.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+    }
+    modified.extend_from_slice(&request[head_len..]);
+    modified
+}
+
+/// 设置请求头：去掉全部同名头部后在头部末尾添加一行
+fn set_request_header(request: &[u8], name: &str, value: &str) -> Vec<u8> {
+    let mut modified = replace_request_header(request, name, None);
+    if let Some(head_end) = find_head_end(&modified) {
+        modified.splice(head_end + 2..head_end + 2, format!("{}: {}\r\n", name, value).into_bytes());
+    }
+    modified
+}
+
+/// 设置响应头，head不含结尾的空行；去掉全部同名头部后依次追加
+fn set_response_headers(head: &str, headers: &HashMap<String, String>) -> String {
+    if headers.is_empty() {
+        return head.to_string();
+    }
+    let mut head = remove_header_lines(head, &headers.keys().collect::<Vec<_>>());
+    for (name, value) in headers {
+        head.push_str(&format!("\r\n{}: {}", name, value));
+    }
+    head
+}
+
+/// 逐跳头部（RFC 7230 6.1节），只在相邻两跳之间有意义，转发时去掉
+///
+/// Transfer-Encoding也是逐跳头部，但消息体按原始字节转发、没有重新分帧，因此保留
+const HOP_BY_HOP_HEADERS: [&str; 6] = ["Connection", "Keep-Alive", "Proxy-Authenticate", "TE", "Trailer", "Upgrade"];
+
+/// 需要去掉的头部：固定的逐跳头部加上Connection中列出的头部
+fn hop_by_hop_names(headers: &[(String, String)]) -> Vec<String> {
+    let mut names: Vec<String> = HOP_BY_HOP_HEADERS.iter().map(|name| name.to_string()).collect();
+    let listed = headers.iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Connection"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(str::trim)
+        .filter(|token| !token.is_empty() && !token.eq_ignore_ascii_case("close") && !token.eq_ignore_ascii_case("keep-alive"))
+        // 分帧相关的头部不能去掉，否则后续无法判断消息体的结束位置
+        .filter(|token| !token.eq_ignore_ascii_case("Transfer-Encoding") && !token.eq_ignore_ascii_case("Content-Length"))
+        .map(str::to_string);
+    names.extend(listed);
+    names
+}
+
+/// 加上X-Forwarded-For、X-Forwarded-Proto和X-Forwarded-Host
+///
+/// 已有的X-Forwarded-For保留前面代理记录的地址，客户端地址追加在末尾；Unix socket的客户端没有IP，不追加。
+/// 客户端出示了证书时再加上X-Client-Cert-Subject和X-Client-Cert-Fingerprint（DER的SHA-256，十六进制）
+fn add_forwarded_headers(request: &[u8], client: &ClientStream) -> Vec<u8> {
+    let headers = parse_headers(&String::from_utf8_lossy(request));
+    let client_ip = client.peer_addr().ip().map(|ip| ip.to_string());
+    let mut forwarded_for: Vec<&str> = headers.iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("X-Forwarded-For"))
+        .map(|(_, value)| value.trim())
+        .filter(|value| !value.is_empty())
+        .collect();
+    forwarded_for.extend(client_ip.as_deref());
+    let proto = if client.is_tls() { "https" } else { "http" };
+    
+    let mut forwarded_headers = format!("X-Forwarded-Proto: {}\r\n", proto);
+    if !forwarded_for.is_empty() {
+        forwarded_headers.insert_str(0, &format!("X-Forwarded-For: {}\r\n", forwarded_for.join(", ")));
+    }
+    if let Some(host) = find_header(&headers, "Host") {
+        forwarded_headers.push_str(&format!("X-Forwarded-Host: {}\r\n", host));
+    }
+    
+    // 客户端通过mTLS出示的证书，后端据此识别客户端；证书无法解析主题时只发送指纹
+    if let Some(certificate) = client.client_certificate() {
+        if let Some(subject) = cert::subject(certificate) {
+            forwarded_headers.push_str(&format!("X-Client-Cert-Subject: {}\r\n", subject));
+        }
+        let fingerprint: String = digest::sha256(certificate).iter().map(|byte| format!("{:02x}", byte)).collect();
+        forwarded_headers.push_str(&format!("X-Client-Cert-Fingerprint: {}\r\n", fingerprint));
+    }
+    
+    // 客户端自己发来的同名头部不可信，一律去掉
+    let mut modified = request.to_vec();
+    for name in ["X-Forwarded-For", "X-Forwarded-Proto", "X-Forwarded-Host", "X-Client-Cert-Subject", "X-Client-Cert-Fingerprint"] {
+        modified = replace_request_header(&modified, name, None);
+    }
+    if let Some(head_end) = find_head_end(&modified) {
+        modified.splice(head_end + 2..head_end + 2, forwarded_headers.bytes());
+    }
+    modified
+}
+
+/// 去掉请求中的逐跳头部，并要求后端在响应后关闭连接；upgrade为WebSocket握手的Upgrade值，此时改为请求升级
+fn strip_request_hop_by_hop(request: &[u8], upgrade: Option<&str>) -> Vec<u8> {
+    let headers = parse_headers(&String::from_utf8_lossy(request));
+    let mut stripped = request.to_vec();
+    for name in hop_by_hop_names(&headers) {
+        stripped = replace_request_header(&stripped, &name, None);
+    }
+    let connection = match upgrade {
+        Some(upgrade) => format!("Connection: Upgrade\r\nUpgrade: {}\r\n", upgrade),
+        None => String::from("Connection: close\r\n"),
+    };
+    if let Some(head_end) = find_head_end(&stripped) {
+        stripped.splice(head_end + 2..head_end + 2, connection.bytes());
+    }
+    stripped
+}
+
+/// 请求是否为WebSocket握手（Connection中有upgrade且Upgrade为websocket），是时返回Upgrade的值
+fn websocket_upgrade(headers: &[(String, String)]) -> Option<&str> {
+    let connection_upgrade = headers.iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Connection"))
+        .flat_map(|(_, value)| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    let upgrade = find_header(headers, "Upgrade")?.trim();
+    let websocket = upgrade.split(',').any(|protocol| protocol.trim().eq_ignore_ascii_case("websocket"));
+    (connection_upgrade && websocket).then_some(upgrade)
+}
+
+/// 101响应去掉其余逐跳头部，保留后端同意的Upgrade，连接之后交给升级后的协议
+fn upgrade_response_head(head: &str, headers: &[(String, String)]) -> String {
+    let upgrade = find_header(headers, "Upgrade").unwrap_or("websocket").trim().to_string();
+    let stripped = remove_header_lines(head, &hop_by_hop_names(headers));
+    format!("{}\r\nConnection: Upgrade\r\nUpgrade: {}", stripped, upgrade)
+}
+
+/// 去掉响应头部块中的逐跳头部，并加上Connection: close
+fn strip_response_hop_by_hop(head: &str, headers: &[(String, String)]) -> String {
+    let stripped = remove_header_lines(head, &hop_by_hop_names(headers));
+    format!("{}\r\nConnection: close", stripped)
+}
+
+/// 从头部块中去掉指定名称的头部（不区分大小写）
+fn remove_header_lines(head: &str, names: &[impl AsRef<str>]) -> String {
+    head.split("\r\n")
+        .filter(|line| match line.split_once(':') {
+            Some((name, _)) => !names.iter().any(|removed| removed.as_ref().eq_ignore_ascii_case(name.trim())),
+            None => true,
+        })
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+/// 默认去掉的响应头，它们暴露后端使用的框架和版本
+const SENSITIVE_RESPONSE_HEADERS: [&str; 6] = [
+    "X-Powered-By",
+    "X-AspNet-Version",
+    "X-AspNetMvc-Version",
+    "X-Runtime",
+    "X-Generator",
+    "X-Backend-Server",
+];
+
+/// 读写超时在不同平台上表现为WouldBlock或TimedOut
+fn is_timeout(error: &io::Error) -> bool {
+    matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+/// 逐行改写头部块（不含结尾的空行）
+///
+/// 按\r\n拆分再按\r\n拼接是精确的逆操作，未改写的行保持原样；
+/// 响应体从不经过这里，无论是否以换行结尾都按原始字节转发
+fn map_header_lines(head: &str, rewrite: impl FnMut(&str) -> String) -> String {
+    head.split("\r\n")
+        .map(rewrite)
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+/// 展开Server头配置中的{version}
+fn expand_server_header(value: &str) -> String {
+    value.replace("{version}", env!("CARGO_PKG_VERSION"))
+}
+
+/// 将Server头改为nextWeb与原始服务器的叠加
+///
+/// head只包含状态行和头部，头部名称不区分大小写；响应体不经过这里，原样转发
+fn rewrite_server_header(head: &str) -> String {
+    // 提取原始Server头
+    let original_server = head.split("\r\n")
+        .skip(1)
+        .find_map(|line| header_line_value(line, "Server"))
+        .unwrap_or("unknown")
+        .to_string();
+    
+    // 构建新的Server头
+    let new_server_header = format!("Server: nextWeb({})/{}", original_server, env!("CARGO_PKG_VERSION"));
+    
+    // 替换Server头，状态行不参与匹配
+    let mut is_status_line = true;
+    map_header_lines(head, |line| {
+        if !std::mem::take(&mut is_status_line) && header_line_value(line, "Server").is_some() {
+            new_server_header.clone()
+        } else {
+            line.to_string()
+        }
+    })
+}
+
+/// 头部行的名称为name（不区分大小写）时返回去掉两端空白的值
+fn header_line_value<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let (line_name, value) = line.split_once(':')?;
+    line_name.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+}
+
+/// 改写Location和Content-Location头中指向后端的地址
+fn rewrite_location_headers(head: &str, proxy_config: &ProxyConfig, public_host: &str) -> String {
+    let mut backend_prefixes: Vec<String> = proxy_config.backend.urls().iter()
+        .map(|backend| backend.trim_end_matches('/').to_string())
+        .collect();
+    if proxy_config.modify_host {
+        backend_prefixes.push(format!("http://{}", proxy_config.header_host));
+    }
+    let public_prefix = format!("http://{}", public_host);
+    
+    map_header_lines(head, |line| {
+        let Some((name, value)) = line.split_once(':') else {
+            return line.to_string();
+        };
+        if !name.eq_ignore_ascii_case("Location") && !name.eq_ignore_ascii_case("Content-Location") {
+            return line.to_string();
+        }
+        let value = value.trim();
+        match backend_prefixes.iter().find_map(|prefix| value.strip_prefix(prefix.as_str())) {
+            // 只替换完整的主机部分，避免把127.0.0.1:80误匹配到127.0.0.1:8080
+            Some(rest) if rest.is_empty() || rest.starts_with('/') || rest.starts_with('?') => {
+                format!("{}: {}{}", name, public_prefix, rest)
+            }
+            _ => line.to_string(),
+        }
+    })
+}
+
+/// 处理CONNECT隧道：连接目标后回复200，然后双向转发字节
+fn handle_connect_tunnel(target: &str, client: &mut ClientStream) -> Outcome {
+    let Some(target_addr) = target.to_socket_addrs().ok().and_then(|mut addrs| addrs.next()) else {
+        return Outcome::Response(HttpResponse::error(400));
+    };
+    let backend = match TcpStream::connect_timeout(&target_addr, BACKEND_CONNECT_TIMEOUT) {
+        Ok(backend) => Socket::Tcp(backend),
+        Err(_) => return Outcome::Response(HttpResponse::error(502)),
+    };
+    
+    if write_fully(client, b"HTTP/1.1 200 Connection Established\r\n\r\n").is_err() {
+        return Outcome::Streamed(200, false);
+    }
+    client.relay(backend);
+    Outcome::Streamed(200, false)
+}
+
+/// 把后端的101响应和之后已经收到的数据发给客户端，然后双向转发直到任意一方关闭
+fn relay_upgraded(client: &mut ClientStream, backend: Socket, head: &str, received_after_head: &[u8]) -> Outcome {
+    let mut response = format!("{}\r\n\r\n", head).into_bytes();
+    response.extend_from_slice(received_after_head);
+    if write_fully(client, &response).is_err() {
+        return Outcome::Streamed(101, false);
+    }
+    // 升级后的连接可能长时间没有数据，不再使用请求的读取超时
+    let _ = client.socket().set_read_timeout(None);
+    let _ = backend.set_read_timeout(None);
+    client.relay(backend);
+    Outcome::Streamed(101, false)
+}
+
+/// 边收边转发响应：先写出已读取的部分，之后每收到数据立即写给客户端，超过代理总超时时中断；返回响应是否完整转发
+fn forward_response_body(backend_stream: &mut Socket, client: &mut ClientStream, first_chunk: &[u8], tracker: &mut BodyTracker, timeouts: &BackendTimeouts) -> bool {
+    if write_fully(client, first_chunk).is_err() {
+        return false;
+    }
+    
+    let mut buffer = [0; 8192];
+    while !tracker.is_complete() {
+        if timeouts.limit_read(backend_stream).is_err() {
+            break;
+        }
+        match backend_stream.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(bytes_read) => {
+                let consumed = tracker.feed(&buffer[..bytes_read]);
+                if write_fully(client, &buffer[..consumed]).is_err() {
+                    return false;
+                }
+            }
+        }
+    }
+    tracker.is_complete()
+}
+
+/// 一个客户端连接的完整处理过程，与接受连接的循环和工作线程的调度无关
+struct Connection<'a> {
+    stream: ClientStream,
+    state: &'a ServerState,
+    // 该连接上已经处理的请求数，大于0时说明正在复用保持的连接
+    requests: usize,
+}
+
+impl<'a> Connection<'a> {
+    fn new(stream: ClientStream, state: &'a ServerState) -> Connection<'a> {
+        Connection { stream, state, requests: 0 }
+    }
+    
+    /// 依次处理连接上的请求，客户端要求保持连接时继续等待下一个请求，返回处理的请求数
+    fn process(&mut self) -> usize {
+        loop {
+            let (handled, keep_alive) = handle_request(&mut self.stream, self.state, self.requests > 0);
+            self.requests += handled;
+            // 正在关闭时不再等待下一个请求
+            if !keep_alive || SHUTDOWN.load(Ordering::SeqCst) {
+                return self.requests;
+            }
+        }
+    }
+}
+
+/// 处理连接上的一个请求，返回处理的请求数（0或1）以及之后是否保持连接
+///
+/// 后续请求最多等待keepalive_timeout_secs，超时或客户端关闭时直接结束连接，不返回408
+fn handle_request(stream: &mut ClientStream, state: &ServerState, is_followup: bool) -> (usize, bool) {
+    let live = state.live();
+    let server_config = &live.config;
+    let middlewares = &live.middlewares;
+    let client_addr = stream.peer_addr().to_string();
+    let error_response = |status_code: u16| {
+        HttpResponse::error(status_code).error_format(server_config.error_format).build()
+    };
+    
+    let mut timing = RequestTiming::start(&state.access_log);
+    let written_before = stream.bytes_written();
+    let read_timeout_secs = if is_followup {
+        server_config.server.keepalive_timeout_secs
+    } else {
+        server_config.server.request_timeout_secs.unwrap_or(server_config.server.timeout_secs)
+    };
+    let read_timeout = Some(read_timeout_secs).filter(|&secs| secs > 0).map(Duration::from_secs);
+    // 代理完整缓冲的请求体超过上限时不再读入内存，由handle_proxy_request返回413；流式转发时只读取头部，
+    // 剩余的请求体在连接后端之后再读；静态和重定向服务器不使用请求体，读出后丢弃
+    let body_read = match &server_config.proxy_config {
+        Some(proxy_config) if server_config.server_type.name == "proxy" => match proxy_config.request_buffering {
+            RequestBuffering::Full => BodyRead::Buffer(proxy_config.max_body_size.unwrap_or(DEFAULT_MAX_BODY_SIZE)),
+            RequestBuffering::Stream => BodyRead::Headers,
+        },
+        _ => BodyRead::Discard(DEFAULT_MAX_BODY_SIZE),
+    };
+    let read_result = read_request(stream, server_config.server.max_header_size, body_read, read_timeout);
+    if read_timeout.is_some() {
+        // 只限制读取请求，之后的隧道等长连接不受影响
+        let _ = stream.socket().set_read_timeout(None);
+    }
+    let mut buffer = match read_result {
+        // 保持的连接空闲超时或被客户端关闭
+        Ok(buffer) if is_followup && buffer.is_empty() => return (0, false),
+        Err(ReadRequestError::Io(_)) if is_followup => return (0, false),
+        Ok(buffer) => buffer,
+        Err(ReadRequestError::HeaderTooLarge) => {
+            send_and_log(stream, &client_addr, "-", 431, &error_response(431), &mut timing);
+            return (1, false);
+        }
+        Err(ReadRequestError::BodyTooLarge) => {
+            send_and_log(stream, &client_addr, "-", 413, &error_response(413), &mut timing);
+            return (1, false);
+        }
+        Err(ReadRequestError::Io(e)) if is_timeout(&e) => {
+            // 客户端发送请求太慢
+            send_and_log(stream, &client_addr, "-", 408, &error_response(408), &mut timing);
+            return (1, false);
+        }
+        Err(ReadRequestError::Io(_)) => {
+            log_access(&client_addr, "-", 400, &timing);
+            return (0, false);
+        }
+    };
+    let bytes_read = buffer.len();
+    if is_followup {
+        // 后续请求从收到数据时开始计时，不包含空闲等待的时间
+        timing = RequestTiming::start(&state.access_log);
+    }
+    
+    // 限制本服务器同时处理中的请求数，不影响同一进程中的其他服务器；只在处理请求期间占用，保持的空闲连接不计入
+    let _in_flight_guard = match server_config.max_concurrent_requests {
+        Some(limit) => match InFlightGuard::acquire(&state.in_flight, limit) {
+            Some(guard) => Some(guard),
+            None => {
+                send_and_log(stream, &client_addr, "-", 503, &overload_response(server_config).build(), &mut timing);
+                return (1, false);
+            }
+        },
+        None => None,
+    };
+    
+    // 解析前先检查请求行长度
+    if let Some(max_length) = server_config.server.max_request_line_length
+        && request_line_length(&buffer[..bytes_read]) > max_length
+    {
+        send_and_log(stream, &client_addr, "-", 414, &error_response(414), &mut timing);
+        return (1, false);
+    }
+    
+    // 方法名区分大小写，转发给后端的必须是规范的大写形式
+    let method_len = buffer[..bytes_read].iter().position(|&b| b == b' ' || b == b'\r' || b == b'\n').unwrap_or(bytes_read);
+    if buffer[..method_len].iter().any(u8::is_ascii_lowercase) {
+        if server_config.server.method_case == MethodCase::Strict {
+            send_and_log(stream, &client_addr, "-", 400, &error_response(400), &mut timing);
+            return (1, false);
+        }
+        buffer[..method_len].make_ascii_uppercase();
+    }
+    
+    let raw_request = &buffer[..bytes_read];
+    timing.request_line = raw_request.split(|&b| b == b'\n').next()
+        .map(|line| String::from_utf8_lossy(line).trim_end().to_string());
+    
+    // 严格模式下请求头必须是合法的UTF-8，末尾被截断的多字节字符不算错误
+    let head_len = find_head_end(raw_request).unwrap_or(raw_request.len());
+    if server_config.server.strict_utf8
+        && let Err(e) = std::str::from_utf8(&raw_request[..head_len])
+        && e.error_len().is_some()
+    {
+        send_and_log(stream, &client_addr, "-", 400, &error_response(400), &mut timing);
+        return (1, false);
+    }
+    
+    if let Some(path) = http09_path(raw_request) {
+        return (handle_http09_request(stream, server_config, &client_addr, &path, raw_request, &mut timing), false);
+    }
+    
+    // 折叠行在不同实现中的解释不一致，可能被用来让本服务器和后端看到不同的头部
+    let unfolded;
+    let raw_request = if has_folded_headers(raw_request) {
+        match server_config.server.header_folding {
+            HeaderFolding::Reject => {
+                send_and_log(stream, &client_addr, &extract_path(raw_request), 400, &HttpResponse::error(400).detail("Obsolete line folding in headers").error_format(server_config.error_format).build(), &mut timing);
+                return (1, false);
+            }
+            HeaderFolding::Unfold => {
+                unfolded = unfold_headers(raw_request);
+                &unfolded[..]
+            }
+        }
+    } else {
+        raw_request
+    };
+    
+    // 没有长度信息的请求体无法确定在哪里结束，不能让后端或下一个请求去猜
+    let delimited;
+    let raw_request = if is_unframed_body_request(raw_request) {
+        match server_config.server.unframed_body {
+            UnframedBody::Require => {
+                send_and_log(stream, &client_addr, &extract_path(raw_request), 411, &error_response(411), &mut timing);
+                return (1, false);
+            }
+            UnframedBody::Wait => {
+                let wait = Duration::from_millis(server_config.server.unframed_body_wait_ms);
+                delimited = read_unframed_body(stream, raw_request, wait);
+                &delimited[..]
+            }
+        }
+    } else {
+        raw_request
+    };
+    
+    // 将原始请求转换为字符串
+    let request = String::from_utf8_lossy(raw_request).to_string();
+    let path = extract_path(raw_request);
+    
+    let request_headers = parse_headers(&request);
+    let keep_alive = server_config.server.keepalive_timeout_secs > 0 && client_wants_keep_alive(&request, &request_headers);
+    if server_config.server.log_header_stats {
+        // 头部块去掉请求行及其CRLF后的长度
+        let header_size = head_len.saturating_sub(request_line_length(raw_request) + 2);
+        timing.header_stats = Some((request_headers.len(), header_size));
+    }
+    if server_config.server.log_referer_user_agent {
+        let header_or_dash = |name| find_header(&request_headers, name).unwrap_or("-").to_string();
+        timing.referer_user_agent = Some((header_or_dash("Referer"), header_or_dash("User-Agent")));
+    }
+    if server_config.server.log_tls_sni {
+        timing.tls_sni = Some(stream.server_name().unwrap_or("-").to_string());
+    }
+    
+    if let Some(max_length) = server_config.server.max_header_value_length
+        && request_headers.iter().any(|(_, value)| value.len() > max_length)
+    {
+        send_and_log(stream, &client_addr, &path, 431, &error_response(431), &mut timing);
+        return (1, false);
+    }
+    
+    // 多个Host头是请求走私的迹象，转发给后端会产生歧义
+    let host_count = request_headers.iter().filter(|(name, _)| name.eq_ignore_ascii_case("Host")).count();
+    if host_count > 1 {
+        send_and_log(stream, &client_addr, &path, 400, &error_response(400), &mut timing);
+        return (1, false);
+    }
+    
+    // Content-Length和Transfer-Encoding同时出现或者多个Content-Length不一致时，后端可能按不同的长度切分请求
+    if BodyFraming::is_ambiguous(&request_headers) {
+        send_and_log(stream, &client_addr, &path, 400, &error_response(400), &mut timing);
+        return (1, false);
+    }
+    
+    // 拒绝无法处理的传输编码
+    if !is_supported_transfer_encoding(&request_headers) {
+        send_and_log(stream, &client_addr, &path, 501, &error_response(501), &mut timing);
+        return (1, false);
+    }
+    
+    // 请求之后多出的数据：保持的连接上以请求行开头时是客户端流水线发送的下一个请求，留给下一次读取；
+    // 其余情况不能当作下一个请求，否则可能被用来走私请求；CONNECT之后的数据属于隧道
+    let method = extract_method(&request);
+    let trailing = match body_read {
+        _ if method == "CONNECT" => None,
+        // 丢弃请求体时缓冲区中头部之后就是多出的数据
+        BodyRead::Discard(_) => find_head_end(raw_request).map(|head_end| head_end + 4).filter(|&end| end < raw_request.len()),
+        _ => request_end(raw_request, &request_headers),
+    };
+    let mut pipelined = None;
+    let raw_request = match trailing {
+        Some(request_end) if keep_alive && starts_with_request_line(&raw_request[request_end..]) => {
+            pipelined = Some(raw_request[request_end..].to_vec());
+            &raw_request[..request_end]
+        }
+        Some(_) if server_config.server.trailing_data == TrailingData::Reject => {
+            send_and_log(stream, &client_addr, &path, 400, &HttpResponse::error(400).detail("Unexpected data after request body").error_format(server_config.error_format).build(), &mut timing);
+            return (1, false);
+        }
+        Some(request_end) => &raw_request[..request_end],
+        None => raw_request,
+    };
+    
+    // 只有OPTIONS可以使用星号形式的请求目标，其余方法既不能解析成文件也不能转发
+    if path == "*" && method != "OPTIONS" {
+        send_and_log(stream, &client_addr, &path, 400, &error_response(400), &mut timing);
+        return (1, false);
+    }
+    
+    // 在转发之前拦截损坏的上传；丢弃的请求体不需要校验
+    if server_config.server.verify_body_digest
+        && !matches!(body_read, BodyRead::Discard(_))
+        && let Err(detail) = verify_body_digest(raw_request, &request_headers, matches!(body_read, BodyRead::Headers))
+    {
+        send_and_log(stream, &client_addr, &path, 400, &HttpResponse::error(400).detail(detail).error_format(server_config.error_format).build(), &mut timing);
+        return (1, false);
+    }
+    
+    let context = RequestContext {
+        client_addr: &client_addr,
+        path: &path,
+    };
+    
+    let outcome = if let Some(access) = &server_config.server.access
+        && let Some(ip) = stream.peer_addr().ip()
+        && !access.permits(ip)
+    {
+        write_log_line(&format!("[{}] 客户端 {} 不允许访问，返回403", log_timestamp(), client_addr), LogLevel::Warn);
+        Outcome::Response(HttpResponse::error(403))
+    } else if let Some(response) = middlewares.iter().find_map(|middleware| middleware.before_request(&context)) {
+        Outcome::Response(response)
+    } else if let Some(response) = rate_limit_response(&live, stream, &path) {
+        Outcome::Response(response)
+    } else if server_config.health_path.as_deref() == Some(path.as_str()) {
+        Outcome::Response(health_response(state))
+    } else if !state.ready.load(Ordering::SeqCst) {
+        // 预热完成前拒绝普通请求，客户端稍后重试
+        Outcome::Response(HttpResponse::error(503).detail("Server is warming up").header("Retry-After", "1").header("Connection", "close"))
+    } else if method == "CONNECT" {
+        // CONNECT只在开启隧道的代理上处理，其余情况不能当作普通请求
+        match &server_config.proxy_config {
+            Some(proxy_config) if server_config.server_type.name == "proxy" && proxy_config.connect_tunnel => {
+                handle_connect_tunnel(&path, stream)
+            }
+            // 没有开启隧道时Allow列出该服务器能处理的普通请求方法
+            _ => {
+                let proxy_config = match server_config.server_type.name.as_str() {
+                    "proxy" => server_config.proxy_config.as_ref(),
+                    _ => server_config.static_config.as_ref().and_then(|static_config| static_config.fallback_proxy.as_ref()),
+                };
+                Outcome::Response(HttpResponse::error(405).header("Allow", &allow_header(proxy_config)))
+            }
+        }
+    } else if let Some(route) = select_header_route(&server_config.header_routes, &request_headers) {
+        handle_site_request(&route.site(), &path, raw_request, &request_headers, stream, &mut timing)
+    } else {
+        match select_vhost(&server_config.vhosts, &request_headers) {
+            Some(vhost) => handle_site_request(&vhost.site(), &path, raw_request, &request_headers, stream, &mut timing),
+            None => handle_site_request(&server_config.site(), &path, raw_request, &request_headers, stream, &mut timing),
+        }
+    };
+    
+    let (status_code, mut local, raw, streamed_keep_alive, file_body) = match outcome {
+        Outcome::Response(response) => (response.status(), Some(response.error_format(server_config.error_format)), None, false, None),
+        Outcome::Raw(response) => (raw_status_code(&response), None, Some(response), false, None),
+        Outcome::Streamed(status_code, keep_alive) => (status_code, None, None, keep_alive, None),
+        Outcome::File(response, file, length) => (response.status(), Some(*response), None, false, Some((file, length))),
+    };
+    
+    let mut response_context = ResponseContext { status_code, local: local.as_mut(), timing: &timing };
+    for middleware in middlewares.iter().rev() {
+        middleware.after_response(&context, &mut response_context);
+    }
+    
+    // 只有能确定响应在哪里结束时才能保持连接，隧道和无法确定长度的流式响应结束后关闭
+    let keep_alive = keep_alive && method != "CONNECT" && match (&local, &raw) {
+        (Some(response), _) => !response.get_header("Connection").is_some_and(|value| value.eq_ignore_ascii_case("close")),
+        (None, Some(response)) => is_framed_response(response, &method),
+        (None, None) => streamed_keep_alive,
+    };
+    
+    let keep_alive = match (local, raw) {
+        (Some(mut response), _) if let Some((file, length)) = file_body => {
+            response.set_header("Connection", if keep_alive { "keep-alive" } else { "close" });
+            send_response(stream, &response.build_head(length));
+            if method != "HEAD"
+                && let Err(e) = send_file_body(stream, file, length)
+            {
+                // 响应已经发出一部分，无法再改成错误响应，只能关闭连接
+                eprintln!("发送文件中断: {}: {}", path, e);
+                false
+            } else {
+                keep_alive
+            }
+        }
+        (Some(mut response), _) => {
+            response.set_header("Connection", if keep_alive { "keep-alive" } else { "close" });
+            let response = response.build();
+            // HEAD请求只发送头部，Content-Length仍是正文的长度
+            let response = match find_head_end(&response) {
+                Some(head_end) if method == "HEAD" => &response[..head_end + 4],
+                _ => &response[..],
+            };
+            send_response(stream, response);
+            keep_alive
+        }
+        (None, Some(response)) if keep_alive => {
+            send_response(stream, &keep_alive_raw_response(&response));
+            keep_alive
+        }
+        (None, Some(response)) => {
+            send_response(stream, &response);
+            keep_alive
+        }
+        (None, None) => keep_alive,
+    };
+    
+    // 连接继续使用时，下一个请求从流水线中已经收到的部分开始读
+    if keep_alive
+        && let Some(pipelined) = pipelined
+    {
+        stream.unread(&pipelined);
+    }
+
+    // 流式响应在处理请求时已经写出，同样计入
+    timing.bytes_sent = Some(stream.bytes_written() - written_before);
+    let response_context = ResponseContext { status_code, local: None, timing: &timing };
+    for middleware in middlewares.iter().rev() {
+        middleware.after_send(&context, &response_context);
+    }
+    (1, keep_alive)
+}
+
+/// 按第一条匹配路径的限流规则检查客户端，超过限制时返回429和该规则对应的Retry-After；Unix socket的客户端不限流
+fn rate_limit_response(live: &LiveConfig, stream: &ClientStream, path: &str) -> Option<HttpResponse> {
+    let rate_limiter = live.rate_limiters.iter().find(|rate_limiter| rate_limiter.matches(path))?;
+    let client = stream.peer_addr().ip()?;
+    let retry_after = rate_limiter.check(client).err()?;
+    Some(HttpResponse::error(429).header("Retry-After", &retry_after.to_string()))
+}
+
+/// 按Host头选择虚拟主机，没有Host头或没有匹配时返回None，使用服务器本身的站点配置
+fn select_vhost<'a>(vhosts: &'a [VirtualHost], headers: &[(String, String)]) -> Option<&'a VirtualHost> {
+    let hostname = request_hostname(headers)?;
+    vhosts.iter().find(|vhost| vhost.hostnames.iter().any(|name| name.trim_end_matches('.').eq_ignore_ascii_case(hostname)))
+}
+
+/// Host头中的主机名，去掉端口、IPv6地址的方括号和末尾的点
+fn request_hostname(headers: &[(String, String)]) -> Option<&str> {
+    let host = find_header(headers, "Host")?.trim();
+    let hostname = match host.strip_prefix('[') {
+        Some(bracketed) => bracketed.split(']').next().unwrap_or(""),
+        None => host.split(':').next().unwrap_or(""),
+    };
+    Some(hostname.trim_end_matches('.'))
+}
+
+/// 按顺序找到第一条请求头匹配的路由规则，同名头部出现多次时任意一个匹配即可
+fn select_header_route<'a>(routes: &'a [HeaderRoute], headers: &[(String, String)]) -> Option<&'a HeaderRoute> {
+    routes.iter().find(|route| {
+        headers.iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case(&route.header))
+            .any(|(_, value)| route.pattern.as_ref().is_none_or(|pattern| pattern.matches(value)))
+    })
+}
+
+/// 按站点类型交给静态文件或代理处理
+fn handle_site_request(site: &Site, path: &str, request: &[u8], headers: &[(String, String)],
+    stream: &mut ClientStream, timing: &mut RequestTiming) -> Outcome {
+    match site.server_type.name.as_str() {
+        "static" => {
+            match site.static_config {
+                Some(static_config) => handle_static_request(static_config, path, request, stream, timing),
+                None => Outcome::Response(HttpResponse::error(500).detail("Static configuration is missing"))
+            }
+        }
+        "proxy" => {
+            match site.proxy_config {
+                Some(proxy_config) => handle_proxy_request(proxy_config, request, stream, timing),
+                None => Outcome::Response(HttpResponse::error(500).detail("Proxy configuration is missing"))
+            }
+        }
+        "redirect" => {
+            match site.redirect_config {
+                Some(redirect_config) => Outcome::Response(handle_redirect_request(redirect_config, path, headers)),
+                None => Outcome::Response(HttpResponse::error(500).detail("Redirect configuration is missing"))
+            }
+        }
+        _ => Outcome::Response(HttpResponse::error(501))
+    }
+}
+
+/// 按[redirect]配置把请求重定向到目标地址，例如从http跳转到https或从裸域名跳转到www
+fn handle_redirect_request(redirect_config: &RedirectConfig, path: &str, headers: &[(String, String)]) -> HttpResponse {
+    let host = match request_hostname(headers) {
+        // 主机名会原样写进Location，只接受域名和IP地址中会出现的字符
+        Some(host) if !host.is_empty() && host.bytes().all(|b| b.is_ascii_alphanumeric() || b"-.:".contains(&b)) => host,
+        _ if !redirect_config.target.contains("{host}") => "",
+        _ => return HttpResponse::error(400).detail("Missing or invalid Host header"),
+    };
+    // IPv6地址在URL中需要方括号
+    let host = if host.contains(':') { format!("[{}]", host) } else { host.to_string() };
+    let location = redirect_config.target.replace("{host}", &host).replace("{path}", path);
+    HttpResponse::new(redirect_config.status).header("Location", &location)
+}
+
+/// 客户端是否希望保持连接：HTTP/1.1默认保持，除非带有Connection: close；HTTP/1.0需要明确的Connection: keep-alive
+fn client_wants_keep_alive(request: &str, headers: &[(String, String)]) -> bool {
+    let version = request.lines().next().and_then(|line| line.split_whitespace().nth(2)).unwrap_or("");
+    let has_token = |token: &str| headers.iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Connection"))
+        .flat_map(|(_, value)| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token));
+    match version {
+        "HTTP/1.1" => !has_token("close"),
+        "HTTP/1.0" => has_token("keep-alive"),
+        _ => false,
+    }
+}
+
+/// 后端响应能否确定结束位置（Content-Length、chunked或没有响应体），不能确定时只能靠关闭连接结束
+fn is_framed_response(response: &[u8], method: &str) -> bool {
+    let Some(head) = find_head_end(response).and_then(|head_end| std::str::from_utf8(&response[..head_end]).ok()) else {
+        return false;
+    };
+    BodyFraming::for_response(method, response_status_code(head), &parse_headers(head)) != BodyFraming::UntilClose
+}
+
+/// 把后端响应中的Connection: close换成keep-alive，响应体保持不变
+fn keep_alive_raw_response(response: &[u8]) -> Vec<u8> {
+    let Some(head_end) = find_head_end(response) else {
+        return response.to_vec();
+    };
+    let head = String::from_utf8_lossy(&response[..head_end]);
+    let mut kept = format!("{}\r\nConnection: keep-alive\r\n\r\n", remove_header_lines(&head, &["Connection"])).into_bytes();
+    kept.extend_from_slice(&response[head_end + 4..]);
+    kept
+}
+
+/// 每次从客户端读取的字节数
+const READ_CHUNK_SIZE: usize = 4096;
+
+/// 请求体的读取方式
+#[derive(Clone, Copy)]
+enum BodyRead {
+    /// 读入内存，超过上限时停止读取
+    Buffer(u64),
+    /// 只读取头部，请求体之后流式转发
+    Headers,
+    /// 读出后丢弃，只保留请求体之后的数据
+    Discard(u64),
+}
+
+enum ReadRequestError {
+    /// 头部结束之前已经超过了max_header_size
+    HeaderTooLarge,
+    /// 要丢弃的请求体超过了上限
+    BodyTooLarge,
+    Io(io::Error),
+}
+
+/// 读取完整的请求：先读到头部结束的空行，再按body_read和Content-Length或chunked读完请求体；
+/// 请求体之后多读到的数据一并返回，由调用方按trailing_data处理
+///
+/// 请求头必须在timeout内全部收到，不能靠每隔一段时间发送一个字节一直占用连接；
+/// 请求体只限制每次读取的等待时间，慢速链路上的大请求体不会因此中断；
+/// 读入内存时声明的长度或已收到的请求体超过上限就停止读取，返回已收到的部分；丢弃时超过上限返回BodyTooLarge
+fn read_request(stream: &mut ClientStream, max_header_size: usize, body_read: BodyRead, timeout: Option<Duration>) -> Result<Vec<u8>, ReadRequestError> {
+    let mut request = Vec::new();
+    let mut chunk = [0; READ_CHUNK_SIZE];
+    let header_deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let head_end = loop {
+        if let Some(head_end) = find_head_end(&request) {
+            break head_end;
+        }
+        // HTTP/0.9请求只有一行，不会有空行
+        if http09_path(&request).is_some() {
+            return Ok(request);
+        }
+        if request.len() > max_header_size {
+            return Err(ReadRequestError::HeaderTooLarge);
+        }
+        if let Some(deadline) = header_deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(ReadRequestError::Io(io::Error::from(ErrorKind::TimedOut)));
+            }
+            stream.socket().set_read_timeout(Some(remaining)).map_err(ReadRequestError::Io)?;
+        }
+        let bytes_read = stream.read(&mut chunk).map_err(ReadRequestError::Io)?;
+        if bytes_read == 0 {
+            // 客户端提前关闭，按收到的部分处理
+            return Ok(request);
+        }
+        request.extend_from_slice(&chunk[..bytes_read]);
+    };
+    if head_end + 4 > max_header_size {
+        return Err(ReadRequestError::HeaderTooLarge);
+    }
+    let (limit, discard) = match body_read {
+        BodyRead::Buffer(limit) => (limit, false),
+        BodyRead::Headers => return Ok(request),
+        BodyRead::Discard(limit) => (limit, true),
+    };
+    if timeout.is_some() {
+        stream.socket().set_read_timeout(timeout).map_err(ReadRequestError::Io)?;
+    }
+    
+    let headers = parse_headers(&String::from_utf8_lossy(&request[..head_end]));
+    if find_header(&headers, "Content-Length").and_then(|length| length.trim().parse::<u64>().ok()).is_some_and(|length| length > limit) {
+        return if discard { Err(ReadRequestError::BodyTooLarge) } else { Ok(request) };
+    }
+    let mut tracker = BodyTracker::new(BodyFraming::from_headers(&headers).unwrap_or(BodyFraming::Empty));
+    let mut body_len = tracker.feed(&request[head_end + 4..]);
+    if discard {
+        request.drain(head_end + 4..head_end + 4 + body_len);
+    }
+    while !tracker.is_complete() {
+        if body_len as u64 > limit {
+            if discard {
+                return Err(ReadRequestError::BodyTooLarge);
+            }
+            break;
+        }
+        let bytes_read = stream.read(&mut chunk).map_err(ReadRequestError::Io)?;
+        if bytes_read == 0 {
+            break;
+        }
+        let consumed = tracker.feed(&chunk[..bytes_read]);
+        body_len += consumed;
+        let kept = if discard { consumed } else { 0 };
+        request.extend_from_slice(&chunk[kept..bytes_read]);
+    }
+    Ok(request)
+}
+
+/// 请求头中是否有以空格或制表符开头的折叠续行
+fn has_folded_headers(request: &[u8]) -> bool {
+    let head_end = find_head_end(request).unwrap_or(request.len());
+    request[..head_end].split(|&b| b == b'\n')
+        .skip(1)
+        .any(|line| line.first().is_some_and(|&b| b == b' ' || b == b'\t'))
+}
+
+/// 把折叠的续行用一个空格接到上一行末尾，请求体保持不变
+fn unfold_headers(request: &[u8]) -> Vec<u8> {
+    let Some(head_end) = find_head_end(request) else {
+        return request.to_vec();
+    };
+    let mut unfolded: Vec<u8> = Vec::with_capacity(request.len());
+    let mut after_header = false;
+    for (index, line) in request[..head_end].split(|&b| b == b'\n').enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let folded = index > 0 && line.first().is_some_and(|&b| b == b' ' || b == b'\t');
+        // 紧跟在请求行之后的续行没有可以接上的头部，按RFC 7230 3.2.4忽略
+        if folded && !after_header {
+            continue;
+        }
+        after_header = index > 0;
+        if folded {
+            let continuation = line.trim_ascii_start();
+            // 去掉上一行的CRLF后接上续行
+            unfolded.truncate(unfolded.len() - 2);
+            while unfolded.last().is_some_and(|&b| b == b' ' || b == b'\t') {
+                unfolded.pop();
+            }
+            unfolded.push(b' ');
+            unfolded.extend_from_slice(continuation);
+        } else {
+            unfolded.extend_from_slice(line);
+        }
+        unfolded.extend_from_slice(b"\r\n");
+    }
+    unfolded.extend_from_slice(&request[head_end + 2..]);
+    unfolded
+}
+
+/// 会带请求体的方法是否缺少Content-Length和chunked，这类请求的请求体长度无从得知
+fn is_unframed_body_request(request: &[u8]) -> bool {
+    let Some(head_end) = find_head_end(request) else {
+        return false;
+    };
+    let head = String::from_utf8_lossy(&request[..head_end]);
+    matches!(extract_method(&head).as_str(), "POST" | "PUT" | "PATCH")
+        && BodyFraming::from_headers(&parse_headers(&head)).is_none()
+}
+
+/// 在wait时间内继续接收请求体，到时间或客户端关闭写方向时为止，然后按收到的长度补上Content-Length
+///
+/// 等待结束之后到达的数据仍然属于这个请求体，因此同时加上Connection: close，响应后关闭连接
+fn read_unframed_body(stream: &mut ClientStream, request: &[u8], wait: Duration) -> Vec<u8> {
+    let mut received = request.to_vec();
+    let deadline = Instant::now() + wait;
+    let mut chunk = [0; READ_CHUNK_SIZE];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || stream.socket().set_read_timeout(Some(remaining)).is_err() {
+            break;
+        }
+        match stream.read(&mut chunk) {
+            Ok(0) | Err(_) => break,
+            Ok(bytes_read) => received.extend_from_slice(&chunk[..bytes_read]),
+        }
+    }
+    let _ = stream.socket().set_read_timeout(None);
+    
+    let body_len = received.len() - find_head_end(&received).expect("请求头已经完整") - 4;
+    let mut delimited = replace_request_header(&received, "Connection", None);
+    let head_end = find_head_end(&delimited).expect("请求头已经完整");
+    let added = format!("Content-Length: {}\r\nConnection: close\r\n", body_len);
+    delimited.splice(head_end + 2..head_end + 2, added.bytes());
+    delimited
+}
+
+/// 请求在缓冲区中已经完整且后面还有多余数据时，返回请求结束的位置
+fn request_end(request: &[u8], headers: &[(String, String)]) -> Option<usize> {
+    let body_start = find_head_end(request)? + 4;
+    let body = &request[body_start..];
+    let mut tracker = BodyTracker::new(BodyFraming::from_headers(headers).unwrap_or(BodyFraming::Empty));
+    let body_len = tracker.feed(body);
+    (tracker.is_complete() && body_len < body.len()).then_some(body_start + body_len)
+}
+
+/// 数据是否以请求行开头：方法名为大写字母，请求行已经完整时还要有请求目标和HTTP/1.x版本；
+/// 请求行还没有收完时只检查已有的部分
+fn starts_with_request_line(data: &[u8]) -> bool {
+    let line_end = data.iter().position(|&b| b == b'\n');
+    let line = &data[..line_end.unwrap_or(data.len())];
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let mut parts = line.split(|&b| b == b' ');
+    let method = parts.next().unwrap_or_default();
+    if method.is_empty() || !method.iter().all(u8::is_ascii_uppercase) {
+        return false;
+    }
+    if line_end.is_none() {
+        return true;
+    }
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(target), Some(version), None) => !target.is_empty() && version.starts_with(b"HTTP/1."),
+        _ => false,
+    }
+}
+
+/// 校验请求体的Content-MD5和Digest（md5、sha-256），请求没有带这些头部时直接通过；
+/// 流式转发时只能校验随头部一起收到的完整请求体，其余情况明确返回错误
+fn verify_body_digest(request: &[u8], headers: &[(String, String)], streamed: bool) -> Result<(), &'static str> {
+    let content_md5 = find_header(headers, "Content-MD5");
+    let digest = find_header(headers, "Digest");
+    if content_md5.is_none() && digest.is_none() {
+        return Ok(());
+    }
+    
+    let body_start = find_head_end(request).map_or(request.len(), |head_end| head_end + 4);
+    let body = match BodyFraming::from_headers(headers) {
+        Some(BodyFraming::Length(length)) => {
+            let received = &request[body_start..];
+            if received.len() < length {
+                return Err(if streamed { "Streamed request body cannot be verified" } else { "Request body is incomplete" });
+            }
+            &received[..length]
+        }
+        Some(BodyFraming::Chunked) => return Err("Chunked request body cannot be verified"),
+        _ => &[],
+    };
+    digest::verify(body, content_md5, digest)
+}
+
+/// 处理HTTP/0.9请求，其响应没有状态行和头部，只有正文
+///
+/// 只有静态服务器可以按0.9方式响应，并且不会回源，其余情况一律返回400
+fn handle_http09_request(stream: &mut ClientStream, server_config: &ServerConfig, client_addr: &str, path: &str, request: &[u8], timing: &mut RequestTiming) -> usize {
+    let static_config = match &server_config.static_config {
+        Some(static_config) if server_config.server.http09 == Http09Mode::Respond && server_config.server_type.name == "static" => static_config,
+        _ => {
+            send_and_log(stream, client_addr, path, 400, &HttpResponse::error(400).error_format(server_config.error_format).build(), timing);
+            return 1;
+        }
+    };
+    
+    let mut static_config = static_config.clone();
+    static_config.fallback_proxy = None;
+    let (status_code, response) = match handle_static_request(&static_config, path, request, stream, timing) {
+        Outcome::Response(response) => (response.status(), response.error_format(server_config.error_format).build()),
+        Outcome::Raw(response) => (raw_status_code(&response), response),
+        Outcome::Streamed(status_code, _) => {
+            log_access(client_addr, path, status_code, timing);
+            return 1;
+        }
+        Outcome::File(response, file, length) => {
+            let written_before = stream.bytes_written();
+            if let Err(e) = send_file_body(stream, file, length) {
+                eprintln!("发送文件中断: {}: {}", path, e);
+            }
+            timing.bytes_sent = Some(stream.bytes_written() - written_before);
+            log_access(client_addr, path, response.status(), timing);
+            return 1;
+        }
+    };
+    
+    let body_start = find_head_end(&response).map_or(0, |head_end| head_end + 4);
+    send_and_log(stream, client_addr, path, status_code, &response[body_start..], timing);
+    1
+}
+
+/// 发送响应之后记录访问日志，日志中的字节数是实际写出的字节数
+fn send_and_log(stream: &mut ClientStream, client_addr: &str, path: &str, status_code: u16, response: &[u8], timing: &mut RequestTiming) {
+    let written_before = stream.bytes_written();
+    send_response(stream, response);
+    timing.bytes_sent = Some(stream.bytes_written() - written_before);
+    log_access(client_addr, path, status_code, timing);
+}
+
+/// 从响应的状态行提取状态码，接受任意HTTP版本；状态行无法解析时当作500
+fn response_status_code(response: &str) -> u16 {
+    let status_line = response.lines().next().unwrap_or("");
+    let mut parts = status_line.split(' ');
+    let is_http = parts.next().is_some_and(|version| version.starts_with("HTTP/"));
+    match parts.next() {
+        Some(code) if is_http && code.len() == 3 && code.bytes().all(|b| b.is_ascii_digit()) => code.parse().unwrap_or(500),
+        _ => 500,
+    }
+}
+
+/// 从原始响应报文的状态行提取状态码
+fn raw_status_code(response: &[u8]) -> u16 {
+    let line_end = response.iter().position(|&b| b == b'\n').unwrap_or(response.len());
+    response_status_code(&String::from_utf8_lossy(&response[..line_end]))
+}
+
+/// 发送HTTP响应，报文按原始字节写出
+fn send_response(stream: &mut impl Write, response: &[u8]) {
+    let _ = write_fully(stream, response);
+}
+
+/// 写出全部数据，慢速客户端导致部分写入或WouldBlock时继续写剩余部分
+fn write_fully<W: Write>(writer: &mut W, mut data: &[u8]) -> io::Result<()> {
+    while !data.is_empty() {
+        match writer.write(data) {
+            Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "连接已关闭")),
+            Ok(written) => data = &data[written..],
+            Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(1)),
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    writer.flush()
+}
+
+/// 每个客户端IP当前的连接数
+type IpConnections = Arc<Mutex<HashMap<IpAddr, usize>>>;
+
+/// 单个连接的计数登记，离开作用域时自动释放
+struct IpConnectionGuard {
+    connections: IpConnections,
+    ip: IpAddr,
+}
+
+impl IpConnectionGuard {
+    /// 登记一个新连接，该IP已达上限时返回None
+    fn acquire(connections: &IpConnections, ip: IpAddr, limit: usize) -> Option<IpConnectionGuard> {
+        let mut counts = connections.lock().unwrap();
+        let count = counts.entry(ip).or_insert(0);
+        if *count >= limit {
+            return None;
+        }
+        *count += 1;
+        Some(IpConnectionGuard { connections: Arc::clone(connections), ip })
+    }
+}
+
+impl Drop for IpConnectionGuard {
+    fn drop(&mut self) {
+        let mut counts = self.connections.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// 服务器处理中请求数的登记，离开作用域时自动释放
+struct InFlightGuard {
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl InFlightGuard {
+    /// 登记一个处理中的请求，已达上限时返回None
+    fn acquire(in_flight: &Arc<AtomicUsize>, limit: usize) -> Option<InFlightGuard> {
+        in_flight.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| (count < limit).then_some(count + 1)).ok()?;
+        Some(InFlightGuard { in_flight: Arc::clone(in_flight) })
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// 请求数达到上限时的503响应，优先使用配置的页面
+fn overload_response(server_config: &ServerConfig) -> HttpResponse {
+    if let Some(page_path) = &server_config.overload_page
+        && let Ok(page) = std::fs::read_to_string(page_path)
+    {
+        return HttpResponse::new(503)
+            .content_type(content_type_for(page_path, "text/html; charset=utf-8"))
+            .body(page);
+    }
+    HttpResponse::error(503).error_format(server_config.error_format)
+}
+
+/// 等待客户端的第一批数据，判断是否是发到TLS端口的明文HTTP请求：TLS握手以0x16开头，HTTP请求以大写的方法名开头
+fn is_plaintext_http(stream: &Socket, timeout: Option<Duration>) -> bool {
+    let _ = stream.set_read_timeout(timeout);
+    let mut prefix = [0; 8];
+    let peeked = stream.peek(&mut prefix);
+    let _ = stream.set_read_timeout(None);
+    match peeked {
+        Ok(length) if length > 0 => {
+            prefix[0].is_ascii_uppercase() && prefix[..length].iter().take_while(|&&b| b != b' ').all(u8::is_ascii_uppercase)
+        }
+        _ => false,
+    }
+}
+
+/// 用明文的400回应发到TLS端口的HTTP请求，然后关闭连接
+fn reject_plaintext_http(stream: Socket, peer_addr: ClientAddr, message: &str, access_log: &Arc<AccessLogOutput>) {
+    let Ok(mut stream) = ClientStream::new(stream, peer_addr, None) else {
+        return;
+    };
+    // 先读掉已经到达的请求，关闭时接收缓冲区中还有数据会发送RST，客户端可能收不到响应
+    let _ = stream.read(&mut [0; 8192]);
+    let response = HttpResponse::new(400)
+        .content_type("text/plain; charset=utf-8")
+        .header("Connection", "close")
+        .body(message);
+    send_and_log(&mut stream, &peer_addr.to_string(), "-", 400, &response.build(), &mut RequestTiming::start(access_log));
+}
+
+/// 处理一个已接受的连接
+fn serve_connection(stream: Socket, state: &ServerState) {
+    let live = state.live();
+    let server_config = &live.config;
+    // 设置SO_LINGER后close会阻塞到剩余数据发出或超时；设为0则直接发送RST丢弃未发送的数据
+    if let Some(linger_secs) = server_config.server.linger_secs {
+        let _ = stream.set_linger(Some(Duration::from_secs(linger_secs)));
+    }
+    
+    // 客户端长时间不读取响应时写入超时，避免工作线程一直阻塞
+    if server_config.server.timeout_secs > 0 {
+        let _ = stream.set_write_timeout(Some(Duration::from_secs(server_config.server.timeout_secs)));
+    }
+    
+    // 取不到对端地址的连接已经失效（通常是客户端在排队期间已经重置），限流和按IP的限制都无从谈起，直接关闭
+    let peer_addr = match stream.peer_addr() {
+        Ok(peer_addr) => peer_addr,
+        Err(e) => {
+            write_log_line(&format!("[{}] 无法获取客户端地址，关闭连接: {}", log_timestamp(), e), LogLevel::Warn);
+            let _ = stream.shutdown(Shutdown::Both);
+            return;
+        }
+    };
+    // 把http://用在https端口上的客户端收到说明原因的明文400，而不是无法理解的TLS告警
+    let request_timeout = server_config.server.request_timeout_secs.unwrap_or(server_config.server.timeout_secs);
+    if let Some(tls_config) = server_config.tls.as_ref().filter(|_| state.tls.is_some())
+        && is_plaintext_http(&stream, Some(request_timeout).filter(|&secs| secs > 0).map(Duration::from_secs))
+    {
+        reject_plaintext_http(stream, peer_addr, &tls_config.plaintext_http_message, &state.access_log);
+        return;
+    }
+    
+    let mut stream = match ClientStream::new(stream, peer_addr, state.tls.as_ref()) {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("无法建立TLS连接: {}", e);
+            return;
+        }
+    };
+    let client_addr = peer_addr.to_string();
+    
+    // 限制单个IP的并发连接数；Unix socket的客户端没有IP，不受限制
+    let _guard = match (server_config.server.max_connections_per_ip, peer_addr.ip()) {
+        (Some(limit), Some(ip)) => match IpConnectionGuard::acquire(&state.ip_connections, ip, limit) {
+            Some(guard) => Some(guard),
+            None => {
+                send_and_log(&mut stream, &client_addr, "-", 429, &HttpResponse::error(429).error_format(server_config.error_format).build(), &mut RequestTiming::start(&state.access_log));
+                return;
+            }
+        },
+        _ => None,
+    };
+    
+    if !server_config.server.connection_log {
+        Connection::new(stream, state).process();
+        return;
+    }
+    
+    let accepted_at = Instant::now();
+    log_connection_opened(&client_addr);
+    let requests = Connection::new(stream, state).process();
+    log_connection_closed(&client_addr, accepted_at.elapsed(), requests);
+}
+
+/// 按服务器配置创建中间件，配置了未知的中间件时直接退出
+fn build_middlewares(server_config: &ServerConfig) -> Result<Vec<Box<dyn Middleware>>, String> {
+    let server_header = match server_config.server_header.as_deref() {
+        None => Some(format!("nextWeb/{}", env!("CARGO_PKG_VERSION"))),
+        Some("") => None,
+        Some(value) => Some(expand_server_header(value)),
+    };
+    middleware::build(&server_config.middlewares, server_header)
+}
+
+/// 填入config.toml中对所有服务器生效的设置
+fn apply_global_settings(server_config: &mut ServerConfig, name: &str, config: &Config) {
+    apply_server_header(server_config, config.server_header.as_ref());
+    apply_keep_alive(server_config);
+    server_config.load_shedding = config.load_shedding.clone();
+    server_config.name = name.to_string();
+}
+
+/// 服务器没有单独配置Server头时使用全局配置，并传给该服务器用到的代理配置
+fn apply_server_header(server_config: &mut ServerConfig, global: Option<&String>) {
+    if server_config.server_header.is_none() {
+        server_config.server_header = global.cloned();
+    }
+    let server_header = server_config.server_header.clone();
+    for proxy_config in proxy_configs_mut(server_config) {
+        proxy_config.server_header = server_header.clone();
+    }
+}
+
+/// 把服务器的keepalive_timeout_secs是否开启告诉该服务器用到的代理配置
+fn apply_keep_alive(server_config: &mut ServerConfig) {
+    let keep_alive = server_config.server.keepalive_timeout_secs > 0;
+    for proxy_config in proxy_configs_mut(server_config) {
+        proxy_config.keep_alive = keep_alive;
+    }
+}
+
+/// 服务器用到的全部代理配置：代理服务器本身和静态服务器的回源代理，包括各虚拟主机中的
+fn proxy_configs_mut(server_config: &mut ServerConfig) -> Vec<&mut ProxyConfig> {
+    let fallback_proxy = server_config.static_config.as_mut().and_then(|static_config| static_config.fallback_proxy.as_mut());
+    let vhost_proxies = server_config.vhosts.iter_mut().flat_map(|vhost| {
+        let fallback_proxy = vhost.static_config.as_mut().and_then(|static_config| static_config.fallback_proxy.as_mut());
+        vhost.proxy_config.as_mut().into_iter().chain(fallback_proxy)
+    });
+    let route_proxies = server_config.header_routes.iter_mut().flat_map(|route| {
+        let fallback_proxy = route.static_config.as_mut().and_then(|static_config| static_config.fallback_proxy.as_mut());
+        route.proxy_config.as_mut().into_iter().chain(fallback_proxy)
+    });
+    server_config.proxy_config.as_mut().into_iter().chain(fallback_proxy).chain(vhost_proxies).chain(route_proxies).collect()
+}
+
+/// 绑定服务器的监听端口或Unix domain socket
+fn bind_server(server_config: &ServerConfig) -> io::Result<Listener> {
+    match &server_config.server.unix_socket {
+        Some(path) => Listener::bind_unix(Path::new(path)),
+        None => Listener::bind_tcp(&listen_address(&server_config.server)),
+    }
+}
+
+/// 配置中的监听地址，用于日志
+fn listen_address(server: &ServerInfo) -> String {
+    match &server.unix_socket {
+        Some(path) => format!("unix:{}", path),
+        None => format!("{}:{}", server.address, server.port),
+    }
+}
+
+/// 找出监听地址冲突的服务器：Unix socket路径相同，或者端口相同并且地址相同或其中一个监听所有地址（0.0.0.0或::）
+fn duplicate_listen_addresses(servers: &[(&str, &ServerInfo)]) -> Vec<String> {
+    let unspecified = |address: &str| address.parse::<IpAddr>().is_ok_and(|ip| ip.is_unspecified());
+    let mut problems = Vec::new();
+    for (index, (name, server)) in servers.iter().enumerate() {
+        for (other_name, other) in &servers[..index] {
+            let conflict = match (&server.unix_socket, &other.unix_socket) {
+                (Some(path), Some(other_path)) => Path::new(path) == Path::new(other_path),
+                (None, None) => server.port == other.port
+                    && (server.address == other.address || unspecified(&server.address) || unspecified(&other.address)),
+                _ => false,
+            };
+            if conflict {
+                problems.push(format!("服务器 '{}' 的监听地址 {} 与服务器 '{}' 的 {} 冲突",
+                    name, listen_address(server), other_name, listen_address(other)));
+            }
+        }
+    }
+    problems
+}
+
+/// 启动时列出每个服务器的名称、类型和实际监听的地址，绑定失败的服务器标为未启动
+fn print_startup_summary(servers: &[(&str, String, Option<String>)]) {
+    // 中文字符按两列宽对齐
+    let width = |text: &str| text.chars().map(|c| if c.is_ascii() { 1 } else { 2 }).sum::<usize>();
+    let pad = |text: &str, column: usize| format!("{}{}", text, " ".repeat(column.saturating_sub(width(text))));
+    let name_column = servers.iter().map(|(name, ..)| width(name)).chain([width("名称")]).max().unwrap_or(0);
+    let type_column = servers.iter().map(|(_, server_type, _)| width(server_type)).chain([width("类型")]).max().unwrap_or(0);
+    println!("{}  {}  地址", pad("名称", name_column), pad("类型", type_column));
+    for (name, server_type, address) in servers {
+        println!("{}  {}  {}", pad(name, name_column), pad(server_type, type_column), address.as_deref().unwrap_or("未启动（绑定失败）"));
+    }
+}
+
+/// 每个连接最多同时占用的文件描述符：客户端连接，加上后端连接或打开的文件
+const DESCRIPTORS_PER_CONNECTION: usize = 2;
+/// 为监听socket、标准输出等保留的文件描述符
+const RESERVED_DESCRIPTORS: usize = 32;
+
+/// 所有服务器已接受、尚未关闭的连接数
+static OPEN_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+/// 连接占用的文件描述符上限，启动时设置一次
+static DESCRIPTOR_LIMIT: OnceLock<usize> = OnceLock::new();
+/// 是否正在拒绝新连接，只在状态变化时记录日志
+static BACKPRESSURE: AtomicBool = AtomicBool::new(false);
+
+/// 设置文件描述符上限，未配置时读取进程的RLIMIT_NOFILE软限制
+fn init_descriptor_limit(fd_soft_limit: Option<usize>) {
+    let limit = fd_soft_limit.unwrap_or_else(|| {
+        let mut rlimit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+        if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlimit) } != 0 {
+            return usize::MAX;
+        }
+        usize::try_from(rlimit.rlim_cur).unwrap_or(usize::MAX)
+    });
+    let _ = DESCRIPTOR_LIMIT.set(limit.saturating_sub(RESERVED_DESCRIPTORS));
+}
+
+/// 一个已接受连接的文件描述符登记，离开作用域时自动释放
+struct DescriptorGuard;
+
+impl DescriptorGuard {
+    /// 登记一个新连接，文件描述符即将耗尽时返回None
+    fn acquire() -> Option<DescriptorGuard> {
+        let limit = DESCRIPTOR_LIMIT.get().copied().unwrap_or(usize::MAX);
+        let admitted = OPEN_CONNECTIONS.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+            ((count + 1).saturating_mul(DESCRIPTORS_PER_CONNECTION) <= limit).then_some(count + 1)
+        });
+        let engaged = admitted.is_err();
+        if BACKPRESSURE.swap(engaged, Ordering::SeqCst) != engaged {
+            let line = if engaged {
+                format!("[{}] 连接可用的文件描述符接近上限 {}，暂停接受新连接（返回503），请考虑提高ulimit -n", log_timestamp(), limit)
+            } else {
+                format!("[{}] 文件描述符恢复正常，继续接受新连接", log_timestamp())
+            };
+            write_log_line(&line, LogLevel::Warn);
+        }
+        admitted.ok().map(|_| DescriptorGuard)
+    }
+}
+
+impl Drop for DescriptorGuard {
+    fn drop(&mut self) {
+        OPEN_CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// 为新接受的连接登记文件描述符，即将耗尽时直接返回503并关闭连接
+fn admit_connection(stream: &mut Socket, state: &ServerState) -> Option<DescriptorGuard> {
+    let live = state.live();
+    let server_config = &live.config;
+    let guard = DescriptorGuard::acquire();
+    if guard.is_none() {
+        let client_addr = stream.peer_addr().map_or_else(|_| String::from("unknown"), |addr| addr.to_string());
+        log_access(&client_addr, "-", 503, &RequestTiming::start(&state.access_log));
+        // TLS端口上还没有握手，无法发送明文的503，只能直接关闭
+        if server_config.tls.is_none() {
+            send_response(stream, &HttpResponse::error(503).error_format(server_config.error_format).build());
+        }
+    }
+    guard
+}
+
+/// 接受连接失败，文件描述符耗尽时稍等再继续，避免空转
+fn handle_accept_error(error: &io::Error) {
+    eprintln!("接受连接失败: {}", error);
+    if error.raw_os_error() == Some(libc::EMFILE) || error.raw_os_error() == Some(libc::ENFILE) {
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// 当前生效的服务器配置和由它生成的中间件，重新加载配置时整体替换
+struct LiveConfig {
+    config: ServerConfig,
+    middlewares: Vec<Box<dyn Middleware>>,
+    // 与rate_limits一一对应，保存各规则下每个客户端的令牌桶，重新加载配置时按新规则重建
+    rate_limiters: Vec<RateLimiter>,
+}
+
+impl LiveConfig {
+    fn new(config: ServerConfig, middlewares: Vec<Box<dyn Middleware>>) -> Arc<LiveConfig> {
+        let rate_limiters = config.rate_limits.iter()
+            .map(|rate_limit| RateLimiter::new(&rate_limit.path, rate_limit.requests, Duration::from_secs(rate_limit.per_secs),
+                rate_limit.burst.unwrap_or(rate_limit.requests)))
+            .collect();
+        Arc::new(LiveConfig { config, middlewares, rate_limiters })
+    }
+}
+
+/// 一个服务器的配置和各连接共享的状态
+///
+/// 启动时创建一次，由Arc在接受线程、工作线程和配置监视线程之间共享；
+/// 需要跨请求修改的计数器等都放在这里（限流表随配置放在LiveConfig中），内部用原子类型或Mutex保证线程安全
+struct ServerState {
+    // 每个请求开始时取一份快照，重新加载配置不影响正在处理的请求
+    live: RwLock<Arc<LiveConfig>>,
+    ip_connections: IpConnections,
+    in_flight: Arc<AtomicUsize>,
+    // 配置了TLS时由证书和私钥生成的TLS配置
+    tls: Option<Arc<rustls::ServerConfig>>,
+    access_log: Arc<AccessLogOutput>,
+    // 服务器启动的时间，健康检查据此报告运行时间
+    started: Instant,
+    // 预热是否已经完成，未配置warmup时一开始就是true
+    ready: AtomicBool,
+}
+
+impl ServerState {
+    fn new(server_config: ServerConfig, middlewares: Vec<Box<dyn Middleware>>, tls: Option<Arc<rustls::ServerConfig>>, access_log: Arc<AccessLogOutput>) -> Arc<ServerState> {
+        let warmup = server_config.warmup.is_some();
+        Arc::new(ServerState {
+            live: RwLock::new(LiveConfig::new(server_config, middlewares)),
+            ip_connections: Arc::new(Mutex::new(HashMap::new())),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            tls,
+            access_log,
+            started: Instant::now(),
+            ready: AtomicBool::new(!warmup),
+        })
+    }
+    
+    /// 当前生效的配置
+    fn live(&self) -> Arc<LiveConfig> {
+        Arc::clone(&self.live.read().unwrap())
+    }
+    
+    /// 换上重新加载的配置，之后开始的请求使用新配置
+    fn replace_config(&self, server_config: ServerConfig, middlewares: Vec<Box<dyn Middleware>>) {
+        *self.live.write().unwrap() = LiveConfig::new(server_config, middlewares);
+    }
+}
+
+/// 启动服务器
+fn start_server(listener: Listener, state: Arc<ServerState>) {
+    let live = state.live();
+    let worker_threads = live.config.server.worker_threads
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |cores| cores.get()));
+    let queue_capacity = live.config.server.worker_queue_capacity;
+    
+    if live.config.warmup.is_some() {
+        let state = Arc::clone(&state);
+        thread::spawn(move || run_warmup(&state));
+    }
+    
+    run_worker_pool(listener, state, worker_threads, queue_capacity);
+}
+
+/// 预热时两次尝试连接后端之间的间隔
+const WARMUP_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 执行预热：等待配置的时间，解析后端地址并等到至少一个后端可以连接，完成或超时后标记为就绪
+fn run_warmup(state: &ServerState) {
+    let live = state.live();
+    let server_config = &live.config;
+    let Some(warmup) = &server_config.warmup else {
+        return;
+    };
+    let deadline = warmup.timeout_secs.map(|timeout| Instant::now() + Duration::from_secs(timeout));
+    thread::sleep(Duration::from_secs(warmup.delay_secs));
+    
+    let proxy_config = server_config.proxy_config.as_ref().filter(|_| server_config.server_type.name == "proxy");
+    if let Some(proxy_config) = proxy_config.filter(|_| warmup.check_backends) {
+        loop {
+            if SHUTDOWN.load(Ordering::SeqCst) {
+                return;
+            }
+            let reachable = backend_candidates(proxy_config).iter().flatten().any(|backend_addr| {
+                let result = backend_addr.connect(BACKEND_CONNECT_TIMEOUT);
+                record_backend_connect(backend_addr, result.as_ref().map(|_| ()));
+                result.is_ok()
+            });
+            if reachable {
+                break;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                write_log_line(&format!("[{}] 服务器 '{}' 预热超时，没有可以连接的后端，仍然开始服务", log_timestamp(), server_config.name), LogLevel::Warn);
+                break;
+            }
+            thread::sleep(WARMUP_RETRY_INTERVAL);
+        }
+    }
+    
+    state.ready.store(true, Ordering::SeqCst);
+    write_log_line(&format!("[{}] 服务器 '{}' 预热完成，开始接受请求", log_timestamp(), server_config.name), LogLevel::Info);
+}
+
+/// 工作队列已满时两次拒绝日志之间的最短间隔
+const SHED_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 在接受线程上发送配置的503并关闭连接，写入超时很短，慢速客户端不会拖住接受线程
+fn shed_connection(mut stream: Socket, server_config: &ServerConfig, load_shedding: &LoadSheddingConfig) {
+    // TLS端口上还没有握手，无法发送明文的503
+    if server_config.tls.is_some() {
+        return;
+    }
+    let mut response = match &load_shedding.body {
+        Some(body) => HttpResponse::new(503).content_type(&load_shedding.content_type).body(body.as_str()),
+        None => HttpResponse::error(503).error_format(server_config.error_format),
+    };
+    if let Some(retry_after) = load_shedding.retry_after {
+        response.set_header("Retry-After", &retry_after.to_string());
+    }
+    response.set_header("Connection", "close");
+    let _ = stream.set_write_timeout(Some(Duration::from_millis(100)));
+    let _ = stream.write_all(&response.build());
+}
+
+/// 当前线程只负责接受连接并放入队列，连接全部交给工作线程处理
+///
+/// 队列深度创新高时记录日志，用于观察工作线程是否跟得上接受速度
+fn run_worker_pool(listener: Listener, state: Arc<ServerState>, worker_threads: usize, queue_capacity: usize) {
+    let pool = ThreadPool::new(worker_threads, queue_capacity);
+    let queue_depth = Arc::new(AtomicUsize::new(0));
+    
+    let mut max_depth = 0;
+    // 拒绝连接的日志最多每SHED_LOG_INTERVAL记录一次，记录期间累计的数量
+    let mut shed_count = 0;
+    let mut last_shed_log = None;
+    // 监听套接字设为非阻塞，poll超时或被信号打断后检查SHUTDOWN，收到信号后停止接受新连接
+    if let Err(e) = listener.set_nonblocking(true) {
+        eprintln!("无法把监听套接字设为非阻塞: {}", e);
+        return;
+    }
+    while !SHUTDOWN.load(Ordering::SeqCst) {
+        if !wait_for_connection(&listener, SHUTDOWN_POLL_INTERVAL) {
+            continue;
+        }
+        let stream = listener.accept().and_then(|stream| stream.set_nonblocking(false).map(|()| stream));
+        match stream {
+            Ok(mut stream) => {
+                // 排队中的连接同样占用文件描述符
+                let Some(descriptor_guard) = admit_connection(&mut stream, &state) else {
+                    continue;
+                };
+                // 队列已满时在接受线程上直接拒绝，不占用工作线程
+                let live = state.live();
+                if let Some(load_shedding) = &live.config.load_shedding
+                    && queue_depth.load(Ordering::SeqCst) >= queue_capacity
+                {
+                    shed_connection(stream, &live.config, load_shedding);
+                    shed_count += 1;
+                    if last_shed_log.is_none_or(|logged: Instant| logged.elapsed() >= SHED_LOG_INTERVAL) {
+                        let line = format!("[{}] 工作队列已满，拒绝了 {} 个连接", log_timestamp(), shed_count);
+                        write_log_line(&line, LogLevel::Warn);
+                        last_shed_log = Some(Instant::now());
+                        shed_count = 0;
+                    }
+                    continue;
+                }
+                let depth = queue_depth.fetch_add(1, Ordering::SeqCst) + 1;
+                if depth > max_depth {
+                    max_depth = depth;
+                    let timestamp = log_timestamp();
+                    write_log_line(&format!("[{}] 接受队列深度达到 {}", timestamp, depth), LogLevel::Info);
+                }
+                let state = Arc::clone(&state);
+                let queue_depth = Arc::clone(&queue_depth);
+                let queued = pool.execute(move || {
+                    let _descriptor_guard = descriptor_guard;
+                    queue_depth.fetch_sub(1, Ordering::SeqCst);
+                    serve_connection(stream, &state);
+                });
+                if !queued {
+                    eprintln!("工作线程已全部退出");
+                    return;
+                }
+            }
+            // 其他接受线程或客户端重置连接导致poll之后没有可接受的连接
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => handle_accept_error(&e),
+        }
+    }
+}
+
+/// 接受循环检查SHUTDOWN的间隔
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// 收到SIGINT或SIGTERM后置位，接受循环停止接受新连接，keep-alive连接处理完当前请求后关闭
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/// 信号处理函数中只修改原子变量，保证异步信号安全
+extern "C" fn request_shutdown(_signal: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+/// 安装SIGINT和SIGTERM的处理函数，代替默认的直接终止进程
+fn install_shutdown_handlers() {
+    let handler = request_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+/// 等待监听套接字上有新连接，最多等待timeout；超时或被信号打断时返回false
+fn wait_for_connection(listener: &Listener, timeout: Duration) -> bool {
+    use std::os::fd::AsRawFd;
+
+    let mut pollfd = libc::pollfd { fd: listener.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+    unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as libc::c_int) > 0 }
+}
+
+/// 所有接受线程退出后等待已接受的连接处理完成，最多等待timeout，返回仍未完成的连接数
+fn drain_connections(timeout: Duration) -> usize {
+    let open = OPEN_CONNECTIONS.load(Ordering::SeqCst);
+    write_log_line(&format!("[{}] 正在关闭，等待 {} 个连接完成", log_timestamp(), open), LogLevel::Warn);
+    let deadline = Instant::now() + timeout;
+    loop {
+        let open = OPEN_CONNECTIONS.load(Ordering::SeqCst);
+        if open == 0 || Instant::now() >= deadline {
+            return open;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// 切换到指定的用户和组，先设置组再设置用户，任何一步失败都返回错误
+fn drop_privileges(user: Option<&str>, group: Option<&str>) -> Result<(), String> {
+    use std::ffi::CString;
+    
+    let passwd = match user {
+        Some(user) => {
+            let name = CString::new(user).map_err(|_| format!("无效的用户名: {}", user))?;
+            // 此时还没有启动其他线程，可以安全调用getpwnam
+            let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+            if passwd.is_null() {
+                return Err(format!("用户不存在: {}", user));
+            }
+            Some(unsafe { ((*passwd).pw_uid, (*passwd).pw_gid) })
+        }
+        None => None,
+    };
+    
+    // 未指定组时使用该用户的主组
+    let gid = match group {
+        Some(group) => {
+            let name = CString::new(group).map_err(|_| format!("无效的组名: {}", group))?;
+            let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+            if entry.is_null() {
+                return Err(format!("组不存在: {}", group));
+            }
+            Some(unsafe { (*entry).gr_gid })
+        }
+        None => passwd.map(|(_, gid)| gid),
+    };
+    
+    if let Some(gid) = gid {
+        if unsafe { libc::setgroups(1, &gid) } != 0 {
+            return Err(format!("setgroups失败: {}", io::Error::last_os_error()));
+        }
+        if unsafe { libc::setgid(gid) } != 0 {
+            return Err(format!("setgid失败: {}", io::Error::last_os_error()));
+        }
+    }
+    if let Some((uid, _)) = passwd
+        && unsafe { libc::setuid(uid) } != 0 {
+        return Err(format!("setuid失败: {}", io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// 没有配置文件时使用的默认静态服务器，服务当前目录
+const DEFAULT_STATIC_CONFIG: &str = r#"
+[server]
+address = "127.0.0.1"
+port = 8080
+
+[type]
+name = "static"
+
+[static]
+webroot = "."
+index = "index.html"
+"#;
+
+/// 定期检查config.toml和各服务器的配置文件，有修改时重新加载
+///
+/// 新配置全部检查通过后才替换，任何一个服务器的配置有问题时保留所有服务器的旧配置
+fn watch_config(config_path: String, running: Vec<(Server, Arc<ServerState>)>, interval: Duration) {
+    let watched_files = |running: &[(Server, Arc<ServerState>)]| {
+        let mut paths = vec![PathBuf::from(&config_path)];
+        paths.extend(running.iter().map(|(server, _)| PathBuf::from(&server.config)));
+        FileWatcher::new(paths)
+    };
+    let mut running = running;
+    let mut watcher = watched_files(&running);
+    while !SHUTDOWN.load(Ordering::SeqCst) {
+        thread::sleep(interval);
+        if !watcher.changed() {
+            continue;
+        }
+        write_log_line(&format!("[{}] 配置文件已修改，重新加载", log_timestamp()), LogLevel::Info);
+        match reload_config(&config_path, &mut running) {
+            Ok(()) => write_log_line(&format!("[{}] 配置已重新加载", log_timestamp()), LogLevel::Info),
+            Err(problems) => {
+                let line = format!("[{}] 新配置无效，继续使用原来的配置:\n{}", log_timestamp(), problems.join("\n"));
+                write_log_line(&line, LogLevel::Error);
+            }
+        }
+        // 服务器的配置文件路径可能改变了
+        watcher = watched_files(&running);
+    }
+}
+
+/// 重新加载各运行中服务器的配置，返回发现的全部问题
+///
+/// 监听地址、工作线程、TLS证书和访问日志在启动时就已经生效，重新加载时沿用原来的值；限流规则按新配置重建，计数重新开始；
+/// config.toml中新增或删除的服务器需要重启才能生效
+fn reload_config(config_path: &str, running: &mut [(Server, Arc<ServerState>)]) -> Result<(), Vec<String>> {
+    let config = load_config(config_path).map_err(|e| vec![e.to_string()])?;
+    let mut problems = Vec::new();
+    let mut reloaded = Vec::new();
+    for (server, state) in running.iter() {
+        let Some(entry) = config.servers.iter().find(|entry| entry.name == server.name) else {
+            write_log_line(&format!("[{}] 服务器 '{}' 已从config.toml中删除，需要重启才能停止", log_timestamp(), server.name), LogLevel::Warn);
+            continue;
+        };
+        let mut server_config = match load_server_config(&entry.config) {
+            Ok(server_config) => server_config,
+            Err(e) => {
+                problems.push(format!("服务器 '{}': {}", server.name, e));
+                continue;
+            }
+        };
+        let old = state.live();
+        if listen_address(&server_config.server) != listen_address(&old.config.server) {
+            write_log_line(&format!("[{}] 服务器 '{}' 的监听地址需要重启才能修改", log_timestamp(), server.name), LogLevel::Warn);
+        }
+        server_config.server.address = old.config.server.address.clone();
+        server_config.server.port = old.config.server.port;
+        server_config.server.unix_socket = old.config.server.unix_socket.clone();
+        server_config.server.worker_threads = old.config.server.worker_threads;
+        server_config.server.worker_queue_capacity = old.config.server.worker_queue_capacity;
+        server_config.tls = old.config.tls.clone();
+        server_config.log = old.config.log.clone();
+        apply_global_settings(&mut server_config, &server.name, &config);
+        match build_middlewares(&server_config) {
+            Ok(middlewares) => reloaded.push((entry.clone(), server_config, middlewares)),
+            Err(e) => problems.push(format!("服务器 '{}': 中间件配置无效: {}", server.name, e)),
+        }
+    }
+    for entry in &config.servers {
+        if !running.iter().any(|(server, _)| server.name == entry.name) {
+            write_log_line(&format!("[{}] 新增的服务器 '{}' 需要重启才能启动", log_timestamp(), entry.name), LogLevel::Warn);
+        }
+    }
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+    
+    for (entry, server_config, middlewares) in reloaded {
+        if let Some((server, state)) = running.iter_mut().find(|(server, _)| server.name == entry.name) {
+            state.replace_config(server_config, middlewares);
+            *server = entry;
+        }
+    }
+    // 缓存的.mime可能随webroot一起改变
+    MIME_SIDECARS.lock().unwrap().clear();
+    Ok(())
+}
+
+fn main() {
+    // --read-binary-log <文件>：把二进制访问日志转换成文本后退出
+    if let Some(position) = env::args().position(|arg| arg == "--read-binary-log") {
+        let Some(path) = env::args().nth(position + 1) else {
+            eprintln!("用法: nextWeb --read-binary-log <文件>");
+            std::process::exit(2);
+        };
+        if let Err(e) = print_binary_log(&path) {
+            eprintln!("无法读取二进制日志 {}: {}", path, e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    
+    println!("nextWeb {}", env!("CARGO_PKG_VERSION"));
+    
+    // 主配置文件：--config <文件>优先，其次是环境变量NEXTWEB_CONFIG，默认为当前目录下的config.toml
+    let config_path = match env::args().position(|arg| arg == "--config") {
+        Some(position) => match env::args().nth(position + 1) {
+            Some(path) => path,
+            None => {
+                eprintln!("用法: nextWeb --config <文件>");
+                std::process::exit(2);
+            }
+        },
+        None => env::var("NEXTWEB_CONFIG").unwrap_or_else(|_| String::from("config.toml")),
+    };
+    
+    // 指定--default-static且没有主配置文件时，直接把当前目录作为静态站点
+    if env::args().any(|arg| arg == "--default-static") && !Path::new(&config_path).exists() {
+        println!("警告: 未找到{}，以默认静态服务器模式运行（当前目录，端口8080）", config_path);
+        let mut server_config: ServerConfig = toml::from_str(DEFAULT_STATIC_CONFIG).expect("默认配置无效");
+        server_config.name = String::from("default_static");
+        init_descriptor_limit(None);
+        let listener = match bind_server(&server_config) {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("无法绑定 {}: {}", listen_address(&server_config.server), e);
+                std::process::exit(1);
+            }
+        };
+        print_startup_summary(&[("default_static", String::from("static"), listener.local_addr().ok().map(|addr| format!("http://{}", addr)))]);
+        let middlewares = build_middlewares(&server_config).expect("中间件配置无效");
+        let access_log = AccessLogOutput::open(&LogConfig::default(), false).expect("访问日志配置无效");
+        install_shutdown_handlers();
+        start_server(listener, ServerState::new(server_config, middlewares, None, access_log));
+        drain_connections(Duration::from_secs(default_shutdown_timeout_secs()));
+        flush_log();
+        return;
+    }
+    
+    let config = match load_config(&config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    // 先检查全局和所有服务器的配置，有任何问题时一起列出后退出，不绑定任何端口
+    let mut problems = Vec::new();
+    if let Err(e) = configure_log_timestamp(&config.log_timestamp_format, config.log_timezone) {
+        problems.push(e);
+    }
+    init_descriptor_limit(config.fd_soft_limit);
+    if config.log_buffering {
+        enable_log_buffering();
+    }
+    
+    let prepared: Vec<_> = config.servers.iter()
+        .filter_map(|server| {
+            let mut server_config = match load_server_config(&server.config) {
+                Ok(server_config) => server_config,
+                Err(e) => {
+                    problems.push(format!("服务器 '{}': {}", server.name, e));
+                    return None;
+                }
+            };
+            apply_global_settings(&mut server_config, &server.name, &config);
+            let middlewares = match build_middlewares(&server_config) {
+                Ok(middlewares) => middlewares,
+                Err(e) => {
+                    problems.push(format!("服务器 '{}': 中间件配置无效: {}", server.name, e));
+                    return None;
+                }
+            };
+            let tls = match &server_config.tls {
+                Some(tls_config) => match connection::load_tls_config(&tls_config.cert, &tls_config.key,
+                    tls_config.session_resumption.then_some(tls_config.session_ticket_lifetime_secs),
+                    tls_config.client_ca.as_deref().map(|ca_path| (ca_path, tls_config.require_client_cert))) {
+                    Ok(tls) => Some(tls),
+                    Err(e) => {
+                        problems.push(format!("服务器 '{}': {}", server.name, e));
+                        return None;
+                    }
+                },
+                None => None,
+            };
+            let access_log = match AccessLogOutput::open(&server_config.log, config.log_buffering) {
+                Ok(access_log) => access_log,
+                Err(e) => {
+                    problems.push(format!("服务器 '{}': {}", server.name, e));
+                    return None;
+                }
+            };
+            Some((server, server_config, middlewares, tls, access_log))
+        })
+        .collect();
+    let listen_addresses: Vec<_> = prepared.iter().map(|(server, server_config, ..)| (server.name.as_str(), &server_config.server)).collect();
+    problems.extend(duplicate_listen_addresses(&listen_addresses));
+    if !problems.is_empty() {
+        eprintln!("配置检查失败，没有启动任何服务器:");
+        for problem in &problems {
+            eprintln!("{}", problem);
+        }
+        std::process::exit(1);
+    }
+    
+    // 全部检查通过后绑定所有端口，再降权，之后才开始处理请求；端口被占用等绑定失败的服务器跳过，不影响其他服务器
+    let mut summary = Vec::new();
+    let listeners: Vec<_> = prepared.into_iter()
+        .filter_map(|(server, server_config, middlewares, tls, access_log)| {
+            let scheme = if tls.is_some() { "https" } else { "http" };
+            match bind_server(&server_config) {
+                Ok(listener) => {
+                    let address = match listener.local_addr() {
+                        Ok(addr @ SocketAddress::Unix(_)) if tls.is_some() => format!("{} (TLS)", addr),
+                        Ok(addr @ SocketAddress::Unix(_)) => addr.to_string(),
+                        Ok(addr) => format!("{}://{}", scheme, addr),
+                        Err(_) => String::from("-"),
+                    };
+                    summary.push((server.name.as_str(), server_config.server_type.name.clone(), Some(address)));
+                    Some((listener, server, server_config, middlewares, tls, access_log))
+                }
+                Err(e) => {
+                    eprintln!("服务器 '{}' 无法绑定 {}，已跳过: {}", server.name, listen_address(&server_config.server), e);
+                    summary.push((server.name.as_str(), server_config.server_type.name.clone(), None));
+                    None
+                }
+            }
+        })
+        .collect();
+    print_startup_summary(&summary);
+    if listeners.is_empty() {
+        eprintln!("没有可以启动的服务器");
+        std::process::exit(1);
+    }
     
-    let config = load_config("config.toml");
+    if config.user.is_some() || config.group.is_some() {
+        if let Err(e) = drop_privileges(config.user.as_deref(), config.group.as_deref()) {
+            eprintln!("降权失败: {}", e);
+            std::process::exit(1);
+        }
+        println!("已切换到用户 {} 组 {}",
+            config.user.as_deref().unwrap_or("-"), config.group.as_deref().unwrap_or("-"));
+    }
     
+    install_shutdown_handlers();
     let mut handles = vec![];
+    let mut access_logs = vec![];
+    let mut running = vec![];
     
-    for server in config.servers {
+    for (listener, server, server_config, middlewares, tls, access_log) in listeners {
+        access_logs.push(Arc::clone(&access_log));
+        let state = ServerState::new(server_config, middlewares, tls, access_log);
+        running.push((server.clone(), Arc::clone(&state)));
         let handle = thread::spawn(move || {
-            start_server(server);
+            start_server(listener, state);
         });
         handles.push(handle);
     }
     
-    // 等待所有线程完成
+    if config.reload_interval_secs > 0 {
+        let interval = Duration::from_secs(config.reload_interval_secs);
+        thread::spawn(move || watch_config(config_path, running, interval));
+    }
+    
+    // 接受线程只在收到关闭信号后退出
     for handle in handles {
         handle.join().unwrap();
     }
+    
+    let remaining = drain_connections(Duration::from_secs(config.shutdown_timeout_secs));
+    if remaining > 0 {
+        write_log_line(&format!("[{}] 等待超时，仍有 {} 个连接未完成", log_timestamp(), remaining), LogLevel::Warn);
+    }
+    for access_log in &access_logs {
+        access_log.flush();
+    }
+    flush_log();
 }
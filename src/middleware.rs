@@ -0,0 +1,74 @@
+use crate::response::HttpResponse;
+use crate::{log_access, RequestTiming};
+
+/// 中间件看到的请求信息
+pub struct RequestContext<'a> {
+    pub client_addr: &'a str,
+    pub path: &'a str,
+}
+
+/// 中间件看到的响应信息
+pub struct ResponseContext<'a> {
+    pub status_code: u16,
+    // 本地生成、尚未发送的响应，可以修改；后端返回或已经直接写出的响应为None
+    pub local: Option<&'a mut HttpResponse>,
+    pub timing: &'a RequestTiming,
+}
+
+/// 请求处理管道中的中间件
+///
+/// before_request按配置顺序调用，after_response按相反顺序调用，
+/// 因此排在前面的中间件包在外层
+pub trait Middleware: Send + Sync {
+    /// 处理请求之前调用，返回响应时不再处理请求，直接使用该响应
+    fn before_request(&self, _request: &RequestContext) -> Option<HttpResponse> {
+        None
+    }
+
+    /// 响应生成之后、发送之前调用
+    fn after_response(&self, _request: &RequestContext, _response: &mut ResponseContext) {}
+
+    /// 响应发送完之后调用，与after_response的顺序相同，此时local总是None
+    fn after_send(&self, _request: &RequestContext, _response: &ResponseContext) {}
+}
+
+/// 记录访问日志，在响应发送完之后记录，以便包含发送的字节数和发送耗时
+struct AccessLog;
+
+impl Middleware for AccessLog {
+    fn after_send(&self, request: &RequestContext, response: &ResponseContext) {
+        log_access(request.client_addr, request.path, response.status_code, response.timing);
+    }
+}
+
+/// 为本地生成的成功响应添加Server头，值为None时不添加
+struct ServerHeader {
+    value: Option<String>,
+}
+
+impl Middleware for ServerHeader {
+    fn after_response(&self, _request: &RequestContext, response: &mut ResponseContext) {
+        if response.status_code < 400
+            && let Some(value) = &self.value
+            && let Some(local) = response.local.as_deref_mut()
+        {
+            local.set_header("Server", value);
+        }
+    }
+}
+
+/// 默认启用的中间件及顺序
+pub fn default_middlewares() -> Vec<String> {
+    vec![String::from("logging"), String::from("server_header")]
+}
+
+/// 按配置的名称和顺序创建中间件，server_header为本地响应使用的Server头
+pub fn build(names: &[String], server_header: Option<String>) -> Result<Vec<Box<dyn Middleware>>, String> {
+    names.iter()
+        .map(|name| match name.as_str() {
+            "logging" => Ok(Box::new(AccessLog) as Box<dyn Middleware>),
+            "server_header" => Ok(Box::new(ServerHeader { value: server_header.clone() }) as Box<dyn Middleware>),
+            _ => Err(format!("未知的中间件: {}", name)),
+        })
+        .collect()
+}
@@ -0,0 +1,42 @@
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// 固定数量的工作线程，任务通过有界队列分发
+///
+/// 队列满时execute会阻塞调用方，接受线程因此暂停接受新连接，积压留在内核的监听队列中
+pub struct ThreadPool {
+    sender: SyncSender<Job>,
+}
+
+impl ThreadPool {
+    /// 启动size个工作线程，队列中最多有queue_capacity个等待处理的任务
+    pub fn new(size: usize, queue_capacity: usize) -> ThreadPool {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_capacity);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..size.max(1) {
+            let receiver = Arc::clone(&receiver);
+            thread::spawn(move || run_worker(&receiver));
+        }
+        ThreadPool { sender }
+    }
+
+    /// 把任务放入队列，队列已满时等待空位；工作线程已全部退出时返回false
+    pub fn execute<F: FnOnce() + Send + 'static>(&self, job: F) -> bool {
+        self.sender.send(Box::new(job)).is_ok()
+    }
+}
+
+/// 工作线程依次取出任务执行，队列关闭后退出
+fn run_worker(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        // 取到任务后立即释放锁，执行期间其他线程可以继续取任务
+        let job = receiver.lock().unwrap().recv();
+        match job {
+            Ok(job) => job(),
+            Err(_) => break,
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// 记录的客户端超过该数量时立即清理已经恢复满额的记录
+const MAX_TRACKED_CLIENTS: usize = 10000;
+
+/// 两次定期清理之间的间隔，恢复满额的记录与没有记录等价，清理后不占内存
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// 一个客户端的令牌桶
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// 某个路径前缀下按客户端IP限流，使用令牌桶：每个请求消耗一个令牌，令牌按固定速率恢复，最多攒到burst个
+pub struct RateLimiter {
+    prefix: String,
+    // 每秒恢复的令牌数
+    rate: f64,
+    capacity: f64,
+    buckets: Mutex<Buckets>,
+}
+
+struct Buckets {
+    by_client: HashMap<IpAddr, Bucket>,
+    last_cleanup: Instant,
+}
+
+impl RateLimiter {
+    /// 每个客户端每period最多requests个请求，允许一次性突发burst个
+    pub fn new(prefix: &str, requests: u32, period: Duration, burst: u32) -> RateLimiter {
+        RateLimiter {
+            prefix: prefix.to_string(),
+            rate: requests as f64 / period.as_secs_f64(),
+            capacity: burst as f64,
+            buckets: Mutex::new(Buckets { by_client: HashMap::new(), last_cleanup: Instant::now() }),
+        }
+    }
+
+    /// 请求路径（不含查询参数）是否属于该限流规则
+    pub fn matches(&self, path: &str) -> bool {
+        path.split('?').next().unwrap_or("").starts_with(&self.prefix)
+    }
+
+    /// 为一个请求消耗令牌，超过限制时返回需要等待的秒数，用作Retry-After
+    pub fn check(&self, client: IpAddr) -> Result<(), u64> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.by_client.len() >= MAX_TRACKED_CLIENTS || now.duration_since(buckets.last_cleanup) >= CLEANUP_INTERVAL {
+            buckets.by_client.retain(|_, bucket| self.refilled(bucket, now) < self.capacity);
+            buckets.last_cleanup = now;
+        }
+        let bucket = buckets.by_client.entry(client).or_insert(Bucket { tokens: self.capacity, updated: now });
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / self.rate).ceil().max(1.0) as u64)
+        }
+    }
+
+    /// 按经过的时间恢复后的令牌数
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.capacity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn burst_is_allowed_then_requests_are_limited() {
+        let limiter = RateLimiter::new("/", 1, Duration::from_secs(10), 3);
+        for _ in 0..3 {
+            assert_eq!(limiter.check(ip("192.0.2.1")), Ok(()));
+        }
+        // 每10秒恢复一个令牌
+        assert_eq!(limiter.check(ip("192.0.2.1")), Err(10));
+    }
+
+    #[test]
+    fn clients_are_counted_separately() {
+        let limiter = RateLimiter::new("/", 1, Duration::from_secs(60), 1);
+        assert_eq!(limiter.check(ip("192.0.2.1")), Ok(()));
+        assert!(limiter.check(ip("192.0.2.1")).is_err());
+        assert_eq!(limiter.check(ip("2001:db8::1")), Ok(()));
+    }
+
+    #[test]
+    fn tokens_are_refilled_over_time() {
+        let limiter = RateLimiter::new("/", 1, Duration::from_secs(60), 2);
+        let now = Instant::now();
+        let bucket = Bucket { tokens: 0.5, updated: now - Duration::from_secs(30) };
+        assert_eq!(limiter.refilled(&bucket, now), 1.0);
+        // 最多恢复到burst个
+        let bucket = Bucket { tokens: 0.0, updated: now - Duration::from_secs(600) };
+        assert_eq!(limiter.refilled(&bucket, now), 2.0);
+    }
+
+    #[test]
+    fn prefix_matching_ignores_query() {
+        let limiter = RateLimiter::new("/login", 1, Duration::from_secs(1), 1);
+        assert!(limiter.matches("/login?next=/"));
+        assert!(limiter.matches("/login/reset"));
+        assert!(!limiter.matches("/static/login"));
+        assert!(!limiter.matches("/?x=/login"));
+    }
+}
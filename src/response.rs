@@ -0,0 +1,224 @@
+use serde::Deserialize;
+
+/// 内置错误响应的正文格式
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorFormat {
+    Html,
+    Json,
+    #[default]
+    Text,
+}
+
+/// HTTP响应构建器
+///
+/// 输出时先写Content-Length和Content-Type，其余头部严格按照插入顺序排列，
+/// 保证同样的响应每次生成的字节完全一致
+pub struct HttpResponse {
+    status: u16,
+    content_type: Option<String>,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    // 内置错误响应，正文在生成时按error_format渲染
+    is_error: bool,
+    detail: Option<String>,
+    error_format: ErrorFormat,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16) -> HttpResponse {
+        HttpResponse {
+            status,
+            content_type: None,
+            headers: Vec::new(),
+            body: Vec::new(),
+            is_error: false,
+            detail: None,
+            error_format: ErrorFormat::default(),
+        }
+    }
+
+    /// 内置错误响应，正文为状态码和原因短语
+    pub fn error(status: u16) -> HttpResponse {
+        let mut response = HttpResponse::new(status);
+        response.is_error = true;
+        response
+    }
+
+    /// 错误响应的附加说明
+    pub fn detail(mut self, detail: &str) -> HttpResponse {
+        self.detail = Some(detail.to_string());
+        self
+    }
+
+    /// 设置内置错误响应使用的格式，对普通响应没有影响
+    pub fn error_format(mut self, error_format: ErrorFormat) -> HttpResponse {
+        self.error_format = error_format;
+        self
+    }
+
+    pub fn content_type(mut self, content_type: &str) -> HttpResponse {
+        self.content_type = Some(content_type.to_string());
+        self
+    }
+
+    /// 设置头部，同名头部（不区分大小写）在原位置替换
+    pub fn header(mut self, name: &str, value: &str) -> HttpResponse {
+        self.set_header(name, value);
+        self
+    }
+
+    /// 与header相同，用于修改已经生成的响应
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        match self.headers.iter_mut().find(|(existing, _)| existing.eq_ignore_ascii_case(name)) {
+            Some(header) => header.1 = value.to_string(),
+            None => self.headers.push((name.to_string(), value.to_string())),
+        }
+    }
+
+    /// 已设置的头部值，名称不区分大小写
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(existing, _)| existing.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// 是否为内置错误响应
+    pub fn is_error(&self) -> bool {
+        self.is_error
+    }
+
+    /// 用自定义页面代替内置错误响应的正文，保留状态码和已设置的头部
+    pub fn page(mut self, content_type: &str, body: impl Into<Vec<u8>>) -> HttpResponse {
+        self.is_error = false;
+        self.content_type = Some(content_type.to_string());
+        self.body = body.into();
+        self
+    }
+
+    /// 响应体按原始字节发送，不要求是UTF-8
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> HttpResponse {
+        self.body = body.into();
+        self
+    }
+
+    /// 按错误格式生成错误响应的Content-Type和正文
+    fn render_error(&self) -> (&'static str, String) {
+        let title = format!("{} {}", self.status, status_reason(self.status));
+        match self.error_format {
+            ErrorFormat::Text => {
+                let body = match &self.detail {
+                    Some(detail) => format!("{}: {}", title, detail),
+                    None => title,
+                };
+                ("text/plain; charset=utf-8", body)
+            }
+            ErrorFormat::Json => {
+                let message = match &self.detail {
+                    Some(detail) => format!("{}: {}", status_reason(self.status), detail),
+                    None => status_reason(self.status).to_string(),
+                };
+                let body = serde_json::json!({ "error": message, "status": self.status }).to_string();
+                ("application/json", body)
+            }
+            ErrorFormat::Html => {
+                let detail = match &self.detail {
+                    Some(detail) => format!("<p>{}</p>", html_escape(detail)),
+                    None => String::new(),
+                };
+                let body = format!(
+                    "<!DOCTYPE html>\n<html>\n<head><meta charset=\"UTF-8\"><title>{0}</title></head>\n<body>\n<h1>{0}</h1>\n{1}<hr><p>nextWeb/{2}</p>\n</body>\n</html>\n",
+                    title, detail, env!("CARGO_PKG_VERSION")
+                );
+                ("text/html; charset=utf-8", body)
+            }
+        }
+    }
+
+    /// 生成完整的响应报文
+    pub fn build(&self) -> Vec<u8> {
+        let (content_type, body) = if self.is_error {
+            let (content_type, body) = self.render_error();
+            (Some(content_type), body.into_bytes())
+        } else {
+            (self.content_type.as_deref(), self.body.clone())
+        };
+        
+        let mut response = self.head(content_type, body.len() as u64).into_bytes();
+        response.extend_from_slice(&body);
+        response
+    }
+
+    /// 只生成响应头，正文由调用方另外发送，例如从文件分块读取
+    pub fn build_head(&self, content_length: u64) -> Vec<u8> {
+        self.head(self.content_type.as_deref(), content_length).into_bytes()
+    }
+
+    fn head(&self, content_type: Option<&str>, content_length: u64) -> String {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, status_reason(self.status));
+        // 304没有正文，不发送Content-Length，避免与缓存中完整响应的长度冲突
+        if self.status != 304 {
+            head.push_str(&format!("Content-Length: {}\r\n", content_length));
+        }
+        if let Some(content_type) = content_type {
+            head.push_str(&format!("Content-Type: {}\r\n", content_type));
+        }
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        head
+    }
+}
+
+/// 转义HTML特殊字符
+pub fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// 状态码对应的原因短语
+pub fn status_reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        416 => "Range Not Satisfiable",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        508 => "Loop Detected",
+        _ => "Unknown",
+    }
+}
@@ -0,0 +1,36 @@
+/// 头部值的匹配模式，*匹配任意长度的字符（包括空），其余字符按原样比较，区分大小写
+///
+/// 加载配置时按*拆分一次，请求时只做子串查找
+#[derive(Clone, Debug, Default)]
+pub struct HeaderPattern {
+    // 按*拆开的各段，至少有一段
+    parts: Vec<String>,
+}
+
+impl HeaderPattern {
+    pub fn compile(pattern: &str) -> HeaderPattern {
+        HeaderPattern { parts: pattern.split('*').map(str::to_string).collect() }
+    }
+
+    pub fn matches(&self, value: &str) -> bool {
+        let (first, rest) = match self.parts.split_first() {
+            Some(split) => split,
+            None => return value.is_empty(),
+        };
+        // 没有*时要求完全相同
+        let Some((last, middle)) = rest.split_last() else {
+            return value == first;
+        };
+        let Some(mut remaining) = value.strip_prefix(first.as_str()) else {
+            return false;
+        };
+        // 中间各段依次取最早的出现位置，给后面的段留下尽量多的字符
+        for part in middle {
+            match remaining.find(part.as_str()) {
+                Some(position) => remaining = &remaining[position + part.len()..],
+                None => return false,
+            }
+        }
+        remaining.ends_with(last.as_str())
+    }
+}
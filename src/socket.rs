@@ -0,0 +1,239 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use socket2::SockRef;
+
+/// 后端或监听的地址：TCP地址或Unix domain socket的路径
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SocketAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl SocketAddress {
+    /// 连接该地址；Unix socket是本机连接，没有监听时立即失败，不使用timeout
+    pub fn connect(&self, timeout: Duration) -> io::Result<Socket> {
+        match self {
+            SocketAddress::Tcp(addr) => TcpStream::connect_timeout(addr, timeout).map(Socket::Tcp),
+            SocketAddress::Unix(path) => UnixStream::connect(path).map(Socket::Unix),
+        }
+    }
+}
+
+impl fmt::Display for SocketAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SocketAddress::Tcp(addr) => write!(f, "{}", addr),
+            SocketAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// 客户端的地址；Unix domain socket的客户端没有IP地址，按IP的访问控制、限流和X-Forwarded-For都不适用
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientAddr {
+    Ip(SocketAddr),
+    Unix,
+}
+
+impl ClientAddr {
+    /// TCP客户端的IP地址，Unix socket的客户端返回None
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            ClientAddr::Ip(addr) => Some(addr.ip()),
+            ClientAddr::Unix => None,
+        }
+    }
+}
+
+impl fmt::Display for ClientAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientAddr::Ip(addr) => write!(f, "{}", addr),
+            ClientAddr::Unix => write!(f, "unix"),
+        }
+    }
+}
+
+/// 一条TCP或Unix domain socket连接，读写和超时等操作对两者相同
+pub enum Socket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Socket {
+    /// 对端地址，Unix socket的对端通常是未绑定路径的匿名socket，只区分出是Unix socket
+    pub fn peer_addr(&self) -> io::Result<ClientAddr> {
+        match self {
+            Socket::Tcp(stream) => stream.peer_addr().map(ClientAddr::Ip),
+            Socket::Unix(stream) => stream.peer_addr().map(|_| ClientAddr::Unix),
+        }
+    }
+
+    /// 读取已经到达的数据但不从接收缓冲区取走，之后的read仍会读到这些数据
+    pub fn peek(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.peek(buffer),
+            // UnixStream::peek还不稳定，直接使用MSG_PEEK
+            Socket::Unix(stream) => {
+                let received = unsafe { libc::recv(stream.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len(), libc::MSG_PEEK) };
+                usize::try_from(received).map_err(|_| io::Error::last_os_error())
+            }
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.set_read_timeout(timeout),
+            Socket::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.set_write_timeout(timeout),
+            Socket::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    /// 关闭Nagle算法，只对TCP连接有意义
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.set_nodelay(nodelay),
+            Socket::Unix(_) => Ok(()),
+        }
+    }
+
+    /// 设置SO_LINGER，只对TCP连接有意义
+    pub fn set_linger(&self, linger: Option<Duration>) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => SockRef::from(stream).set_linger(linger),
+            Socket::Unix(_) => Ok(()),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            Socket::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.shutdown(how),
+            Socket::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Socket> {
+        match self {
+            Socket::Tcp(stream) => stream.try_clone().map(Socket::Tcp),
+            Socket::Unix(stream) => stream.try_clone().map(Socket::Unix),
+        }
+    }
+}
+
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Socket::Tcp(stream) => stream.as_raw_fd(),
+            Socket::Unix(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.read(buffer),
+            Socket::Unix(stream) => stream.read(buffer),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.write(data),
+            Socket::Unix(stream) => stream.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.flush(),
+            Socket::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// 服务器的监听socket，TCP端口或Unix domain socket文件
+pub enum Listener {
+    Tcp(TcpListener),
+    // 监听的socket文件在Listener释放时删除
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    pub fn bind_tcp(address: &str) -> io::Result<Listener> {
+        TcpListener::bind(address).map(Listener::Tcp)
+    }
+
+    /// 绑定Unix domain socket；上次运行遗留的socket文件没有进程监听时先删除，仍在使用时返回AddrInUse
+    pub fn bind_unix(path: &Path) -> io::Result<Listener> {
+        if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(ErrorKind::AddrInUse, "socket文件正在被其他进程监听"));
+            }
+            fs::remove_file(path)?;
+        }
+        UnixListener::bind(path).map(|listener| Listener::Unix(listener, path.to_path_buf()))
+    }
+
+    pub fn accept(&self) -> io::Result<Socket> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Socket::Tcp(stream)),
+            Listener::Unix(listener, _) => listener.accept().map(|(stream, _)| Socket::Unix(stream)),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            Listener::Unix(listener, _) => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// 实际监听的地址，用于启动时的汇总
+    pub fn local_addr(&self) -> io::Result<SocketAddress> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(SocketAddress::Tcp),
+            Listener::Unix(_, path) => Ok(SocketAddress::Unix(path.clone())),
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener, _) => listener.as_raw_fd(),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
@@ -0,0 +1,38 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// 按修改时间轮询一组文件，用于发现配置文件的修改
+pub struct FileWatcher {
+    // 文件路径和上次看到的修改时间，文件不存在时为None
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+impl FileWatcher {
+    /// 记下各文件当前的修改时间，之后的修改才算变化
+    pub fn new(paths: impl IntoIterator<Item = PathBuf>) -> FileWatcher {
+        FileWatcher {
+            files: paths.into_iter().map(|path| {
+                let modified = modified_time(&path);
+                (path, modified)
+            }).collect(),
+        }
+    }
+
+    /// 自上次检查以来是否有文件被修改、创建或删除，同时记下新的修改时间
+    pub fn changed(&mut self) -> bool {
+        let mut changed = false;
+        for (path, seen) in &mut self.files {
+            let modified = modified_time(path);
+            if modified != *seen {
+                *seen = modified;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
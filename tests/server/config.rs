@@ -0,0 +1,128 @@
+use std::net::TcpListener;
+
+use crate::support::{get, run_to_exit, static_config, test_dir, write_file, TestServer};
+
+#[test]
+fn env_vars_in_config_are_substituted() {
+    let dir = test_dir("env_vars_in_config_are_substituted");
+    write_file(&dir, "www/index.html", "hello");
+    write_file(&dir, "server.toml", "[server]\naddress = \"127.0.0.1\"\nport = ${PORT}\n\
+        [type]\nname = \"static\"\n[static]\nwebroot = \"${WEBROOT}\"\nindex = \"index.html\"\n");
+    write_file(&dir, "config.toml", "[[servers]]\nname = \"test\"\nconfig = \"server.toml\"\n");
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let server = TestServer::spawn_with(&dir, |command| {
+        command.args(["--config", "config.toml"]).env("PORT", port.to_string()).env("WEBROOT", "www");
+    });
+
+    assert_eq!(server.address, format!("127.0.0.1:{}", port));
+    assert_eq!(get(&server.address, "/index.html").body, b"hello");
+}
+
+#[test]
+fn missing_env_var_fails_at_startup() {
+    let dir = test_dir("missing_env_var_fails_at_startup");
+    write_file(&dir, "server.toml", "[server]\naddress = \"127.0.0.1\"\nport = ${NEXTWEB_TEST_UNSET_PORT}\n\
+        [type]\nname = \"static\"\n[static]\nwebroot = \".\"\nindex = \"index.html\"\n");
+    write_file(&dir, "config.toml", "[[servers]]\nname = \"test\"\nconfig = \"server.toml\"\n");
+
+    let (success, output) = run_to_exit(&dir);
+    assert!(!success);
+    assert!(output.contains("环境变量 NEXTWEB_TEST_UNSET_PORT 未设置"), "{}", output);
+}
+
+#[test]
+fn config_path_is_taken_from_nextweb_config() {
+    let dir = test_dir("config_path_is_taken_from_nextweb_config");
+    write_file(&dir, "index.html", "hello");
+    write_file(&dir, "server.toml", static_config("", ""));
+    write_file(&dir, "deploy/main.toml", "[[servers]]\nname = \"test\"\nconfig = \"server.toml\"\n");
+    let server = TestServer::spawn_with(&dir, |command| {
+        command.env("NEXTWEB_CONFIG", "deploy/main.toml");
+    });
+
+    assert_eq!(get(&server.address, "/index.html").body, b"hello");
+}
+
+/// 监听指定地址和端口、以测试目录为webroot的静态服务器配置
+fn static_server(address: &str, port: u16) -> String {
+    format!("[server]\naddress = \"{}\"\nport = {}\n[type]\nname = \"static\"\n[static]\nwebroot = \".\"\nindex = \"index.html\"\n", address, port)
+}
+
+#[test]
+fn servers_sharing_a_port_are_reported_before_binding() {
+    let dir = test_dir("servers_sharing_a_port_are_reported_before_binding");
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    write_file(&dir, "a.toml", static_server("127.0.0.1", port));
+    write_file(&dir, "b.toml", static_server("0.0.0.0", port));
+    write_file(&dir, "config.toml", "[[servers]]\nname = \"a\"\nconfig = \"a.toml\"\n[[servers]]\nname = \"b\"\nconfig = \"b.toml\"\n");
+
+    let (success, output) = run_to_exit(&dir);
+    assert!(!success);
+    assert!(output.contains(&format!("服务器 'b' 的监听地址 0.0.0.0:{} 与服务器 'a' 的 127.0.0.1:{} 冲突", port, port)), "{}", output);
+    assert!(!output.contains("panicked"), "{}", output);
+}
+
+#[test]
+fn server_on_busy_port_is_skipped_and_others_start() {
+    let dir = test_dir("server_on_busy_port_is_skipped_and_others_start");
+    write_file(&dir, "index.html", "hello");
+    let busy = TcpListener::bind("127.0.0.1:0").unwrap();
+    write_file(&dir, "busy.toml", static_server("127.0.0.1", busy.local_addr().unwrap().port()));
+    write_file(&dir, "server.toml", static_config("", ""));
+    write_file(&dir, "config.toml", "[[servers]]\nname = \"busy\"\nconfig = \"busy.toml\"\n[[servers]]\nname = \"test\"\nconfig = \"server.toml\"\n");
+    let server = TestServer::spawn(&dir);
+
+    assert_eq!(get(&server.address, "/index.html").body, b"hello");
+    server.wait_for_line(|line| line.contains("服务器 'busy' 无法绑定") && line.contains("已跳过"));
+    server.wait_for_line(|line| line.starts_with("busy ") && line.contains("未启动（绑定失败）"));
+}
+
+#[test]
+fn invalid_log_timestamp_format_is_reported() {
+    let dir = test_dir("invalid_log_timestamp_format_is_reported");
+    write_file(&dir, "server.toml", static_config("", ""));
+    write_file(&dir, "config.toml", "log_timestamp_format = \"%Y-%\"\n[[servers]]\nname = \"test\"\nconfig = \"server.toml\"\n");
+
+    let (success, output) = run_to_exit(&dir);
+    assert!(!success);
+    assert!(output.contains("无效的日志时间格式: %Y-%"), "{}", output);
+    assert!(!output.contains("panicked"), "{}", output);
+}
+
+/// 每秒检查一次配置文件的主配置
+const RELOADING_CONFIG: &str = "reload_interval_secs = 1\n[[servers]]\nname = \"test\"\nconfig = \"server.toml\"\n";
+
+#[test]
+fn reloaded_config_takes_effect_without_restart() {
+    let dir = test_dir("reloaded_config_takes_effect_without_restart");
+    write_file(&dir, "old/index.html", "old");
+    write_file(&dir, "new/index.html", "new");
+    let server_config = |webroot: &str, extra: &str| format!("[server]\naddress = \"127.0.0.1\"\nport = 0\n\
+        [type]\nname = \"static\"\n[static]\nwebroot = \"{}\"\nindex = \"index.html\"\n{}", webroot, extra);
+    write_file(&dir, "server.toml", server_config("old", ""));
+    write_file(&dir, "config.toml", RELOADING_CONFIG);
+    let server = TestServer::spawn(&dir);
+    assert_eq!(get(&server.address, "/index.html").body, b"old");
+
+    // 新的webroot和限流规则不需要重启
+    write_file(&dir, "server.toml", server_config("new", "[rate_limit]\nrequests_per_second = 1\nburst = 1\n"));
+    server.wait_for_line(|line| line.contains("配置已重新加载"));
+    assert_eq!(get(&server.address, "/index.html").body, b"new");
+    assert_eq!(get(&server.address, "/index.html").status, 429);
+}
+
+#[test]
+fn reload_clears_cached_mime_sidecars() {
+    let dir = test_dir("reload_clears_cached_mime_sidecars");
+    write_file(&dir, "data.bin", "hello");
+    write_file(&dir, "data.bin.mime", "text/plain");
+    write_file(&dir, "server.toml", static_config("", ""));
+    write_file(&dir, "config.toml", RELOADING_CONFIG);
+    let server = TestServer::spawn(&dir);
+    assert_eq!(get(&server.address, "/data.bin").header("Content-Type"), Some("text/plain"));
+
+    write_file(&dir, "data.bin.mime", "application/x-changed");
+    write_file(&dir, "server.toml", static_config("connection_log = false", ""));
+    server.wait_for_line(|line| line.contains("配置已重新加载"));
+    assert_eq!(get(&server.address, "/data.bin").header("Content-Type"), Some("application/x-changed"));
+}
@@ -0,0 +1,100 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::DateTime;
+use serde_json::Value;
+
+use crate::support::{connect, get, send, static_config, test_dir, write_file, TestServer};
+
+#[test]
+fn redirect_logs_status_301() {
+    let dir = test_dir("redirect_logs_status_301");
+    let server = TestServer::start(&dir, "[server]\naddress = \"127.0.0.1\"\nport = 0\n\
+        [type]\nname = \"redirect\"\n[redirect]\ntarget = \"https://example.com{path}\"\n");
+
+    let response = get(&server.address, "/foo");
+    assert_eq!(response.status, 301);
+    assert_eq!(response.header("Location"), Some("https://example.com/foo"));
+    let line = server.wait_for_line(|line| line.contains(" - /foo - "));
+    assert!(line.contains(" - /foo - 301 - "), "{}", line);
+}
+
+#[test]
+fn common_log_records_bytes_sent() {
+    let dir = test_dir("common_log_records_bytes_sent");
+    write_file(&dir, "www/index.html", "<h1>hello</h1>\n");
+    let server = TestServer::start(&dir, "[server]\naddress = \"127.0.0.1\"\nport = 0\n\
+        [type]\nname = \"static\"\n[static]\nwebroot = \"www\"\nindex = \"index.html\"\n[log]\nformat = \"common\"\n");
+
+    let mut stream = connect(&server.address);
+    stream.write_all(b"GET /index.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    let line = server.wait_for_line(|line| line.contains("\"GET /index.html HTTP/1.1\""));
+    assert!(line.ends_with(&format!("\" 200 {}", response.len())), "{}", line);
+}
+
+/// 等待日志文件中至少有count行
+fn wait_for_log_lines(path: &Path, count: usize) -> Vec<String> {
+    let started = Instant::now();
+    loop {
+        let lines: Vec<String> = fs::read_to_string(path).unwrap_or_default().lines().map(str::to_string).collect();
+        if lines.len() >= count {
+            return lines;
+        }
+        assert!(started.elapsed() < Duration::from_secs(10), "日志文件只有 {} 行", lines.len());
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn access_log_file_uses_common_log_format() {
+    let dir = test_dir("access_log_file_uses_common_log_format");
+    write_file(&dir, "index.html", "hello");
+    let server = TestServer::start(&dir, &static_config("", "[log]\nfile = \"access.log\"\nformat = \"common\""));
+
+    assert_eq!(get(&server.address, "/index.html").status, 200);
+    assert_eq!(get(&server.address, "/missing.html").status, 404);
+    assert_eq!(get(&server.address, "/index.html?x=1").status, 200);
+
+    let lines = wait_for_log_lines(&dir.join("access.log"), 3);
+    let expected = [("/index.html", 200), ("/missing.html", 404), ("/index.html?x=1", 200)];
+    for (line, (target, status)) in lines.iter().zip(expected) {
+        // 127.0.0.1 - - [10/Oct/2024:13:55:36 +0000] "GET /index.html HTTP/1.1" 200 123
+        let (prefix, rest) = line.split_once(" [").unwrap();
+        assert_eq!(prefix, "127.0.0.1 - -", "{}", line);
+        let (timestamp, rest) = rest.split_once("] ").unwrap();
+        assert!(DateTime::parse_from_str(timestamp, "%d/%b/%Y:%H:%M:%S %z").is_ok(), "{}", line);
+        let fields: Vec<&str> = rest.rsplitn(3, ' ').collect();
+        assert_eq!(fields[2], format!("\"GET {} HTTP/1.1\"", target), "{}", line);
+        assert_eq!(fields[1], status.to_string(), "{}", line);
+        assert!(fields[0].parse::<u64>().is_ok(), "{}", line);
+    }
+}
+
+#[test]
+fn json_log_line_has_escaped_request_fields() {
+    let dir = test_dir("json_log_line_has_escaped_request_fields");
+    write_file(&dir, "index.html", "hello");
+    let server = TestServer::start(&dir, &static_config("", "[log]\nformat = \"json\""));
+
+    let response = get(&server.address, "/index.html");
+    send(&server.address, "GET /say\"hi\"\\.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    let line = server.wait_for_line(|line| line.starts_with('{') && line.contains("index.html"));
+    let record: Value = serde_json::from_str(&line).unwrap();
+    assert!(DateTime::parse_from_rfc3339(record["timestamp"].as_str().unwrap()).is_ok(), "{}", line);
+    assert_eq!(record["client_ip"], "127.0.0.1");
+    assert_eq!(record["method"], "GET");
+    assert_eq!(record["path"], "/index.html");
+    assert_eq!(record["status"], 200);
+    assert!(record["bytes_sent"].as_u64().unwrap() > response.body.len() as u64, "{}", line);
+    assert!(record["duration_ms"].is_u64(), "{}", line);
+
+    // 路径中的引号和反斜杠经过转义，整行仍是有效的JSON
+    let line = server.wait_for_line(|line| line.starts_with('{') && line.contains("say"));
+    let record: Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(record["path"], "/say\"hi\"\\.html");
+}
@@ -0,0 +1,11 @@
+//! 启动nextWeb进程，通过socket检查服务器的行为
+
+mod config;
+mod logging;
+mod proxy;
+mod requests;
+mod static_files;
+mod support;
+mod timeouts;
+mod tls;
+mod unix_socket;
@@ -0,0 +1,522 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+use crate::support::{backend, connect, get, proxy_config, proxy_server_config, read_request, read_response, send, test_dir, TestServer};
+
+#[test]
+fn truncated_buffered_response_gets_502() {
+    let dir = test_dir("truncated_buffered_response_gets_502");
+    let backend = backend(|mut stream| {
+        read_request(&mut stream);
+        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\nshort");
+    });
+    let server = TestServer::start(&dir, &proxy_config(&[&backend], "proxy_buffering = \"full\""));
+
+    let response = get(&server.address, "/");
+    assert_eq!(response.status, 502);
+    assert_eq!(response.header("Connection"), Some("close"));
+}
+
+#[test]
+fn redirect_to_configured_backend_is_followed() {
+    let dir = test_dir("redirect_to_configured_backend_is_followed");
+    let target = backend(|mut stream| {
+        read_request(&mut stream);
+        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\ntarget");
+    });
+    let location = format!("http://{}/moved", target);
+    let first = backend(move |mut stream| {
+        read_request(&mut stream);
+        let _ = write!(stream, "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\n\r\n", location);
+    });
+    let extra = format!("follow_redirects = true\nrewrite_location = false\nbackup_backends = [\"http://{}\"]", target);
+    let server = TestServer::start(&dir, &proxy_config(&[&first], &extra));
+
+    let response = get(&server.address, "/");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, b"target");
+}
+
+#[test]
+fn redirect_to_other_host_is_passed_to_client() {
+    let dir = test_dir("redirect_to_other_host_is_passed_to_client");
+    let requested = Arc::new(AtomicBool::new(false));
+    let other = backend({
+        let requested = Arc::clone(&requested);
+        move |mut stream| {
+            requested.store(true, Ordering::SeqCst);
+            read_request(&mut stream);
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\ninside");
+        }
+    });
+    let location = format!("http://{}/internal", other);
+    let first = backend(move |mut stream| {
+        read_request(&mut stream);
+        let _ = write!(stream, "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\n\r\n", location);
+    });
+    let server = TestServer::start(&dir, &proxy_config(&[&first], "follow_redirects = true\nrewrite_location = false"));
+
+    let response = get(&server.address, "/");
+    assert_eq!(response.status, 302);
+    assert_eq!(response.header("Location"), Some(format!("http://{}/internal", other).as_str()));
+    assert!(!requested.load(Ordering::SeqCst));
+}
+
+#[test]
+fn connect_without_tunnel_lists_allowed_methods() {
+    let dir = test_dir("connect_without_tunnel_lists_allowed_methods");
+    let backend = backend(|_| {});
+    let server = TestServer::start(&dir, &proxy_config(&[&backend], "allowed_methods = [\"GET\", \"POST\"]"));
+
+    let response = send(&server.address, "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n");
+    assert_eq!(response.status, 405);
+    assert_eq!(response.header("Allow"), Some("GET, POST"));
+}
+
+/// 每个字节为位置对256取模的响应体，截断或错位时内容不同
+fn patterned_body(length: usize) -> Vec<u8> {
+    (0..length).map(|index| index as u8).collect()
+}
+
+#[test]
+fn slow_client_receives_complete_large_response() {
+    let dir = test_dir("slow_client_receives_complete_large_response");
+    const LENGTH: usize = 8 * 1024 * 1024;
+    let backend = backend(|mut stream| {
+        read_request(&mut stream);
+        let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", LENGTH);
+        let _ = stream.write_all(&patterned_body(LENGTH));
+    });
+    let server = TestServer::start(&dir, &proxy_config(&[&backend], "proxy_buffering = \"full\""));
+
+    // 客户端先不读取，服务器写满发送缓冲区后只能部分写入
+    let mut stream = connect(&server.address);
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    thread::sleep(Duration::from_millis(500));
+    let response = read_response(&mut BufReader::new(stream));
+    assert_eq!(response.status, 200);
+    assert!(response.body == patterned_body(LENGTH), "响应体长度 {}", response.body.len());
+}
+
+#[test]
+fn event_stream_is_forwarded_before_backend_finishes() {
+    let dir = test_dir("event_stream_is_forwarded_before_backend_finishes");
+    let backend = backend(|mut stream| {
+        read_request(&mut stream);
+        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\r\ndata: one\n\n");
+        thread::sleep(Duration::from_secs(3));
+        let _ = stream.write_all(b"data: two\n\n");
+    });
+    // 即使配置了完整缓冲，事件流也要边收边转发
+    let server = TestServer::start(&dir, &proxy_config(&[&backend], "proxy_buffering = \"full\""));
+
+    let mut stream = connect(&server.address);
+    stream.write_all(b"GET /events HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let started = Instant::now();
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    while line.trim_end() != "data: one" {
+        line.clear();
+        assert!(reader.read_line(&mut line).unwrap() > 0, "连接在第一个事件之前关闭");
+    }
+    assert!(started.elapsed() < Duration::from_secs(2), "用时 {:?}", started.elapsed());
+}
+
+#[test]
+fn body_without_final_newline_is_forwarded_exactly() {
+    let dir = test_dir("body_without_final_newline_is_forwarded_exactly");
+    const BODY: &[u8] = b"first line\r\n\r\nServer: not a header\r\nlast line";
+    let backend = backend(|mut stream| {
+        read_request(&mut stream);
+        let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", BODY.len());
+        let _ = stream.write_all(BODY);
+    });
+    let server = TestServer::start(&dir, &proxy_config(&[&backend], ""));
+
+    assert_eq!(get(&server.address, "/").body, BODY);
+}
+
+#[test]
+fn large_binary_response_is_forwarded_unchanged() {
+    let dir = test_dir("large_binary_response_is_forwarded_unchanged");
+    // 响应体远超8192字节，其中还有像Server头的文本，改写Server头时不能碰到
+    let mut body = patterned_body(20000);
+    body.extend_from_slice(b"\r\nServer: backend\r\n");
+    let expected = body.clone();
+    let backend = backend(move |mut stream| {
+        read_request(&mut stream);
+        let _ = write!(stream, "HTTP/1.1 200 OK\r\nServer: backend\r\nContent-Length: {}\r\n\r\n", body.len());
+        let _ = stream.write_all(&body);
+    });
+    for buffering in ["full", "stream"] {
+        let server = TestServer::start(&dir, &format!("[server]\naddress = \"127.0.0.1\"\nport = 0\n[type]\nname = \"proxy\"\n\
+            [proxy]\nbackend = \"http://{}\"\nmodify_host = false\nheader_host = \"\"\nmodify_server = true\nproxy_buffering = \"{}\"\n", backend, buffering));
+
+        let response = get(&server.address, "/");
+        assert_eq!(response.status, 200);
+        assert_ne!(response.header("Server"), Some("backend"), "{}", buffering);
+        assert!(response.body == expected, "{}: 响应体长度 {}", buffering, response.body.len());
+    }
+}
+
+#[test]
+fn backend_closing_inside_headers_gets_502() {
+    let dir = test_dir("backend_closing_inside_headers_gets_502");
+    let backend = backend(|mut stream| {
+        read_request(&mut stream);
+        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/ht");
+    });
+    let server = TestServer::start(&dir, &proxy_config(&[&backend], ""));
+
+    let response = get(&server.address, "/");
+    assert_eq!(response.status, 502);
+    assert!(!String::from_utf8_lossy(&response.body).contains("text/ht"));
+    server.wait_for_line(|line| line.contains("后端在响应头完整之前关闭连接"));
+}
+
+#[test]
+fn client_address_is_appended_to_x_forwarded_for() {
+    let dir = test_dir("client_address_is_appended_to_x_forwarded_for");
+    let backend = backend(|mut stream| {
+        let request = read_request(&mut stream);
+        let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", request.len(), request);
+    });
+    let server = TestServer::start(&dir, &proxy_config(&[&backend], ""));
+
+    let forwarded = |request: &str| String::from_utf8(send(&server.address, request).body).unwrap();
+    let direct = forwarded("GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n");
+    assert!(direct.contains("X-Forwarded-For: 127.0.0.1\r\n"), "{}", direct);
+    assert!(direct.contains("X-Forwarded-Proto: http\r\n"), "{}", direct);
+    assert!(direct.contains("X-Forwarded-Host: example.com\r\n"), "{}", direct);
+
+    let chained = forwarded("GET / HTTP/1.1\r\nHost: example.com\r\nX-Forwarded-For: 203.0.113.7\r\nConnection: close\r\n\r\n");
+    assert!(chained.contains("X-Forwarded-For: 203.0.113.7, 127.0.0.1\r\n"), "{}", chained);
+    assert_eq!(chained.matches("X-Forwarded-For").count(), 1, "{}", chained);
+}
+
+/// 统计收到的全部字节的模拟后端，读到请求体长度或连接关闭为止，响应体是收到的请求体字节数
+fn counting_upload_backend(body_length: usize) -> (String, Arc<AtomicUsize>) {
+    let received = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&received);
+    let address = backend(move |mut stream| {
+        let mut buffer = [0; 65536];
+        let mut request = Vec::new();
+        while let Ok(bytes_read @ 1..) = stream.read(&mut buffer) {
+            request.extend_from_slice(&buffer[..bytes_read]);
+            counter.store(request.len(), Ordering::SeqCst);
+            let head_end = request.windows(4).position(|window| window == b"\r\n\r\n");
+            if head_end.is_some_and(|head_end| request.len() - head_end - 4 >= body_length) {
+                break;
+            }
+        }
+        let body_length = request.windows(4).position(|window| window == b"\r\n\r\n")
+            .map_or(0, |head_end| request.len() - head_end - 4)
+            .to_string();
+        let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body_length.len(), body_length);
+    });
+    (address, received)
+}
+
+#[test]
+fn streamed_upload_reaches_backend_before_it_is_complete() {
+    let dir = test_dir("streamed_upload_reaches_backend_before_it_is_complete");
+    const LENGTH: usize = 32 * 1024 * 1024;
+    let (backend, received) = counting_upload_backend(LENGTH);
+    let server = TestServer::start(&dir, &proxy_config(&[&backend], "request_buffering = \"stream\"\nmax_body_size = 67108864"));
+
+    let mut stream = connect(&server.address);
+    write!(stream, "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", LENGTH).unwrap();
+    let body = patterned_body(LENGTH);
+    let (first, rest) = body.split_at(1024 * 1024);
+    stream.write_all(first).unwrap();
+    // 剩余部分还没有发送时后端已经在接收，full模式下这里会一直等待
+    let started = Instant::now();
+    while received.load(Ordering::SeqCst) == 0 {
+        assert!(started.elapsed() < Duration::from_secs(5), "后端没有收到请求");
+        thread::sleep(Duration::from_millis(20));
+    }
+    stream.write_all(rest).unwrap();
+    let response = read_response(&mut BufReader::new(stream));
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, LENGTH.to_string().as_bytes());
+}
+
+#[test]
+fn streamed_upload_falls_back_when_backend_refuses_connection() {
+    let dir = test_dir("streamed_upload_falls_back_when_backend_refuses_connection");
+    let (live, _) = counting_upload_backend(5);
+    let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let server = TestServer::start(&dir, &proxy_config(&[&dead, &live], "request_buffering = \"stream\""));
+
+    // 轮流分配，其中一次先连接到拒绝连接的后端，还没有读取请求体，可以换下一个后端
+    for _ in 0..2 {
+        let mut stream = connect(&server.address);
+        stream.write_all(b"POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nConnection: close\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        stream.write_all(b"hello").unwrap();
+        let response = read_response(&mut BufReader::new(stream));
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"5");
+    }
+    server.wait_for_line(|line| line.contains(&format!("后端 {} 触发 error，尝试下一个后端", dead)));
+}
+
+#[test]
+fn body_digest_is_verified_before_forwarding() {
+    let dir = test_dir("body_digest_is_verified_before_forwarding");
+    let (backend, _) = counting_upload_backend(5);
+    let upload = |content_md5: &str| format!("POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\
+        Content-MD5: {}\r\nConnection: close\r\n\r\nhello", content_md5);
+    let server = TestServer::start(&dir, &proxy_server_config("verify_body_digest = true", &[&backend], ""));
+    assert_eq!(send(&server.address, &upload("XUFAKrxLKna5cZ2REBfFkg==")).status, 200);
+    assert_eq!(send(&server.address, &upload("ZZFAKrxLKna5cZ2REBfFkg==")).status, 400);
+    drop(server);
+
+    // 流式转发时请求体在头部之后才到达，无法校验
+    let server = TestServer::start(&dir, &proxy_server_config("verify_body_digest = true", &[&backend], "request_buffering = \"stream\""));
+    let request = upload("XUFAKrxLKna5cZ2REBfFkg==");
+    let (head, body) = request.split_at(request.len() - 5);
+    let mut stream = connect(&server.address);
+    stream.write_all(head.as_bytes()).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    let _ = stream.write_all(body.as_bytes());
+    let response = read_response(&mut BufReader::new(stream));
+    assert_eq!(response.status, 400);
+    assert!(String::from_utf8_lossy(&response.body).contains("Streamed request body cannot be verified"));
+}
+
+#[test]
+fn streamed_upload_over_max_body_size_is_aborted() {
+    let dir = test_dir("streamed_upload_over_max_body_size_is_aborted");
+    let (backend, received) = counting_upload_backend(usize::MAX);
+    let server = TestServer::start(&dir, &proxy_config(&[&backend], "request_buffering = \"stream\"\nmax_body_size = 1048576"));
+
+    // chunked上传没有声明长度，只能按已转发的字节累计
+    let mut stream = connect(&server.address);
+    let mut writer = stream.try_clone().unwrap();
+    let uploader = thread::spawn(move || {
+        let _ = writer.write_all(b"POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n");
+        for _ in 0..64 {
+            let _ = write!(writer, "10000\r\n");
+            let _ = writer.write_all(&patterned_body(0x10000));
+            let _ = writer.write_all(b"\r\n");
+        }
+        let _ = writer.write_all(b"0\r\n\r\n");
+    });
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    if !response.is_empty() {
+        assert!(response.starts_with(b"HTTP/1.1 413"), "{}", String::from_utf8_lossy(&response));
+    }
+    server.wait_for_line(|line| line.contains("请求体超过 max_body_size，中断转发"));
+    assert!(received.load(Ordering::SeqCst) < 2 * 1024 * 1024, "后端收到了 {} 字节", received.load(Ordering::SeqCst));
+    uploader.join().unwrap();
+}
+
+#[test]
+fn non_http_backend_response_gets_502() {
+    let dir = test_dir("non_http_backend_response_gets_502");
+    for (index, reply) in ["Traceback (most recent call last):\r\n  File \"app.py\"\r\n\r\n", "HTTP/1.1 OK\r\nContent-Length: 0\r\n\r\n", "ICY 200 OK\r\n\r\n"].into_iter().enumerate() {
+        let backend = backend(move |mut stream| {
+            read_request(&mut stream);
+            let _ = stream.write_all(reply.as_bytes());
+        });
+        let server = TestServer::start(&dir.join(index.to_string()), &proxy_config(&[&backend], ""));
+
+        let response = get(&server.address, "/");
+        assert_eq!(response.status, 502, "{:?}", reply);
+        assert!(!String::from_utf8_lossy(&response.body).contains("Traceback"));
+        server.wait_for_line(|line| line.contains("后端返回的状态行无效") || line.contains("后端返回的不是HTTP响应"));
+    }
+}
+
+#[test]
+fn hostname_backend_is_resolved() {
+    let dir = test_dir("hostname_backend_is_resolved");
+    let backend = backend(|mut stream| {
+        read_request(&mut stream);
+        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok");
+    });
+    let port = backend.rsplit_once(':').unwrap().1;
+    let server = TestServer::start(&dir, &proxy_config(&[&format!("localhost:{}", port)], ""));
+
+    let response = get(&server.address, "/");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, b"ok");
+}
+
+#[test]
+fn unresolvable_backend_gets_502() {
+    let dir = test_dir("unresolvable_backend_gets_502");
+    let server = TestServer::start(&dir, &proxy_config(&["backend.invalid:8080"], ""));
+
+    assert_eq!(get(&server.address, "/").status, 502);
+}
+
+#[test]
+fn health_endpoint_reports_backend_reachability() {
+    let dir = test_dir("health_endpoint_reports_backend_reachability");
+    let live = backend(|mut stream| {
+        read_request(&mut stream);
+        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok");
+    });
+    // 绑定后立即关闭的端口，连接会被拒绝
+    let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let server = TestServer::start(&dir, &format!("health_path = \"/healthz\"\n{}", proxy_config(&[&live, &dead], "")));
+
+    // 轮流分配，两个后端都会被连接一次
+    for _ in 0..2 {
+        assert_eq!(get(&server.address, "/").status, 200);
+    }
+    let response = get(&server.address, "/healthz");
+    assert_eq!(response.status, 200);
+    let health: Value = serde_json::from_slice(&response.body).unwrap();
+    assert_eq!(health["server"], "test");
+    assert_eq!(health["type"], "proxy");
+    assert!(health["uptime_secs"].is_u64());
+    let backend = |address: &str| health["backends"].as_array().unwrap().iter()
+        .find(|backend| backend["address"] == address)
+        .unwrap_or_else(|| panic!("没有后端 {}: {}", address, health))
+        .clone();
+    assert_eq!(backend(&live)["reachable"], true);
+    assert_eq!(backend(&dead)["reachable"], false);
+    assert!(backend(&dead)["failures"].as_u64().unwrap() >= 1);
+}
+
+#[test]
+fn header_rewrites_do_not_touch_bodies() {
+    let dir = test_dir("header_rewrites_do_not_touch_bodies");
+    const BODY: &str = "first line\r\nServer: foo\r\nHost: backend.example\r\n\r\nlast";
+    // 后端把收到的请求放在Server: foo之后一起返回
+    let backend = backend(|mut stream| {
+        let request = read_request(&mut stream);
+        let body = format!("{}\n{}", BODY, request);
+        let _ = write!(stream, "HTTP/1.1 200 OK\r\nServer: foo\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+    });
+    let server = TestServer::start(&dir, &format!("[server]\naddress = \"127.0.0.1\"\nport = 0\n[type]\nname = \"proxy\"\n\
+        [proxy]\nbackend = \"http://{}\"\nmodify_host = true\nheader_host = \"internal.example\"\nmodify_server = true\n", backend));
+
+    let response = send(&server.address, &format!("POST / HTTP/1.1\r\nHost: public.example\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", BODY.len(), BODY));
+    assert_eq!(response.status, 200);
+    assert_ne!(response.header("Server"), Some("foo"));
+    let body = String::from_utf8(response.body).unwrap();
+    let (response_body, forwarded) = body.split_at(BODY.len());
+    assert_eq!(response_body, BODY);
+    assert!(forwarded.contains("\r\nHost: internal.example\r\n"), "{}", forwarded);
+    assert!(forwarded.ends_with(&format!("\r\n\r\n{}", BODY)), "{}", forwarded);
+}
+
+/// 响应体为name的模拟后端
+fn named_backend(name: &'static str) -> String {
+    backend(move |mut stream| {
+        read_request(&mut stream);
+        let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", name.len(), name);
+    })
+}
+
+#[test]
+fn round_robin_alternates_between_backends() {
+    let dir = test_dir("round_robin_alternates_between_backends");
+    let server = TestServer::start(&dir, &proxy_config(&[&named_backend("a"), &named_backend("b")], "balance = \"round_robin\""));
+
+    let bodies: Vec<Vec<u8>> = (0..6).map(|_| get(&server.address, "/").body).collect();
+    for pair in bodies.windows(2) {
+        assert_ne!(pair[0], pair[1], "{:?}", bodies);
+    }
+    assert!(bodies.iter().all(|body| body == b"a" || body == b"b"));
+}
+
+#[test]
+fn refused_backend_is_retried_on_the_next_one() {
+    let dir = test_dir("refused_backend_is_retried_on_the_next_one");
+    let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let server = TestServer::start(&dir, &proxy_config(&[&dead, &named_backend("a")], ""));
+
+    for _ in 0..4 {
+        assert_eq!(get(&server.address, "/").body, b"a");
+    }
+}
+
+#[test]
+fn failing_backend_is_ejected_until_cooldown_passes() {
+    let dir = test_dir("failing_backend_is_ejected_until_cooldown_passes");
+    let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let server = TestServer::start(&dir, &proxy_config(&[&named_backend("a"), &dead.to_string()], "max_fails = 1\nfail_timeout_secs = 2"));
+    let bodies = || (0..4).map(|_| get(&server.address, "/").body).collect::<Vec<_>>();
+
+    assert!(bodies().iter().all(|body| body == b"a"));
+    // 后端恢复后，摘除期间仍然不分配请求
+    let revived = TcpListener::bind(dead).unwrap();
+    thread::spawn(move || {
+        for mut stream in revived.incoming().map_while(Result::ok) {
+            read_request(&mut stream);
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\nConnection: close\r\n\r\nb");
+        }
+    });
+    assert!(bodies().iter().all(|body| body == b"a"));
+    thread::sleep(Duration::from_millis(2500));
+    assert!(bodies().iter().any(|body| body == b"b"));
+}
+
+#[test]
+fn configured_headers_are_added_and_removed() {
+    let dir = test_dir("configured_headers_are_added_and_removed");
+    let backend = backend(|mut stream| {
+        let request = read_request(&mut stream);
+        let _ = write!(stream, "HTTP/1.1 200 OK\r\nX-Frame-Options: SAMEORIGIN\r\nX-Debug-Token: abc\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", request.len(), request);
+    });
+    let server = TestServer::start(&dir, &proxy_config(&[&backend], "remove_response_headers = [\"x-debug-token\"]\n\
+        [proxy.add_request_headers]\nX-Environment = \"test\"\n[proxy.add_response_headers]\nX-Frame-Options = \"DENY\""));
+
+    let response = send(&server.address, "GET / HTTP/1.1\r\nHost: localhost\r\nx-environment: client\r\nConnection: close\r\n\r\n");
+    assert_eq!(response.status, 200);
+    let frame_options: Vec<&str> = response.headers.iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("X-Frame-Options"))
+        .map(|(_, value)| value.as_str())
+        .collect();
+    assert_eq!(frame_options, ["DENY"]);
+    assert_eq!(response.header("X-Debug-Token"), None);
+    // 配置的请求头替换客户端发来的同名头部
+    let forwarded = String::from_utf8(response.body).unwrap();
+    assert!(forwarded.contains("X-Environment: test\r\n"), "{}", forwarded);
+    assert!(!forwarded.contains("client"), "{}", forwarded);
+}
+
+#[test]
+fn websocket_upgrade_relays_frames_both_ways() {
+    let dir = test_dir("websocket_upgrade_relays_frames_both_ways");
+    // 回应101之后把收到的字节原样写回的后端
+    let backend = backend(|mut stream| {
+        let request = read_request(&mut stream);
+        assert!(request.contains("\r\nUpgrade: websocket\r\n"), "{}", request);
+        let _ = stream.write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n");
+        let mut writer = stream.try_clone().unwrap();
+        let _ = std::io::copy(&mut stream, &mut writer);
+    });
+    let server = TestServer::start(&dir, &proxy_config(&[&backend], ""));
+
+    let mut stream = connect(&server.address);
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    stream.write_all(b"GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+    let response = read_response(&mut reader);
+    assert_eq!(response.status, 101);
+    assert_eq!(response.header("Upgrade"), Some("websocket"));
+    assert_eq!(response.header("Sec-WebSocket-Accept"), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+    // 带掩码的文本帧"hello"，转发时不解析帧，后端回显原样的字节
+    let frame = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+    for _ in 0..2 {
+        stream.write_all(&frame).unwrap();
+        let mut echoed = [0; 11];
+        reader.read_exact(&mut echoed).unwrap();
+        assert_eq!(echoed, frame);
+    }
+}
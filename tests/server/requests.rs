@@ -0,0 +1,401 @@
+use std::io::{BufReader, Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::support::{backend, connect, get, proxy_config, proxy_server_config, read_request, read_response, send, static_config, test_dir, write_file, TestServer};
+
+/// 计数收到的请求并返回200的模拟后端
+fn counting_backend() -> (String, Arc<AtomicUsize>) {
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&requests);
+    let address = backend(move |mut stream| {
+        read_request(&mut stream);
+        counter.fetch_add(1, Ordering::SeqCst);
+        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok");
+    });
+    (address, requests)
+}
+
+#[test]
+fn duplicate_host_headers_get_400() {
+    let dir = test_dir("duplicate_host_headers_get_400");
+    let (backend, requests) = counting_backend();
+    let server = TestServer::start(&dir, &proxy_config(&[&backend], ""));
+
+    let response = send(&server.address, "GET / HTTP/1.1\r\nHost: a.example.com\r\nHost: b.example.com\r\nConnection: close\r\n\r\n");
+    assert_eq!(response.status, 400);
+    assert_eq!(requests.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn ambiguous_body_framing_gets_400() {
+    let dir = test_dir("ambiguous_body_framing_gets_400");
+    let (backend, requests) = counting_backend();
+    let server = TestServer::start(&dir, &proxy_config(&[&backend], ""));
+
+    let conflicting_lengths = "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\nContent-Length: 8\r\n\r\nabcGET / ";
+    assert_eq!(send(&server.address, conflicting_lengths).status, 400);
+    let length_and_chunked = "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+    assert_eq!(send(&server.address, length_and_chunked).status, 400);
+    assert_eq!(requests.load(Ordering::SeqCst), 0);
+
+    let repeated_length = "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\nContent-Length: 3\r\nConnection: close\r\n\r\nabc";
+    assert_eq!(send(&server.address, repeated_length).status, 200);
+}
+
+#[test]
+fn idle_keep_alive_connection_does_not_count_as_in_flight() {
+    let dir = test_dir("idle_keep_alive_connection_does_not_count_as_in_flight");
+    write_file(&dir, "index.html", "hello");
+    let server = TestServer::start(&dir, "max_concurrent_requests = 1\n[server]\naddress = \"127.0.0.1\"\nport = 0\nworker_threads = 2\n\
+        [type]\nname = \"static\"\n[static]\nwebroot = \".\"\nindex = \"index.html\"\n");
+
+    // 第一个连接处理完请求后保持空闲，不再占用处理中的请求数
+    let mut idle = connect(&server.address);
+    idle.write_all(b"GET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut idle = BufReader::new(idle);
+    assert_eq!(read_response(&mut idle).status, 200);
+    // 响应发出之后服务器才结束这个请求，稍等它释放
+    thread::sleep(Duration::from_millis(200));
+
+    assert_eq!(get(&server.address, "/index.html").status, 200);
+}
+
+/// 发送原始请求，读取连接关闭前收到的全部字节
+fn send_raw(address: &str, request: &str) -> Vec<u8> {
+    let mut stream = connect(address);
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).unwrap();
+    received
+}
+
+#[test]
+fn http09_request_is_rejected_by_default() {
+    let dir = test_dir("http09_request_is_rejected_by_default");
+    write_file(&dir, "index.html", "hello");
+    let server = TestServer::start(&dir, &static_config("", ""));
+
+    assert!(send_raw(&server.address, "GET /index.html\r\n").starts_with(b"HTTP/1.1 400 "));
+}
+
+#[test]
+fn http09_request_gets_bare_body_when_enabled() {
+    let dir = test_dir("http09_request_gets_bare_body_when_enabled");
+    write_file(&dir, "index.html", "hello");
+    let server = TestServer::start(&dir, &static_config("http09 = \"respond\"", ""));
+
+    assert_eq!(send_raw(&server.address, "GET /index.html\r\n"), b"hello");
+}
+
+#[test]
+fn overlong_header_value_gets_431() {
+    let dir = test_dir("overlong_header_value_gets_431");
+    write_file(&dir, "index.html", "hello");
+    let server = TestServer::start(&dir, &static_config("max_header_value_length = 16", ""));
+
+    let request = |value: &str| format!("GET /index.html HTTP/1.1\r\nHost: localhost\r\nX-Long: {}\r\nConnection: close\r\n\r\n", value);
+    assert_eq!(send(&server.address, &request(&"a".repeat(16))).status, 200);
+    assert_eq!(send(&server.address, &request(&"a".repeat(17))).status, 431);
+}
+
+/// 把收到的请求放在响应体中返回的模拟后端
+fn echo_request_backend() -> String {
+    backend(|mut stream| {
+        let request = read_request(&mut stream);
+        let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", request.len(), request);
+    })
+}
+
+/// 请求体之后紧跟着另一个请求，一次写出
+const REQUEST_WITH_TRAILING_DATA: &str = "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nConnection: close\r\n\r\n\
+    helloGET /smuggled HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+#[test]
+fn trailing_data_is_discarded_by_default() {
+    let dir = test_dir("trailing_data_is_discarded_by_default");
+    let server = TestServer::start(&dir, &proxy_config(&[&echo_request_backend()], ""));
+
+    let response = send(&server.address, REQUEST_WITH_TRAILING_DATA);
+    assert_eq!(response.status, 200);
+    let forwarded = String::from_utf8(response.body).unwrap();
+    assert!(forwarded.ends_with("\r\n\r\nhello"), "{}", forwarded);
+}
+
+#[test]
+fn trailing_data_is_rejected_when_configured() {
+    let dir = test_dir("trailing_data_is_rejected_when_configured");
+    // 检查在分发到站点之前进行，静态服务器也一样
+    let server = TestServer::start(&dir, &static_config("trailing_data = \"reject\"", ""));
+
+    assert_eq!(send(&server.address, REQUEST_WITH_TRAILING_DATA).status, 400);
+}
+
+#[test]
+fn pipelined_request_is_not_trailing_data() {
+    let dir = test_dir("pipelined_request_is_not_trailing_data");
+    write_file(&dir, "a.html", "first");
+    write_file(&dir, "b.html", "second");
+    let server = TestServer::start(&dir, &static_config("trailing_data = \"reject\"", ""));
+
+    // 保持的连接上紧跟着的有效请求照常处理
+    let mut stream = connect(&server.address);
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    stream.write_all(b"GET /a.html HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n\
+        GET /b.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    assert_eq!(read_response(&mut reader).body, b"first");
+    assert_eq!(read_response(&mut reader).body, b"second");
+
+    // 不是请求行的数据仍然拒绝
+    let garbage = "GET /a.html HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\nnot a request\r\n\r\n";
+    assert_eq!(send(&server.address, garbage).status, 400);
+}
+
+#[test]
+fn large_request_is_read_completely() {
+    let dir = test_dir("large_request_is_read_completely");
+    let server = TestServer::start(&dir, &proxy_server_config("max_header_size = 16384", &[&echo_request_backend()], ""));
+
+    // 头部远超1024字节并分两次到达，请求体在之后单独发送
+    let cookie = "c".repeat(6000);
+    let body = "b".repeat(3000);
+    let mut stream = connect(&server.address);
+    let head = format!("POST /upload HTTP/1.1\r\nHost: localhost\r\nCookie: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", cookie, body.len());
+    let (first, second) = head.split_at(2000);
+    stream.write_all(first.as_bytes()).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    stream.write_all(second.as_bytes()).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    stream.write_all(body.as_bytes()).unwrap();
+    let response = read_response(&mut BufReader::new(stream));
+    assert_eq!(response.status, 200);
+    let forwarded = String::from_utf8(response.body).unwrap();
+    assert!(forwarded.contains(&format!("Cookie: {}\r\n", cookie)));
+    assert!(forwarded.ends_with(&format!("\r\n\r\n{}", body)));
+
+    let oversized = format!("GET / HTTP/1.1\r\nHost: localhost\r\nCookie: {}\r\nConnection: close\r\n\r\n", "c".repeat(20000));
+    assert_eq!(send(&server.address, &oversized).status, 431);
+}
+
+#[test]
+fn asterisk_target_is_only_allowed_for_options() {
+    let dir = test_dir("asterisk_target_is_only_allowed_for_options");
+    let (backend, requests) = counting_backend();
+    let server = TestServer::start(&dir, &proxy_config(&[&backend], ""));
+
+    for method in ["GET", "POST", "DELETE"] {
+        let response = send(&server.address, &format!("{} * HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", method));
+        assert_eq!(response.status, 400, "{}", method);
+    }
+    assert_eq!(requests.load(Ordering::SeqCst), 0);
+
+    assert_ne!(send(&server.address, "OPTIONS * HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").status, 400);
+}
+
+#[test]
+fn keep_alive_serves_several_requests_on_one_connection() {
+    let dir = test_dir("keep_alive_serves_several_requests_on_one_connection");
+    write_file(&dir, "a.html", "first");
+    write_file(&dir, "b.html", "second");
+    let server = TestServer::start(&dir, &static_config("", ""));
+
+    let mut stream = connect(&server.address);
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    stream.write_all(b"GET /a.html HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let first = read_response(&mut reader);
+    assert_eq!(first.body, b"first");
+    assert_eq!(first.header("Connection"), Some("keep-alive"));
+    assert_eq!(first.header("Content-Length"), Some("5"));
+
+    stream.write_all(b"GET /b.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let second = read_response(&mut reader);
+    assert_eq!(second.body, b"second");
+    assert_eq!(second.header("Connection"), Some("close"));
+    // Connection: close之后服务器关闭连接
+    let mut rest = Vec::new();
+    assert_eq!(reader.read_to_end(&mut rest).unwrap(), 0);
+}
+
+#[test]
+fn pipelined_requests_in_one_write_are_all_served() {
+    let dir = test_dir("pipelined_requests_in_one_write_are_all_served");
+    write_file(&dir, "a.html", "first");
+    write_file(&dir, "b.html", "second");
+    let server = TestServer::start(&dir, &static_config("", ""));
+
+    let mut stream = connect(&server.address);
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    stream.write_all(b"GET /a.html HTTP/1.1\r\nHost: localhost\r\n\r\n\
+        GET /b.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let first = read_response(&mut reader);
+    assert_eq!(first.status, 200);
+    assert_eq!(first.body, b"first");
+    let second = read_response(&mut reader);
+    assert_eq!(second.status, 200);
+    assert_eq!(second.body, b"second");
+    let mut rest = Vec::new();
+    assert_eq!(reader.read_to_end(&mut rest).unwrap(), 0);
+}
+
+#[test]
+fn concurrent_slow_requests_are_spread_over_workers() {
+    let dir = test_dir("concurrent_slow_requests_are_spread_over_workers");
+    write_file(&dir, "index.html", "hello");
+    let server = TestServer::start(&dir, &static_config("worker_threads = 4", ""));
+
+    // 每个客户端在请求中途停顿，逐个处理需要10秒
+    let started = Instant::now();
+    let clients: Vec<_> = (0..50).map(|_| {
+        let address = server.address.clone();
+        thread::spawn(move || {
+            let mut stream = connect(&address);
+            stream.write_all(b"GET /index.html HTTP/1.1\r\n").unwrap();
+            thread::sleep(Duration::from_millis(200));
+            stream.write_all(b"Host: localhost\r\nConnection: close\r\n\r\n").unwrap();
+            read_response(&mut BufReader::new(stream)).status
+        })
+    }).collect();
+    for client in clients {
+        assert_eq!(client.join().unwrap(), 200);
+    }
+    assert!(started.elapsed() < Duration::from_secs(8), "耗时 {:?}", started.elapsed());
+}
+
+/// 带折叠请求头的请求，X-Note的值续在下一行
+const FOLDED_REQUEST: &str = "GET / HTTP/1.1\r\nHost: localhost\r\nX-Note: first\r\n  second\r\nConnection: close\r\n\r\n";
+
+#[test]
+fn folded_header_is_rejected_by_default() {
+    let dir = test_dir("folded_header_is_rejected_by_default");
+    let (backend, requests) = counting_backend();
+    let server = TestServer::start(&dir, &proxy_config(&[&backend], ""));
+
+    assert_eq!(send(&server.address, FOLDED_REQUEST).status, 400);
+    assert_eq!(requests.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn folded_header_is_unfolded_when_configured() {
+    let dir = test_dir("folded_header_is_unfolded_when_configured");
+    let server = TestServer::start(&dir, &proxy_server_config("header_folding = \"unfold\"", &[&echo_request_backend()], ""));
+
+    let response = send(&server.address, FOLDED_REQUEST);
+    assert_eq!(response.status, 200);
+    let forwarded = String::from_utf8(response.body).unwrap();
+    assert!(forwarded.contains("\r\nX-Note: first second\r\nConnection"), "{}", forwarded);
+}
+
+#[test]
+fn denied_client_gets_403_and_allowed_client_is_served() {
+    let dir = test_dir("denied_client_gets_403_and_allowed_client_is_served");
+    write_file(&dir, "index.html", "hello");
+    let allowed = TestServer::start(&dir, &static_config("allow = [\"127.0.0.0/8\", \"::1\"]\naccess_default = \"deny\"", ""));
+    assert_eq!(get(&allowed.address, "/index.html").body, b"hello");
+    drop(allowed);
+
+    let denied = TestServer::start(&dir, &static_config("deny = [\"127.0.0.1/32\"]\nallow = [\"127.0.0.0/8\"]", ""));
+    assert_eq!(get(&denied.address, "/index.html").status, 403);
+    denied.wait_for_line(|line| line.contains("不允许访问，返回403"));
+}
+
+#[test]
+fn declared_body_over_max_body_size_gets_413() {
+    let dir = test_dir("declared_body_over_max_body_size_gets_413");
+    let (backend, requests) = counting_backend();
+    let server = TestServer::start(&dir, &proxy_config(&[&backend], "max_body_size = 4096"));
+    let post = |length: usize| send(&server.address, &format!("POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", length, "x".repeat(length)));
+
+    assert_eq!(post(4096).status, 200);
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+    assert_eq!(post(4097).status, 413);
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn declared_body_over_default_limit_gets_413() {
+    let dir = test_dir("declared_body_over_default_limit_gets_413");
+    let (backend, requests) = counting_backend();
+    let server = TestServer::start(&dir, &proxy_config(&[&backend], ""));
+
+    // 没有配置max_body_size时完整缓冲的请求体最多64 MiB
+    let response = send(&server.address, "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 67108865\r\n\r\n");
+    assert_eq!(response.status, 413);
+    assert_eq!(requests.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn static_server_discards_request_body() {
+    let dir = test_dir("static_server_discards_request_body");
+    write_file(&dir, "a.html", "first");
+    write_file(&dir, "b.html", "second");
+    let server = TestServer::start(&dir, &static_config("", ""));
+
+    // 请求体分几次到达，读完丢弃后同一连接上的下一个请求照常处理
+    let mut stream = connect(&server.address);
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    stream.write_all(b"GET /a.html HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10000\r\n\r\n").unwrap();
+    for _ in 0..4 {
+        thread::sleep(Duration::from_millis(50));
+        stream.write_all(&[b'x'; 2500]).unwrap();
+    }
+    stream.write_all(b"GET /b.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    assert_eq!(read_response(&mut reader).body, b"first");
+    assert_eq!(read_response(&mut reader).body, b"second");
+
+    // 声明超过上限的请求体不再读取
+    let response = send(&server.address, "GET /a.html HTTP/1.1\r\nHost: localhost\r\nContent-Length: 67108865\r\n\r\n");
+    assert_eq!(response.status, 413);
+}
+
+#[test]
+fn body_within_max_body_size_is_forwarded_completely() {
+    let dir = test_dir("body_within_max_body_size_is_forwarded_completely");
+    let server = TestServer::start(&dir, &proxy_config(&[&echo_request_backend()], "max_body_size = 65536"));
+
+    let body: String = (0..5000).map(|index| char::from(b'a' + (index % 26) as u8)).collect();
+    let response = send(&server.address, &format!("PUT /item HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body));
+    assert_eq!(response.status, 200);
+    assert!(String::from_utf8(response.body).unwrap().ends_with(&format!("\r\n\r\n{}", body)));
+}
+
+#[test]
+fn requests_over_rate_limit_get_429() {
+    let dir = test_dir("requests_over_rate_limit_get_429");
+    write_file(&dir, "index.html", "hello");
+    let server = TestServer::start(&dir, &format!("{}[rate_limit]\nrequests_per_second = 1\nburst = 3\n", static_config("", "")));
+
+    // 突发的3个请求之后每秒只补充一个令牌
+    let responses: Vec<_> = (0..10).map(|_| get(&server.address, "/index.html")).collect();
+    assert!(responses[..3].iter().all(|response| response.status == 200));
+    let limited: Vec<_> = responses.iter().filter(|response| response.status == 429).collect();
+    assert!(limited.len() >= 6, "{}", limited.len());
+    assert!(limited.iter().all(|response| response.header("Retry-After").is_some_and(|secs| secs.parse::<u64>().unwrap() >= 1)));
+    server.wait_for_line(|line| line.contains(" - /index.html - 429 - "));
+}
+
+/// 只做跳转的服务器配置，extra追加在[redirect]中
+fn redirect_config(target: &str, extra: &str) -> String {
+    format!("[server]\naddress = \"127.0.0.1\"\nport = 0\n[type]\nname = \"redirect\"\n[redirect]\ntarget = \"{}\"\n{}\n", target, extra)
+}
+
+#[test]
+fn redirect_server_builds_location_from_host_and_path() {
+    let dir = test_dir("redirect_server_builds_location_from_host_and_path");
+    let server = TestServer::start(&dir, &redirect_config("https://{host}{path}", ""));
+
+    let response = send(&server.address, "GET /foo?a=1 HTTP/1.1\r\nHost: example.com:8080\r\nConnection: close\r\n\r\n");
+    assert_eq!(response.status, 301);
+    assert_eq!(response.header("Location"), Some("https://example.com/foo?a=1"));
+}
+
+#[test]
+fn redirect_server_uses_configured_status() {
+    let dir = test_dir("redirect_server_uses_configured_status");
+    let server = TestServer::start(&dir, &redirect_config("https://www.example.com{path}", "status = 302"));
+
+    let response = get(&server.address, "/foo");
+    assert_eq!(response.status, 302);
+    assert_eq!(response.header("Location"), Some("https://www.example.com/foo"));
+}
@@ -0,0 +1,355 @@
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+
+use flate2::read::GzDecoder;
+
+use crate::support::{connect, get, send, static_config, test_dir, write_file, Response, TestServer};
+
+/// 带Accept-Encoding: gzip的GET请求
+fn get_gzip(address: &str, path: &str) -> Response {
+    send(address, &format!("GET {} HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n", path))
+}
+
+#[test]
+fn only_files_above_compression_min_size_are_gzipped() {
+    let dir = test_dir("only_files_above_compression_min_size_are_gzipped");
+    write_file(&dir, "small.html", "x".repeat(99));
+    write_file(&dir, "large.html", "x".repeat(100));
+    let server = TestServer::start(&dir, &static_config("", "gzip = true\ncompression_min_size = 100"));
+
+    let small = get_gzip(&server.address, "/small.html");
+    assert_eq!(small.status, 200);
+    assert_eq!(small.header("Content-Encoding"), None);
+    assert_eq!(small.body.len(), 99);
+
+    let large = get_gzip(&server.address, "/large.html");
+    assert_eq!(large.status, 200);
+    assert_eq!(large.header("Content-Encoding"), Some("gzip"));
+}
+
+#[test]
+fn gzip_min_length_is_accepted_as_compression_min_size() {
+    let dir = test_dir("gzip_min_length_is_accepted_as_compression_min_size");
+    write_file(&dir, "page.html", "x".repeat(500));
+    let server = TestServer::start(&dir, &static_config("", "gzip = true\ngzip_min_length = 1000"));
+
+    assert_eq!(get_gzip(&server.address, "/page.html").header("Content-Encoding"), None);
+}
+
+#[test]
+fn file_without_final_newline_is_served_exactly() {
+    let dir = test_dir("file_without_final_newline_is_served_exactly");
+    write_file(&dir, "page.txt", "first line\r\n\r\nlast line");
+    let server = TestServer::start(&dir, &static_config("", ""));
+
+    assert_eq!(get(&server.address, "/page.txt").body, b"first line\r\n\r\nlast line");
+}
+
+#[test]
+fn if_match_with_other_etag_gets_412() {
+    let dir = test_dir("if_match_with_other_etag_gets_412");
+    write_file(&dir, "page.html", "hello");
+    let server = TestServer::start(&dir, &static_config("", ""));
+
+    let etag = get(&server.address, "/page.html").header("ETag").unwrap().to_string();
+    let request = |if_match: &str| format!("GET /page.html HTTP/1.1\r\nHost: localhost\r\nIf-Match: {}\r\nConnection: close\r\n\r\n", if_match);
+    assert_eq!(send(&server.address, &request(&etag)).status, 200);
+    assert_eq!(send(&server.address, &request("\"other\"")).status, 412);
+}
+
+#[test]
+fn matching_if_match_and_wildcard_get_200() {
+    let dir = test_dir("matching_if_match_and_wildcard_get_200");
+    write_file(&dir, "page.html", "hello");
+    // 读入内存和分块发送两条路径都要检查
+    for static_extra in ["", "stream_min_size = 1"] {
+        let server = TestServer::start(&dir, &static_config("", static_extra));
+        let etag = get(&server.address, "/page.html").header("ETag").unwrap().to_string();
+        let request = |if_match: &str| format!("GET /page.html HTTP/1.1\r\nHost: localhost\r\nIf-Match: {}\r\nConnection: close\r\n\r\n", if_match);
+        for if_match in [etag.clone(), String::from("*"), format!("\"other\", {}", etag)] {
+            let response = send(&server.address, &request(&if_match));
+            assert_eq!(response.status, 200, "{} {}", static_extra, if_match);
+            assert_eq!(response.body, b"hello", "{} {}", static_extra, if_match);
+        }
+    }
+}
+
+#[test]
+fn binary_file_is_served_as_raw_bytes_with_its_type() {
+    let dir = test_dir("binary_file_is_served_as_raw_bytes_with_its_type");
+    let png = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', 0x00, 0xff, 0xfe, 0x80];
+    write_file(&dir, "image.png", png);
+    let server = TestServer::start(&dir, &static_config("", ""));
+
+    let response = get(&server.address, "/image.png");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Content-Type"), Some("image/png"));
+    assert_eq!(response.body, png);
+}
+
+#[test]
+fn paths_outside_webroot_are_not_served() {
+    let dir = test_dir("paths_outside_webroot_are_not_served");
+    write_file(&dir, "secret.txt", "secret");
+    write_file(&dir, "www/index.html", "hello");
+    let server = TestServer::start(&dir, "[server]\naddress = \"127.0.0.1\"\nport = 0\n[type]\nname = \"static\"\n\
+        [static]\nwebroot = \"www\"\nindex = \"index.html\"\n");
+
+    for path in ["/../secret.txt", "/%2e%2e/secret.txt", "/..%2fsecret.txt", "/a/../../secret.txt"] {
+        let response = get(&server.address, path);
+        assert_ne!(response.status, 200, "{}", path);
+        assert!(!String::from_utf8_lossy(&response.body).contains("secret"), "{}", path);
+    }
+}
+
+#[test]
+fn file_truncated_while_streaming_closes_connection() {
+    let dir = test_dir("file_truncated_while_streaming_closes_connection");
+    write_file(&dir, "large.bin", vec![b'x'; 64 * 1024 * 1024]);
+    let server = TestServer::start(&dir, &static_config("", ""));
+
+    let mut stream = connect(&server.address);
+    stream.write_all(b"GET /large.bin HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut headers = String::new();
+    while !headers.ends_with("\r\n\r\n") {
+        reader.read_line(&mut headers).unwrap();
+    }
+    assert!(headers.contains("Content-Length: 67108864"), "{}", headers);
+
+    // 服务器还在发送时截断文件，它不能再凑满Content-Length，只能关闭连接
+    OpenOptions::new().write(true).open(dir.join("large.bin")).unwrap().set_len(0).unwrap();
+    let mut body = Vec::new();
+    reader.read_to_end(&mut body).unwrap();
+    assert!(body.len() < 64 * 1024 * 1024, "收到了完整的响应体");
+    server.wait_for_line(|line| line.contains("发送文件中断"));
+}
+
+/// 在目录中创建命名管道：大小总是0，服务器打开后读到contents，相当于读取过程中文件变长
+fn growing_file(dir: &Path, name: &str, contents: &'static str) {
+    let path = dir.join(name);
+    assert!(Command::new("mkfifo").arg(&path).status().unwrap().success());
+    thread::spawn(move || {
+        let _ = fs::write(path, contents);
+    });
+}
+
+#[test]
+fn file_changed_while_reading_gets_500_by_default() {
+    let dir = test_dir("file_changed_while_reading_gets_500_by_default");
+    growing_file(&dir, "live.txt", "fresh content");
+    let server = TestServer::start(&dir, &static_config("", ""));
+
+    assert_eq!(get(&server.address, "/live.txt").status, 500);
+    server.wait_for_line(|line| line.contains("文件在读取过程中被修改: ") && line.contains("读到 13 字节"));
+}
+
+#[test]
+fn file_changed_while_reading_is_logged_and_sent_when_configured() {
+    let dir = test_dir("file_changed_while_reading_is_logged_and_sent_when_configured");
+    growing_file(&dir, "live.txt", "fresh content");
+    let server = TestServer::start(&dir, &static_config("", "short_read = \"log\""));
+
+    let response = get(&server.address, "/live.txt");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, b"fresh content");
+    assert_eq!(response.header("Content-Length"), Some("13"));
+    server.wait_for_line(|line| line.contains("文件在读取过程中被修改，发送读到的内容"));
+}
+
+#[test]
+fn gzipped_html_decompresses_to_original_and_images_are_untouched() {
+    let dir = test_dir("gzipped_html_decompresses_to_original_and_images_are_untouched");
+    let html: String = (0..1000).map(|line| format!("<p>{:05}</p>\n", line)).collect();
+    assert!(html.len() >= 10 * 1024);
+    write_file(&dir, "page.html", &html);
+    write_file(&dir, "image.png", vec![0x89; 10 * 1024]);
+    let server = TestServer::start(&dir, &static_config("", "gzip = true"));
+
+    let response = get_gzip(&server.address, "/page.html");
+    assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+    assert_eq!(response.header("Content-Length"), Some(response.body.len().to_string().as_str()));
+    let mut decompressed = String::new();
+    GzDecoder::new(response.body.as_slice()).read_to_string(&mut decompressed).unwrap();
+    assert_eq!(decompressed, html);
+
+    let image = get_gzip(&server.address, "/image.png");
+    assert_eq!(image.header("Content-Encoding"), None);
+    assert_eq!(image.body.len(), 10 * 1024);
+}
+
+#[test]
+fn autoindex_lists_directory_without_index_file() {
+    let dir = test_dir("autoindex_lists_directory_without_index_file");
+    write_file(&dir, "files/b.txt", "b");
+    write_file(&dir, "files/a.txt", "a");
+    write_file(&dir, "files/<script>.txt", "x");
+    write_file(&dir, "files/sub/c.txt", "c");
+    let server = TestServer::start(&dir, &static_config("", "autoindex = true"));
+
+    let response = get(&server.address, "/files/");
+    assert_eq!(response.status, 200);
+    let listing = String::from_utf8(response.body).unwrap();
+    // 目录在前，文件按名称排序；文件名经过转义
+    let positions: Vec<usize> = [">sub/<", ">&lt;script&gt;.txt<", ">a.txt<", ">b.txt<"].iter()
+        .map(|name| listing.find(name).unwrap_or_else(|| panic!("缺少 {}: {}", name, listing)))
+        .collect();
+    assert!(positions.is_sorted(), "{}", listing);
+    assert!(!listing.contains("<script>"), "{}", listing);
+
+    let disabled = TestServer::start(&test_dir("autoindex_disabled"), &static_config("", ""));
+    assert_ne!(get(&disabled.address, "/").status, 200);
+}
+
+#[test]
+fn request_paths_are_percent_decoded_without_query() {
+    let dir = test_dir("request_paths_are_percent_decoded_without_query");
+    write_file(&dir, "my file.html", "space");
+    write_file(&dir, "文档.html", "unicode");
+    write_file(&dir, "page.html", "page");
+    let server = TestServer::start(&dir, &static_config("", ""));
+
+    assert_eq!(get(&server.address, "/my%20file.html").body, b"space");
+    assert_eq!(get(&server.address, "/%E6%96%87%E6%A1%A3.html").body, b"unicode");
+    assert_eq!(get(&server.address, "/page.html?v=2").body, b"page");
+    for invalid in ["/page%2.html", "/page%zz.html", "/%FF.html"] {
+        assert_eq!(get(&server.address, invalid).status, 400, "{}", invalid);
+    }
+}
+
+#[test]
+fn virtual_hosts_on_one_port_use_their_own_webroots() {
+    let dir = test_dir("virtual_hosts_on_one_port_use_their_own_webroots");
+    write_file(&dir, "index.html", "default");
+    write_file(&dir, "sites/a/index.html", "site a");
+    write_file(&dir, "sites/b/index.html", "site b");
+    let vhost = |hostname: &str, webroot: &str| format!("[[vhosts]]\nhostnames = [\"{}\"]\n[vhosts.type]\nname = \"static\"\n\
+        [vhosts.static]\nwebroot = \"{}\"\nindex = \"index.html\"\n", hostname, webroot);
+    let config = format!("{}{}{}", static_config("", ""), vhost("a.example.com", "sites/a"), vhost("b.example.com", "sites/b"));
+    let server = TestServer::start(&dir, &config);
+
+    let request = |host: &str| send(&server.address, &format!("GET / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", host)).body;
+    assert_eq!(request("a.example.com"), b"site a");
+    // 域名不区分大小写并忽略端口
+    assert_eq!(request("B.Example.com:8080"), b"site b");
+    assert_eq!(request("c.example.com"), b"default");
+}
+
+#[test]
+fn large_file_is_streamed_with_bounded_memory() {
+    let dir = test_dir("large_file_is_streamed_with_bounded_memory");
+    const LENGTH: usize = 20 * 1024 * 1024;
+    let contents: Vec<u8> = (0..LENGTH).map(|index| (index % 251) as u8).collect();
+    write_file(&dir, "video.mp4", &contents);
+    let server = TestServer::start(&dir, &static_config("", ""));
+
+    let response = get(&server.address, "/video.mp4");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Content-Length"), Some(LENGTH.to_string().as_str()));
+    assert!(response.body == contents, "响应体长度 {}", response.body.len());
+    // 文件分块发送，服务器的内存不会增长到文件大小
+    assert!(server.peak_memory_kib() < (LENGTH / 1024) as u64, "最大常驻内存 {} KiB", server.peak_memory_kib());
+}
+
+#[test]
+fn range_requests_get_partial_content() {
+    let dir = test_dir("range_requests_get_partial_content");
+    let contents: Vec<u8> = (0..1000).map(|index| (index % 256) as u8).collect();
+    write_file(&dir, "data.bin", &contents);
+    let server = TestServer::start(&dir, &static_config("", ""));
+    let request = |range: &str| send(&server.address, &format!("GET /data.bin HTTP/1.1\r\nHost: localhost\r\nRange: {}\r\nConnection: close\r\n\r\n", range));
+
+    let full = get(&server.address, "/data.bin");
+    assert_eq!(full.status, 200);
+    assert_eq!(full.header("Accept-Ranges"), Some("bytes"));
+
+    let middle = request("bytes=100-199");
+    assert_eq!(middle.status, 206);
+    assert_eq!(middle.header("Content-Range"), Some("bytes 100-199/1000"));
+    assert_eq!(middle.header("Content-Length"), Some("100"));
+    assert_eq!(middle.body, &contents[100..200]);
+
+    let open_ended = request("bytes=100-");
+    assert_eq!(open_ended.status, 206);
+    assert_eq!(open_ended.header("Content-Range"), Some("bytes 100-999/1000"));
+    assert_eq!(open_ended.body, &contents[100..]);
+
+    let unsatisfiable = request("bytes=1000-");
+    assert_eq!(unsatisfiable.status, 416);
+    assert_eq!(unsatisfiable.header("Content-Range"), Some("bytes */1000"));
+}
+
+#[test]
+fn current_validators_get_304() {
+    let dir = test_dir("current_validators_get_304");
+    write_file(&dir, "style.css", "body {}");
+    let server = TestServer::start(&dir, &static_config("", ""));
+    let request = |header: &str| send(&server.address, &format!("GET /style.css HTTP/1.1\r\nHost: localhost\r\n{}\r\nConnection: close\r\n\r\n", header));
+
+    let first = get(&server.address, "/style.css");
+    let etag = first.header("ETag").unwrap();
+    let last_modified = first.header("Last-Modified").unwrap();
+
+    let by_etag = request(&format!("If-None-Match: {}", etag));
+    assert_eq!(by_etag.status, 304);
+    assert!(by_etag.body.is_empty());
+    assert_eq!(request(&format!("If-Modified-Since: {}", last_modified)).status, 304);
+    assert_eq!(request("If-None-Match: \"stale\"").status, 200);
+}
+
+#[test]
+fn missing_path_gets_configured_error_page() {
+    let dir = test_dir("missing_path_gets_configured_error_page");
+    write_file(&dir, "404.html", "<h1>custom not found</h1>");
+    let server = TestServer::start(&dir, &static_config("", "error_pages = { 404 = \"404.html\" }"));
+
+    let response = get(&server.address, "/missing.html");
+    assert_eq!(response.status, 404);
+    assert_eq!(response.header("Content-Type"), Some("text/html; charset=utf-8"));
+    assert_eq!(response.body, b"<h1>custom not found</h1>");
+}
+
+#[test]
+fn missing_error_page_falls_back_to_builtin_response() {
+    let dir = test_dir("missing_error_page_falls_back_to_builtin_response");
+    let server = TestServer::start(&dir, &static_config("", "error_pages = { 404 = \"404.html\" }"));
+    let builtin = TestServer::start(&test_dir("missing_error_page_builtin"), &static_config("", ""));
+
+    let response = get(&server.address, "/missing.html");
+    assert_eq!(response.status, 404);
+    assert_eq!(response.body, get(&builtin.address, "/missing.html").body);
+}
+
+#[test]
+fn unknown_page_routes_get_spa_fallback() {
+    let dir = test_dir("unknown_page_routes_get_spa_fallback");
+    write_file(&dir, "index.html", "<div id=\"app\"></div>");
+    let server = TestServer::start(&dir, &static_config("", "fallback = \"index.html\""));
+    let request = |path: &str| send(&server.address, &format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\
+        Accept: text/html,application/xhtml+xml,*/*;q=0.8\r\nConnection: close\r\n\r\n", path));
+
+    let route = request("/some/app/route");
+    assert_eq!(route.status, 200);
+    assert_eq!(route.body, b"<div id=\"app\"></div>");
+    // 缺失的资源文件仍返回404
+    assert_eq!(request("/missing.js").status, 404);
+    assert_eq!(request("/assets/logo.png").status, 404);
+    // 不接受HTML的请求，例如前端的API调用，也返回404
+    assert_eq!(get(&server.address, "/some/app/route").status, 404);
+}
+
+#[test]
+fn server_header_and_error_page_use_package_version() {
+    let dir = test_dir("server_header_and_error_page_use_package_version");
+    write_file(&dir, "index.html", "hello");
+    let server = TestServer::start(&dir, &format!("error_format = \"html\"\n{}", static_config("", "")));
+
+    let version = format!("nextWeb/{}", env!("CARGO_PKG_VERSION"));
+    assert_eq!(get(&server.address, "/index.html").header("Server"), Some(version.as_str()));
+    let missing = get(&server.address, "/missing.html");
+    assert_eq!(missing.status, 404);
+    assert!(String::from_utf8_lossy(&missing.body).contains(&format!("<p>{}</p>", version)));
+}
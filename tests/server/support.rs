@@ -0,0 +1,269 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// 等待服务器启动、日志出现等的最长时间
+const WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 每个测试单独的临时目录，上次运行留下的内容先删除
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// 在目录中写入文件，需要时创建上级目录
+pub fn write_file(dir: &Path, name: &str, contents: impl AsRef<[u8]>) {
+    let path = dir.join(name);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).unwrap();
+    }
+    fs::write(path, contents).unwrap();
+}
+
+/// 运行中的nextWeb进程，释放时结束进程
+pub struct TestServer {
+    child: Child,
+    // 标准输出和标准错误的各行，按到达顺序
+    output: Arc<Mutex<Vec<String>>>,
+    // 启动汇总中名为test的服务器的地址，TCP为IP:端口，Unix socket为unix:路径
+    pub address: String,
+}
+
+impl TestServer {
+    /// 写入只包含一个服务器test的config.toml和它的配置文件server.toml，启动后等待开始监听
+    pub fn start(dir: &Path, server_config: &str) -> TestServer {
+        write_file(dir, "server.toml", server_config);
+        write_file(dir, "config.toml", "[[servers]]\nname = \"test\"\nconfig = \"server.toml\"\n");
+        TestServer::spawn(dir)
+    }
+
+    /// 使用目录中已有的config.toml启动，其中需要有名为test的服务器
+    pub fn spawn(dir: &Path) -> TestServer {
+        TestServer::spawn_with(dir, |command| {
+            command.args(["--config", "config.toml"]);
+        })
+    }
+
+    /// 在目录中启动，由configure设置命令行参数和环境变量
+    pub fn spawn_with(dir: &Path, configure: impl FnOnce(&mut Command)) -> TestServer {
+        let (child, output) = spawn_process(dir, configure);
+        let mut server = TestServer { child, output, address: String::new() };
+        let row = server.wait_for_line(|line| line.starts_with("test ") && (line.contains("://") || line.contains("unix:")));
+        let address = row.split_whitespace().last().unwrap();
+        server.address = address.split_once("://").map_or(address, |(_, address)| address).to_string();
+        server
+    }
+
+    /// 等待满足条件的输出行出现并返回该行，超时时测试失败
+    pub fn wait_for_line(&self, mut matches: impl FnMut(&str) -> bool) -> String {
+        let started = Instant::now();
+        loop {
+            if let Some(line) = self.output.lock().unwrap().iter().find(|line| matches(line)) {
+                return line.clone();
+            }
+            assert!(started.elapsed() < WAIT_TIMEOUT, "等待输出超时，已有输出:\n{}", self.output.lock().unwrap().join("\n"));
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+impl TestServer {
+    /// 进程运行以来的最大常驻内存（/proc中的VmHWM），单位KiB
+    pub fn peak_memory_kib(&self) -> u64 {
+        let status = fs::read_to_string(format!("/proc/{}/status", self.child.id())).unwrap();
+        status.lines()
+            .find_map(|line| line.strip_prefix("VmHWM:"))
+            .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+            .unwrap()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// 在目录中启动nextWeb，后台线程收集标准输出和标准错误
+fn spawn_process(dir: &Path, configure: impl FnOnce(&mut Command)) -> (Child, Arc<Mutex<Vec<String>>>) {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_nextWeb"));
+    configure(&mut command);
+    let mut child = command
+        .current_dir(dir)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let stdout: Box<dyn Read + Send> = Box::new(child.stdout.take().unwrap());
+    let stderr: Box<dyn Read + Send> = Box::new(child.stderr.take().unwrap());
+    for pipe in [stdout, stderr] {
+        let output = Arc::clone(&output);
+        thread::spawn(move || {
+            for line in BufReader::new(pipe).lines().map_while(Result::ok) {
+                output.lock().unwrap().push(line);
+            }
+        });
+    }
+    (child, output)
+}
+
+/// 启动nextWeb并等待它退出，返回是否正常退出和全部输出，用于检查启动时的配置错误
+pub fn run_to_exit(dir: &Path) -> (bool, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_nextWeb"))
+        .args(["--config", "config.toml"])
+        .current_dir(dir)
+        .output()
+        .unwrap();
+    let text = format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+    (output.status.success(), text)
+}
+
+/// 解析后的HTTP响应
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// 第一个名称匹配的头部的值，名称不区分大小写
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+}
+
+/// 连接服务器，TCP地址为127.0.0.1:端口
+pub fn connect(address: &str) -> TcpStream {
+    let stream = TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(WAIT_TIMEOUT)).unwrap();
+    stream
+}
+
+/// 发送原始请求并读取一个响应
+pub fn send(address: &str, request: &str) -> Response {
+    let mut stream = connect(address);
+    stream.write_all(request.as_bytes()).unwrap();
+    read_response(&mut BufReader::new(stream))
+}
+
+/// 发送不保持连接的GET请求
+pub fn get(address: &str, path: &str) -> Response {
+    send(address, &format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path))
+}
+
+/// 读取一个响应，按Content-Length或chunked确定响应体，都没有时读到连接关闭
+pub fn read_response(reader: &mut impl BufRead) -> Response {
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line).unwrap();
+    let status = status_line.split_whitespace().nth(1)
+        .and_then(|status| status.parse().ok())
+        .unwrap_or_else(|| panic!("无效的状态行: {:?}", status_line));
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').unwrap();
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    let mut response = Response { status, headers, body: Vec::new() };
+    if status == 204 || status == 304 || (100..200).contains(&status) {
+        return response;
+    }
+    if let Some(length) = response.header("Content-Length") {
+        response.body = vec![0; length.parse().unwrap()];
+        reader.read_exact(&mut response.body).unwrap();
+    } else if response.header("Transfer-Encoding").is_some_and(|value| value.eq_ignore_ascii_case("chunked")) {
+        response.body = read_chunked(reader);
+    } else {
+        reader.read_to_end(&mut response.body).unwrap();
+    }
+    response
+}
+
+/// 读取chunked编码的响应体，忽略trailer
+fn read_chunked(reader: &mut impl BufRead) -> Vec<u8> {
+    let mut body = Vec::new();
+    loop {
+        let mut size_line = String::new();
+        reader.read_line(&mut size_line).unwrap();
+        let size = usize::from_str_radix(size_line.trim().split(';').next().unwrap(), 16).unwrap();
+        if size == 0 {
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            return body;
+        }
+        let mut chunk = vec![0; size + 2];
+        reader.read_exact(&mut chunk).unwrap();
+        body.extend_from_slice(&chunk[..size]);
+    }
+}
+
+/// 在随机端口上运行的模拟后端，每个连接在新线程中交给handler处理，返回监听地址
+pub fn backend(handler: impl Fn(TcpStream) + Send + Sync + 'static) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let handler = Arc::new(handler);
+    thread::spawn(move || {
+        for stream in listener.incoming().map_while(Result::ok) {
+            let handler = Arc::clone(&handler);
+            thread::spawn(move || handler(stream));
+        }
+    });
+    address
+}
+
+/// 模拟后端读取一个请求：头部和Content-Length指定的请求体
+pub fn read_request(stream: &mut impl Read) -> String {
+    let mut reader = BufReader::new(stream);
+    let mut request = String::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+            return request;
+        }
+        request.push_str(&line);
+        if line == "\r\n" {
+            break;
+        }
+    }
+    let length = request.lines()
+        .find_map(|line| line.split_once(':').filter(|(name, _)| name.eq_ignore_ascii_case("Content-Length")))
+        .map_or(0, |(_, value)| value.trim().parse().unwrap());
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).unwrap();
+    request.push_str(&String::from_utf8_lossy(&body));
+    request
+}
+
+/// 代理到backends（IP:端口）的最小配置，extra追加在[proxy]中
+pub fn proxy_config(backends: &[&str], extra: &str) -> String {
+    proxy_server_config("", backends, extra)
+}
+
+/// 同proxy_config，server_extra追加在[server]中
+pub fn proxy_server_config(server_extra: &str, backends: &[&str], extra: &str) -> String {
+    let backends: Vec<String> = backends.iter().map(|backend| format!("\"http://{}\"", backend)).collect();
+    format!("[server]\naddress = \"127.0.0.1\"\nport = 0\n{}\n[type]\nname = \"proxy\"\n[proxy]\nbackend = [{}]\n\
+        modify_host = false\nheader_host = \"\"\nmodify_server = false\n{}\n", server_extra, backends.join(", "), extra)
+}
+
+/// 以测试目录本身为webroot的静态文件服务器，server_extra追加在[server]中，static_extra追加在[static]中
+pub fn static_config(server_extra: &str, static_extra: &str) -> String {
+    format!("[server]\naddress = \"127.0.0.1\"\nport = 0\n{}\n[type]\nname = \"static\"\n\
+        [static]\nwebroot = \".\"\nindex = \"index.html\"\n{}\n", server_extra, static_extra)
+}
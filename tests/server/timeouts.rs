@@ -0,0 +1,117 @@
+use std::io::{BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use socket2::{Domain, Socket, Type};
+
+use crate::support::{backend, connect, get, proxy_config, read_request, read_response, static_config, test_dir, TestServer};
+
+#[test]
+fn slow_client_gets_408() {
+    let dir = test_dir("slow_client_gets_408");
+    let server = TestServer::start(&dir, &static_config("request_timeout_secs = 1", ""));
+
+    let mut stream = connect(&server.address);
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: local").unwrap();
+    assert_eq!(read_response(&mut BufReader::new(stream)).status, 408);
+}
+
+#[test]
+fn slow_backend_gets_504() {
+    let dir = test_dir("slow_backend_gets_504");
+    let backend = backend(|mut stream| {
+        read_request(&mut stream);
+        thread::sleep(Duration::from_secs(3));
+    });
+    let server = TestServer::start(&dir, &proxy_config(&[&backend], "backend_first_byte_timeout = 1"));
+
+    assert_eq!(get(&server.address, "/").status, 504);
+}
+
+#[test]
+fn backend_connect_timeout_gets_504() {
+    let dir = test_dir("backend_connect_timeout_gets_504");
+    let (_listener, _queued, backend) = unresponsive_backend();
+    let server = TestServer::start(&dir, &proxy_config(&[&backend.to_string()], ""));
+
+    assert_eq!(get(&server.address, "/").status, 504);
+}
+
+#[test]
+fn exhausted_budget_stops_failover_with_504() {
+    let dir = test_dir("exhausted_budget_stops_failover_with_504");
+    let (_first_listener, _first_queued, first) = unresponsive_backend();
+    let (_second_listener, _second_queued, second) = unresponsive_backend();
+    let server = TestServer::start(&dir, &proxy_config(&[&first.to_string(), &second.to_string()], "proxy_timeout_budget = 1"));
+
+    // 每个后端的连接超时是5秒，总超时1秒用完后不再尝试第二个后端
+    let started = Instant::now();
+    assert_eq!(get(&server.address, "/").status, 504);
+    assert!(started.elapsed() < Duration::from_secs(4), "用时 {:?}", started.elapsed());
+    server.wait_for_line(|line| line.contains("代理总超时已用完"));
+}
+
+/// 立即返回响应头、之后每300毫秒发送一个字节响应体的后端，发完20字节需要6秒
+fn slow_body_backend() -> String {
+    backend(|mut stream| {
+        read_request(&mut stream);
+        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 20\r\nConnection: close\r\n\r\n");
+        for _ in 0..20 {
+            thread::sleep(Duration::from_millis(300));
+            if stream.write_all(b"x").is_err() {
+                return;
+            }
+        }
+    })
+}
+
+#[test]
+fn proxy_timeout_budget_covers_response_body() {
+    let dir = test_dir("proxy_timeout_budget_covers_response_body");
+    let server = TestServer::start(&dir, &proxy_config(&[&slow_body_backend()], "proxy_timeout_budget = 1\nproxy_buffering = \"full\""));
+
+    // 完整缓冲时响应体没有在总超时内收完，返回502而不是残缺的响应
+    let started = Instant::now();
+    assert_eq!(get(&server.address, "/").status, 502);
+    assert!(started.elapsed() < Duration::from_secs(4), "用时 {:?}", started.elapsed());
+    drop(server);
+
+    // 边收边转发时超时后中断连接
+    let server = TestServer::start(&dir, &proxy_config(&[&slow_body_backend()], "proxy_timeout_budget = 1"));
+    let started = Instant::now();
+    let mut stream = connect(&server.address);
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    assert!(started.elapsed() < Duration::from_secs(4), "用时 {:?}", started.elapsed());
+    assert!(response.starts_with(b"HTTP/1.1 200"), "{}", String::from_utf8_lossy(&response));
+    assert!(!response.ends_with(&[b'x'; 20]), "{}", String::from_utf8_lossy(&response));
+}
+
+/// 监听队列已满且从不accept的后端，之后的连接握手得不到回应；返回的监听socket和排队的连接需要保持到测试结束
+fn unresponsive_backend() -> (Socket, TcpStream, SocketAddr) {
+    let listener = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+    listener.bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap().into()).unwrap();
+    listener.listen(0).unwrap();
+    let address = listener.local_addr().unwrap().as_socket().unwrap();
+    let queued = TcpStream::connect(address).unwrap();
+    (listener, queued, address)
+}
+
+#[test]
+fn partial_request_line_is_closed_after_timeout_secs() {
+    let dir = test_dir("partial_request_line_is_closed_after_timeout_secs");
+    let server = TestServer::start(&dir, &static_config("timeout_secs = 1", ""));
+
+    let started = Instant::now();
+    let mut stream = connect(&server.address);
+    stream.write_all(b"GET /ind").unwrap();
+    let mut reader = BufReader::new(stream);
+    assert_eq!(read_response(&mut reader).status, 408);
+    // 408之后连接被关闭，不会继续等待
+    let mut rest = Vec::new();
+    assert_eq!(reader.read_to_end(&mut rest).unwrap(), 0);
+    assert!(started.elapsed() < Duration::from_secs(5), "用时 {:?}", started.elapsed());
+    server.wait_for_line(|line| line.contains(" - 408 - "));
+}
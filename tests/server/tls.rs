@@ -0,0 +1,178 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+use crate::support::{backend, connect, proxy_config, read_request, read_response, send, test_dir, write_file, Response, TestServer};
+
+/// 测试用证书所在的目录，CA签发了localhost和127.0.0.1的服务器证书，以及主题为O=nextWeb, CN=test-client的客户端证书
+fn cert_path(name: &str) -> String {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/server/certs").join(name).display().to_string()
+}
+
+/// 使用测试证书的HTTPS静态文件服务器，extra追加在[server]中
+fn tls_static_config(extra: &str) -> String {
+    format!("[server]\naddress = \"127.0.0.1\"\nport = 0\n{}\n[type]\nname = \"static\"\n\
+        [static]\nwebroot = \".\"\nindex = \"index.html\"\n[tls]\ncert = \"{}\"\nkey = \"{}\"\n",
+        extra, cert_path("server.pem"), cert_path("server.key"))
+}
+
+/// 使用测试证书的HTTPS代理，extra追加在[proxy]中，tls_extra追加在[tls]中
+fn tls_proxy_config(backend: &str, extra: &str, tls_extra: &str) -> String {
+    format!("{}[tls]\ncert = \"{}\"\nkey = \"{}\"\n{}\n", proxy_config(&[backend], extra),
+        cert_path("server.pem"), cert_path("server.key"), tls_extra)
+}
+
+/// 信任测试CA的TLS客户端配置，with_client_cert时出示测试客户端证书
+fn client_config(with_client_cert: bool) -> ClientConfig {
+    let mut roots = RootCertStore::empty();
+    roots.add(CertificateDer::from_pem_file(cert_path("ca.pem")).unwrap()).unwrap();
+    let builder = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots);
+    if with_client_cert {
+        let cert = CertificateDer::from_pem_file(cert_path("client.pem")).unwrap();
+        let key = PrivateKeyDer::from_pem_file(cert_path("client.key")).unwrap();
+        builder.with_client_auth_cert(vec![cert], key).unwrap()
+    } else {
+        builder.with_no_client_auth()
+    }
+}
+
+/// 建立TLS连接，SNI为server_name；握手在第一次读写时进行
+fn connect_tls(address: &str, server_name: &str, config: ClientConfig) -> StreamOwned<ClientConnection, TcpStream> {
+    let connection = ClientConnection::new(Arc::new(config), ServerName::try_from(server_name.to_string()).unwrap()).unwrap();
+    StreamOwned::new(connection, connect(address))
+}
+
+/// 通过TLS发送请求并读取一个响应，SNI为server_name
+fn send_tls(address: &str, server_name: &str, config: ClientConfig, request: &str) -> Response {
+    let mut stream = connect_tls(address, server_name, config);
+    stream.write_all(request.as_bytes()).unwrap();
+    read_response(&mut BufReader::new(stream))
+}
+
+#[test]
+fn access_log_records_tls_sni() {
+    let dir = test_dir("access_log_records_tls_sni");
+    write_file(&dir, "index.html", "hello");
+    let server = TestServer::start(&dir, &tls_static_config("log_tls_sni = true"));
+
+    let response = send_tls(&server.address, "localhost", client_config(false),
+        "GET /index.html HTTP/1.1\r\nHost: other.example.com\r\nConnection: close\r\n\r\n");
+    assert_eq!(response.status, 200);
+    server.wait_for_line(|line| line.contains(" - /index.html - 200 - ") && line.ends_with(" - sni=localhost"));
+}
+
+/// 要求客户端证书的HTTPS代理，后端把收到的请求原样返回在响应体中
+fn mtls_echo_proxy(dir: &Path) -> TestServer {
+    let backend = backend(|mut stream| {
+        let request = read_request(&mut stream);
+        let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", request.len(), request);
+    });
+    TestServer::start(dir, &tls_proxy_config(&backend, "", &format!("client_ca = \"{}\"", cert_path("ca.pem"))))
+}
+
+#[test]
+fn client_certificate_is_forwarded_to_backend() {
+    let dir = test_dir("client_certificate_is_forwarded_to_backend");
+    let server = mtls_echo_proxy(&dir);
+
+    let response = send_tls(&server.address, "localhost", client_config(true),
+        "GET / HTTP/1.1\r\nHost: localhost\r\nX-Client-Cert-Subject: CN=admin\r\nConnection: close\r\n\r\n");
+    assert_eq!(response.status, 200);
+    let forwarded = String::from_utf8(response.body).unwrap();
+    assert!(forwarded.contains("X-Client-Cert-Subject: CN=test-client,O=nextWeb\r\n"), "{}", forwarded);
+    assert!(forwarded.contains("X-Client-Cert-Fingerprint: 20dd5246eb9e06ff1640301c5100831e5469e930e87d1f58aff836d616c12e1b\r\n"), "{}", forwarded);
+    assert!(!forwarded.contains("CN=admin"), "{}", forwarded);
+}
+
+#[test]
+fn client_without_certificate_is_rejected() {
+    let dir = test_dir("client_without_certificate_is_rejected");
+    let server = mtls_echo_proxy(&dir);
+
+    let mut stream = connect_tls(&server.address, "localhost", client_config(false));
+    let _ = stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    let mut received = Vec::new();
+    assert!(stream.read_to_end(&mut received).is_err() || received.is_empty());
+}
+
+#[test]
+fn plain_http_on_tls_port_gets_400() {
+    let dir = test_dir("plain_http_on_tls_port_gets_400");
+    write_file(&dir, "index.html", "hello");
+    let server = TestServer::start(&dir, &format!("{}plaintext_http_message = \"Use https://\"\n", tls_static_config("")));
+
+    let response = send(&server.address, "GET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(response.status, 400);
+    assert_eq!(response.body, b"Use https://");
+
+    // TLS客户端不受影响
+    let response = send_tls(&server.address, "localhost", client_config(false),
+        "GET /index.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    assert_eq!(response.status, 200);
+}
+
+/// 把收到的数据原样发回的后端；有handshake时先读取一个请求并以它回应，之后再开始回显
+fn echo_backend(handshake: Option<&'static str>) -> String {
+    backend(move |mut stream| {
+        if let Some(handshake) = handshake {
+            read_request(&mut stream);
+            let _ = stream.write_all(handshake.as_bytes());
+        }
+        let mut writer = stream.try_clone().unwrap();
+        let _ = io::copy(&mut stream, &mut writer);
+    })
+}
+
+/// 写入数据并读回同样长度的数据
+fn round_trip(stream: &mut BufReader<impl Read + Write>, data: &[u8]) -> Vec<u8> {
+    stream.get_mut().write_all(data).unwrap();
+    let mut echoed = vec![0; data.len()];
+    stream.read_exact(&mut echoed).unwrap();
+    echoed
+}
+
+#[test]
+fn websocket_over_tls_round_trips() {
+    let dir = test_dir("websocket_over_tls_round_trips");
+    let backend = echo_backend(Some("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+        Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n"));
+    let server = TestServer::start(&dir, &tls_proxy_config(&backend, "", ""));
+
+    let mut stream = BufReader::new(connect_tls(&server.address, "localhost", client_config(false)));
+    stream.get_mut().write_all(b"GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+    let response = read_response(&mut stream);
+    assert_eq!(response.status, 101);
+    assert_eq!(response.header("Upgrade"), Some("websocket"));
+
+    // 带掩码的文本帧"hello"，转发时不解析帧，后端回显原样的字节
+    let frame = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+    assert_eq!(round_trip(&mut stream, &frame), frame);
+    assert_eq!(round_trip(&mut stream, &frame), frame);
+}
+
+#[test]
+fn connect_tunnel_over_tls_round_trips() {
+    let dir = test_dir("connect_tunnel_over_tls_round_trips");
+    let target = echo_backend(None);
+    let server = TestServer::start(&dir, &tls_proxy_config(&target, "connect_tunnel = true", ""));
+
+    let mut stream = BufReader::new(connect_tls(&server.address, "localhost", client_config(false)));
+    stream.get_mut().write_all(format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target).as_bytes()).unwrap();
+    let mut status_line = String::new();
+    stream.read_line(&mut status_line).unwrap();
+    assert!(status_line.starts_with("HTTP/1.1 200"), "{}", status_line);
+    let mut blank = String::new();
+    stream.read_line(&mut blank).unwrap();
+
+    assert_eq!(round_trip(&mut stream, b"ping"), b"ping");
+    assert_eq!(round_trip(&mut stream, b"pong"), b"pong");
+}
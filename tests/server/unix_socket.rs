@@ -0,0 +1,78 @@
+use std::io::{BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::thread;
+
+use crate::support::{backend, get, read_request, read_response, run_to_exit, test_dir, write_file, Response, TestServer};
+
+/// 通过Unix socket发送请求并读取响应
+fn send_unix(path: &str, request: &str) -> Response {
+    let mut stream = UnixStream::connect(path).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    read_response(&mut BufReader::new(stream))
+}
+
+#[test]
+fn serves_static_file_over_unix_socket() {
+    let dir = test_dir("serves_static_file_over_unix_socket");
+    write_file(&dir, "www/index.html", "<h1>over unix</h1>\n");
+    let socket = dir.join("nextweb.sock").display().to_string();
+    let server = TestServer::start(&dir, &format!("[server]\nunix_socket = \"{}\"\n\
+        [type]\nname = \"static\"\n[static]\nwebroot = \"www\"\nindex = \"index.html\"\n", socket));
+    assert_eq!(server.address, format!("unix:{}", socket));
+
+    let response = send_unix(&socket, "GET /index.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, b"<h1>over unix</h1>\n");
+    server.wait_for_line(|line| line.contains(" unix - /index.html - 200 - "));
+}
+
+#[test]
+fn proxies_to_unix_socket_backend() {
+    let dir = test_dir("proxies_to_unix_socket_backend");
+    let socket = dir.join("backend.sock");
+    let listener = UnixListener::bind(&socket).unwrap();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().map_while(Result::ok) {
+            let request = read_request(&mut stream);
+            let body = request.lines().next().unwrap().to_string();
+            let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+        }
+    });
+    let server = TestServer::start(&dir, &format!("[server]\naddress = \"127.0.0.1\"\nport = 0\n\
+        [type]\nname = \"proxy\"\n[proxy]\nbackend = \"unix:{}\"\nmodify_host = false\nheader_host = \"\"\nmodify_server = false\n",
+        socket.display()));
+
+    let response = get(&server.address, "/hello?x=1");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, b"GET /hello?x=1 HTTP/1.1");
+}
+
+#[test]
+fn unix_socket_clients_have_no_forwarded_ip() {
+    let dir = test_dir("unix_socket_clients_have_no_forwarded_ip");
+    let backend = backend(|mut stream| {
+        let request = read_request(&mut stream);
+        let forwarded: Vec<&str> = request.lines().filter(|line| line.starts_with("X-Forwarded-")).collect();
+        let body = forwarded.join("\n");
+        let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+    });
+    let socket = dir.join("nextweb.sock").display().to_string();
+    let _server = TestServer::start(&dir, &format!("[server]\nunix_socket = \"{}\"\n\
+        [type]\nname = \"proxy\"\n[proxy]\nbackend = \"http://{}\"\nmodify_host = false\nheader_host = \"\"\nmodify_server = false\n",
+        socket, backend));
+
+    let response = send_unix(&socket, "GET / HTTP/1.1\r\nHost: app\r\nConnection: close\r\n\r\n");
+    assert_eq!(response.body, b"X-Forwarded-Proto: http\nX-Forwarded-Host: app");
+}
+
+#[test]
+fn unix_socket_rejects_ip_based_settings() {
+    let dir = test_dir("unix_socket_rejects_ip_based_settings");
+    write_file(&dir, "server.toml", format!("[server]\nunix_socket = \"{}\"\nallow = [\"127.0.0.1\"]\n\
+        [type]\nname = \"static\"\n[static]\nwebroot = \".\"\nindex = \"index.html\"\n", dir.join("nextweb.sock").display()));
+    write_file(&dir, "config.toml", "[[servers]]\nname = \"test\"\nconfig = \"server.toml\"\n");
+
+    let (success, output) = run_to_exit(&dir);
+    assert!(!success);
+    assert!(output.contains("unix_socket的客户端没有IP地址"), "{}", output);
+}